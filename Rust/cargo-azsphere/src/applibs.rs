@@ -0,0 +1,224 @@
+//! Capabilities required by the applibs functions a binary imports.
+//!
+//! The OS refuses applibs calls, usually with EPERM, when the app manifest lacks the matching
+//! capability.  The map below covers the headers `azure-sphere-sys` binds.  Many of their
+//! `static inline` functions call versioned exports named `z__<function>`, which are matched as
+//! if the prefix were absent.
+
+use crate::error::ImageError;
+use crate::manifest::{Diagnostic, Severity};
+use object::{Object, ObjectSymbol};
+use serde_json::Value;
+use std::collections::{BTreeMap, BTreeSet};
+
+/// Symbol patterns, with a trailing `*` matching any suffix, and the capability each needs
+const CAPABILITY_SYMBOLS: &[(&str, &str)] = &[
+    ("ADC_*", "Adc"),
+    ("GPIO_*", "Gpio"),
+    ("I2CMaster_*", "I2cMaster"),
+    ("PWM_*", "Pwm"),
+    ("SPIMaster_*", "SpiMaster"),
+    ("UART_*", "Uart"),
+    ("Application_Connect", "AllowedApplicationConnections"),
+    ("CertStore_*", "CertStore"),
+    ("DeviceAuth_*", "DeviceAuthentication"),
+    ("Networking_DhcpServer_*", "DhcpService"),
+    ("Networking_SetHardwareAddress", "HardwareAddressConfig"),
+    ("Networking_IpConfig_Apply", "NetworkConfig"),
+    ("Networking_IpConfig_ReleaseIp", "NetworkConfig"),
+    ("Networking_IpConfig_RenewIp", "NetworkConfig"),
+    ("Networking_SetInterfaceState", "NetworkConfig"),
+    ("Networking_Proxy_Apply", "NetworkProxyConfig"),
+    ("Networking_Proxy_Get", "ReadNetworkProxyConfig"),
+    ("Networking_SntpServer_*", "SntpService"),
+    ("Networking_TimeSync_*", "TimeSyncConfig"),
+    ("PowerManagement_*", "PowerControls"),
+    ("Storage_DeleteMutableFile", "MutableStorage"),
+    ("Storage_OpenMutableFile", "MutableStorage"),
+    ("SysEvent_DeferEvent", "SoftwareUpdateDeferral"),
+    ("SysEvent_ResumeEvent", "SoftwareUpdateDeferral"),
+    (
+        "SysEvent_RegisterForEventNotifications",
+        "SystemEventNotifications",
+    ),
+    ("WifiConfig_*", "WifiConfig"),
+    ("clock_systohc", "SystemTime"),
+];
+
+/// Names of the undefined symbols of an ELF file: the dynamic imports of an executable, or the
+/// external references of an object file
+pub fn imported_symbols(elf: &[u8]) -> Result<BTreeSet<String>, ImageError> {
+    let file = object::File::parse(elf).map_err(|e| ImageError::Malformed(e.to_string()))?;
+    Ok(file
+        .dynamic_symbols()
+        .chain(file.symbols())
+        .filter(|symbol| symbol.is_undefined())
+        .filter_map(|symbol| symbol.name().ok())
+        .filter(|name| !name.is_empty())
+        .map(|name| name.to_string())
+        .collect())
+}
+
+fn capability_for_symbol(symbol: &str) -> Option<&'static str> {
+    let symbol = symbol.strip_prefix("z__").unwrap_or(symbol);
+    CAPABILITY_SYMBOLS
+        .iter()
+        .find(|(pattern, _)| match pattern.strip_suffix('*') {
+            Some(prefix) => symbol.starts_with(prefix),
+            None => symbol == *pattern,
+        })
+        .map(|(_, capability)| *capability)
+}
+
+/// Capabilities needed by `symbols`, each with the symbols that need it
+pub fn required_capabilities<'a>(
+    symbols: impl IntoIterator<Item = &'a String>,
+) -> BTreeMap<&'static str, Vec<&'a str>> {
+    let mut capabilities = BTreeMap::<_, Vec<_>>::new();
+    for symbol in symbols {
+        if let Some(capability) = capability_for_symbol(symbol) {
+            capabilities
+                .entry(capability)
+                .or_default()
+                .push(symbol.as_str());
+        }
+    }
+    capabilities
+}
+
+/// A capability is granted when it's present and not empty or false
+fn is_granted(manifest: &Value, capability: &str) -> bool {
+    match &manifest["Capabilities"][capability] {
+        Value::Null | Value::Bool(false) => false,
+        Value::Array(items) => !items.is_empty(),
+        _ => true,
+    }
+}
+
+/// Warn about each capability the executable `elf` needs but `manifest` doesn't grant
+pub fn check_capabilities(manifest: &Value, elf: &[u8]) -> Result<Vec<Diagnostic>, ImageError> {
+    let symbols = imported_symbols(elf)?;
+    Ok(required_capabilities(&symbols)
+        .into_iter()
+        .filter(|(capability, _)| !is_granted(manifest, capability))
+        .map(|(capability, symbols)| Diagnostic {
+            severity: Severity::Warning,
+            path: format!("$.Capabilities.{capability}"),
+            message: format!("missing, but the app calls {}", symbols.join(", ")),
+        })
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use object::write::{Object as WriteObject, Symbol, SymbolSection};
+    use object::{Architecture, BinaryFormat, Endianness, SymbolFlags, SymbolKind, SymbolScope};
+    use regex::Regex;
+    use serde_json::json;
+    use std::fs;
+    use std::path::Path;
+
+    /// An ARM object file referencing `imports`
+    fn elf_importing(imports: &[&str]) -> Vec<u8> {
+        let mut obj = WriteObject::new(BinaryFormat::Elf, Architecture::Arm, Endianness::Little);
+        for name in imports {
+            obj.add_symbol(Symbol {
+                name: name.as_bytes().to_vec(),
+                value: 0,
+                size: 0,
+                kind: SymbolKind::Text,
+                scope: SymbolScope::Dynamic,
+                weak: false,
+                section: SymbolSection::Undefined,
+                flags: SymbolFlags::None,
+            });
+        }
+        obj.write().unwrap()
+    }
+
+    #[test]
+    fn test_check_capabilities() {
+        let elf = elf_importing(&[
+            "GPIO_OpenAsOutput",
+            "GPIO_SetValue",
+            "z__UART_Open",
+            "Storage_OpenMutableFile",
+            "Storage_OpenFileInImagePackage",
+            "WifiConfig_GetStoredNetworkCount",
+            "Networking_IsNetworkingReady",
+            "Log_Debug",
+            "malloc",
+        ]);
+        let symbols = imported_symbols(&elf).unwrap();
+        assert!(symbols.contains("z__UART_Open") && symbols.contains("malloc"));
+        let required = required_capabilities(&symbols);
+        assert_eq!(
+            required.keys().copied().collect::<Vec<_>>(),
+            ["Gpio", "MutableStorage", "Uart", "WifiConfig"]
+        );
+        assert_eq!(required["Gpio"], ["GPIO_OpenAsOutput", "GPIO_SetValue"]);
+
+        let manifest = json!({
+            "Capabilities": {
+                "Gpio": [8],
+                "Uart": [],
+                "WifiConfig": false,
+                "MutableStorage": { "SizeKB": 8 }
+            }
+        });
+        let diagnostics = check_capabilities(&manifest, &elf).unwrap();
+        assert_eq!(
+            diagnostics
+                .iter()
+                .map(|d| d.path.as_str())
+                .collect::<Vec<_>>(),
+            ["$.Capabilities.Uart", "$.Capabilities.WifiConfig"]
+        );
+        assert_eq!(
+            diagnostics[0].message,
+            "missing, but the app calls z__UART_Open"
+        );
+
+        assert!(matches!(
+            imported_symbols(b"not an elf"),
+            Err(ImageError::Malformed(_))
+        ));
+    }
+
+    /// The applibs functions `azure-sphere-sys` binds, as called by the `azure-sphere` wrappers
+    /// and declared by its static inline helpers.  The bindings themselves are generated from
+    /// the SDK headers at build time, so these checked-in sources stand in for them.
+    fn bound_functions() -> BTreeSet<String> {
+        let crate_dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("../rust/azure-sphere");
+        let call = Regex::new(r"\b[a-z0-9_]+::([A-Za-z][A-Za-z0-9]*_[A-Za-z0-9_]+)\(").unwrap();
+        let helper = Regex::new(r"\b([A-Za-z][A-Za-z0-9_]*)_inline\(").unwrap();
+        let mut functions = BTreeSet::new();
+        for entry in fs::read_dir(crate_dir.join("src/applibs")).unwrap() {
+            let source = fs::read_to_string(entry.unwrap().path()).unwrap();
+            functions.extend(call.captures_iter(&source).map(|c| c[1].to_string()));
+        }
+        let helpers = fs::read_to_string(
+            crate_dir.join("azure-sphere-sys/bindings/applibs/static_inline_helpers.h"),
+        )
+        .unwrap();
+        functions.extend(helper.captures_iter(&helpers).map(|c| c[1].to_string()));
+        functions.retain(|name| !name.ends_with("_inline"));
+        functions
+    }
+
+    #[test]
+    fn test_capability_symbols_are_bound() {
+        let functions = bound_functions();
+        assert!(functions.contains("GPIO_OpenAsOutput") && functions.contains("clock_systohc"));
+        for (pattern, capability) in CAPABILITY_SYMBOLS {
+            assert!(
+                functions.iter().any(|f| match pattern.strip_suffix('*') {
+                    Some(prefix) => f.starts_with(prefix),
+                    None => f == pattern,
+                }),
+                "{pattern} ({capability}) matches no function azure-sphere-sys binds"
+            );
+        }
+    }
+}
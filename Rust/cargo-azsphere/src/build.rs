@@ -0,0 +1,165 @@
+use crate::devices::TargetArgs;
+use crate::dry_run::{DryRun, Step};
+use crate::package::{self, PackageContext};
+use crate::sideload;
+use anyhow::Context;
+use std::process::Command;
+
+/// Rust target of Azure Sphere high-level apps
+const TARGET: &str = "armv7-unknown-linux-musleabihf";
+
+/// `cargo <subcommand>` for the Azure Sphere target
+pub(crate) fn cargo_command(subcommand: &str, release: bool) -> Command {
+    let mut command = Command::new("cargo");
+    command.arg(subcommand).arg("--target").arg(TARGET);
+    if release {
+        command.arg("--release");
+    }
+    command
+}
+
+/// Make `command` act on the crate of `context` only, with its features and its settings in the
+/// environment the build scripts read
+pub(crate) fn select_crate(command: &mut Command, context: &PackageContext) {
+    let config = &context.package_config;
+    command
+        .arg("--manifest-path")
+        .arg(context.manifest_file_dir.join("Cargo.toml"));
+    if !config.features.is_empty() {
+        command.arg("--features").arg(config.features.join(","));
+    }
+    command.env("AZURE_SPHERE_ARV", &config.arv);
+    if let Some(target_hardware) = &config.target_hardware {
+        command.env("AZURE_SPHERE_TARGET_HARDWARE", target_hardware);
+    }
+    if let Some(target_definition) = &config.target_definition {
+        command.env("AZURE_SPHERE_TARGET_DEFINITION", target_definition);
+    }
+}
+
+#[derive(clap::Parser, Debug)]
+pub(crate) struct CliArgs {
+    #[clap(flatten)]
+    package: package::PackageArgs,
+    /// sideload and start the app package once it is built
+    #[arg(long, conflicts_with = "all_variants")]
+    deploy: bool,
+    /// the device to deploy to when multiple devices are attached; repeat it to deploy to
+    /// several at once.
+    /// Values from: azsphere device list-attached. Specify the ID, IP address, or Local Connection ID of the device.
+    #[arg(short, long, requires = "deploy")]
+    device: Vec<String>,
+    /// deploy to every attached device at once
+    #[arg(long, requires = "deploy", conflicts_with = "device")]
+    all_attached: bool,
+    /// don't start the app once it is deployed
+    #[arg(short, long, requires = "deploy")]
+    manual_start: bool,
+    /// regenerate the app package even if it is up to date
+    #[arg(long)]
+    force_package: bool,
+}
+
+impl CliArgs {
+    /// `build --deploy` of the crates `common` selects, to `device`
+    pub(crate) fn deploy(common: package::CliArgs, device: Option<String>) -> Self {
+        Self {
+            package: package::PackageArgs::new(common),
+            deploy: true,
+            device: device.into_iter().collect(),
+            all_attached: false,
+            manual_start: false,
+            force_package: false,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CliSetting {
+    verbose: bool,
+    release: bool,
+    deploy: bool,
+    force_package: bool,
+    package: package::CliSetting,
+    sideload: sideload::CliSetting,
+}
+
+impl CliSetting {
+    pub(crate) fn new(args: CliArgs) -> Self {
+        let common = args.package.common.clone();
+        let sideload = sideload::CliSetting::new(sideload::CliArgs {
+            common: common.clone(),
+            targets: TargetArgs {
+                device: args.device,
+                all_attached: args.all_attached,
+            },
+            force: false,
+            manual_start: args.manual_start,
+            follow: false,
+            dry_run: args.package.dry_run.clone(),
+        });
+        Self {
+            verbose: common.verbose,
+            release: common.release,
+            deploy: args.deploy,
+            force_package: args.force_package,
+            package: package::CliSetting::new(args.package),
+            sideload,
+        }
+    }
+
+    fn dry_run(&self) -> Option<&DryRun> {
+        self.package.dry_run.as_ref()
+    }
+
+    /// Build the crate of `context`, with its features and its settings in the environment the
+    /// build scripts read
+    fn cargo_build(&self, context: &PackageContext) -> anyhow::Result<()> {
+        let mut command = cargo_command("build", self.release);
+        select_crate(&mut command, context);
+        if self.verbose {
+            command.arg("--verbose");
+            println!("Running {:?}", command);
+        }
+        if let Some(dry_run) = self.dry_run() {
+            let string = |s: &std::ffi::OsStr| s.to_string_lossy().to_string();
+            dry_run.record(Step::Run {
+                program: command.get_program().into(),
+                args: command.get_args().map(string).collect(),
+                env: command
+                    .get_envs()
+                    .filter_map(|(name, value)| Some((string(name), string(value?))))
+                    .collect(),
+            });
+            return Ok(());
+        }
+        // Inherit stdout and stderr, so compiler output is streamed as it is produced
+        let status = command.status().context("failed to run 'cargo build'")?;
+        if !status.success() {
+            anyhow::bail!("'cargo build' failed with {status}");
+        }
+        Ok(())
+    }
+
+    pub fn do_build(self) -> anyhow::Result<()> {
+        // Each crate is built with its own features and settings, and variants build the same
+        // executable differently, so each is built then packaged before the next overwrites it
+        for context in &self.package.contexts()? {
+            if self.dry_run().is_none() {
+                println!("Building {}", context.package_config.package_name());
+            }
+            self.cargo_build(context)?;
+            // A dry run doesn't build, so it packages whatever would be built
+            if self.force_package || self.dry_run().is_some() || context.is_package_stale() {
+                self.package.package(context)?;
+            } else {
+                println!("{} is up to date", context.app_package().display());
+            }
+        }
+
+        if self.deploy {
+            self.sideload.do_sideload()?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,257 @@
+//! Settings from Cargo's configuration, found the way Cargo finds them: the `.cargo/config.toml`
+//! (or legacy `.cargo/config`) of a directory and of each of its ancestors, nearest first, then
+//! that of `$CARGO_HOME`.  The build scripts of the Azure Sphere crates read `AZURE_SPHERE_*`
+//! from the environment Cargo sets up from `[env]`, so these are resolved the same way.
+
+use crate::error::{ConfigError, Error, FileAnnotatedError};
+use std::env;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::path::{Path, PathBuf};
+use toml::Value;
+
+/// Where a setting came from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// An environment variable, which overrides the config files
+    Environment(String),
+    File(PathBuf),
+}
+
+impl Display for Source {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Source::Environment(name) => write!(f, "environment variable {name}"),
+            Source::File(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Setting {
+    pub value: String,
+    pub source: Source,
+}
+
+/// The config files that apply to a directory, nearest first
+#[derive(Debug, Default)]
+pub struct CargoConfig {
+    files: Vec<(PathBuf, Value)>,
+}
+
+/// `$CARGO_HOME`, or `~/.cargo`
+fn cargo_home() -> Option<PathBuf> {
+    env::var_os("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".cargo")))
+}
+
+/// The config file in `dir`.  Like Cargo, the legacy `config` wins when both exist.
+fn config_file(dir: &Path) -> Option<PathBuf> {
+    ["config", "config.toml"]
+        .iter()
+        .map(|name| dir.join(name))
+        .find(|path| path.is_file())
+}
+
+impl CargoConfig {
+    /// The config of `dir`, its ancestors and `$CARGO_HOME`
+    pub fn load(dir: &Path) -> Result<Self, Error> {
+        Self::load_with_home(dir, cargo_home().as_deref())
+    }
+
+    fn load_with_home(dir: &Path, cargo_home: Option<&Path>) -> Result<Self, Error> {
+        let mut paths: Vec<PathBuf> = dir
+            .ancestors()
+            .filter_map(|dir| config_file(&dir.join(".cargo")))
+            .collect();
+        if let Some(path) = cargo_home.and_then(config_file) {
+            if !paths.contains(&path) {
+                paths.push(path);
+            }
+        }
+        let files = paths
+            .into_iter()
+            .map(|path| {
+                let toml = fs::read_to_string(&path).map_err(|e| Error::FileIo(path.clone(), e))?;
+                let toml = toml::from_str::<Value>(&toml)
+                    .map_err(|e| FileAnnotatedError(Some(path.clone()), e))?;
+                Ok((path, toml))
+            })
+            .collect::<Result<_, Error>>()?;
+        Ok(Self { files })
+    }
+
+    /// The string at `keys`, such as `["target", TARGET, "linker"]`, from the nearest file that
+    /// sets it
+    pub fn get_str(&self, keys: &[&str]) -> Result<Option<Setting>, Error> {
+        for (path, toml) in &self.files {
+            let value = keys.iter().try_fold(toml, |value, key| value.get(key));
+            if let Some(value) = value {
+                let value = value.as_str().ok_or_else(|| {
+                    FileAnnotatedError(
+                        Some(path.clone()),
+                        ConfigError::WrongType(keys.join("."), "a string"),
+                    )
+                })?;
+                return Ok(Some(Setting {
+                    value: value.to_string(),
+                    source: Source::File(path.clone()),
+                }));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The environment variable `name`, or else `[env] name` from the nearest file that sets it
+    pub fn env(&self, name: &str) -> Result<Option<Setting>, Error> {
+        self.env_or(name, env::var(name).ok())
+    }
+
+    fn env_or(&self, name: &str, environment: Option<String>) -> Result<Option<Setting>, Error> {
+        if let Some(value) = environment {
+            return Ok(Some(Setting {
+                value,
+                source: Source::Environment(name.to_string()),
+            }));
+        }
+        for (path, toml) in &self.files {
+            let Some(value) = toml.get("env").and_then(|env| env.get(name)) else {
+                continue;
+            };
+            let wrong_type = || {
+                FileAnnotatedError(
+                    Some(path.clone()),
+                    ConfigError::WrongType(
+                        format!("env.{name}"),
+                        "a string, or a table with a string `value`",
+                    ),
+                )
+            };
+            // `NAME = "value"`, or `NAME = { value = "value", relative = true }`, where a relative
+            // value is a path relative to the directory containing `.cargo`
+            let value = match value {
+                Value::String(value) => value.clone(),
+                Value::Table(table) => {
+                    let value = table
+                        .get("value")
+                        .and_then(Value::as_str)
+                        .ok_or_else(wrong_type)?;
+                    let relative = match table.get("relative") {
+                        None => false,
+                        Some(relative) => relative.as_bool().ok_or_else(wrong_type)?,
+                    };
+                    match path.parent().and_then(Path::parent) {
+                        Some(base) if relative => base.join(value).display().to_string(),
+                        _ => value.to_string(),
+                    }
+                }
+                _ => return Err(wrong_type().into()),
+            };
+            return Ok(Some(Setting {
+                value,
+                source: Source::File(path.clone()),
+            }));
+        }
+        Ok(None)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_cargo_config() {
+        let dir = tempfile::tempdir().unwrap();
+        let ws = dir.path().join("ws");
+        let app = ws.join("apps/app");
+        let home = dir.path().join("home");
+        for dir in [ws.join(".cargo"), app.join(".cargo"), home.clone()] {
+            fs::create_dir_all(dir).unwrap();
+        }
+        fs::write(
+            ws.join(".cargo/config"),
+            "[env]\nAZURE_SPHERE_ARV = \"16\"\n\
+             AZURE_SPHERE_TARGET_HARDWARE = \"mt3620_rdb\"\n\
+             HW_DIR = { value = \"hw\", relative = true }\n\
+             [target.armv7-unknown-linux-musleabihf]\nlinker = \"arm-gcc\"\n",
+        )
+        .unwrap();
+        // Ignored, as the legacy file in the same directory wins
+        fs::write(
+            ws.join(".cargo/config.toml"),
+            "[env]\nAZURE_SPHERE_ARV = \"9\"\n",
+        )
+        .unwrap();
+        fs::write(
+            app.join(".cargo/config.toml"),
+            "[env]\nAZURE_SPHERE_TARGET_HARDWARE = { value = \"avnet\" }\n",
+        )
+        .unwrap();
+        fs::write(
+            home.join("config.toml"),
+            "[env]\nAZURE_SPHERE_TARGET_DEFINITION = \"sample_appliance\"\nBAD = 16\n",
+        )
+        .unwrap();
+
+        let config = CargoConfig::load_with_home(&app.join("src"), Some(&home)).unwrap();
+        let setting = |name| config.env_or(name, None).unwrap();
+        let from = |path: PathBuf, value: &str| {
+            Some(Setting {
+                value: value.to_string(),
+                source: Source::File(path),
+            })
+        };
+        assert_eq!(
+            setting("AZURE_SPHERE_ARV"),
+            from(ws.join(".cargo/config"), "16")
+        );
+        assert_eq!(
+            setting("AZURE_SPHERE_TARGET_HARDWARE"),
+            from(app.join(".cargo/config.toml"), "avnet")
+        );
+        assert_eq!(
+            setting("AZURE_SPHERE_TARGET_DEFINITION"),
+            from(home.join("config.toml"), "sample_appliance")
+        );
+        assert_eq!(
+            setting("HW_DIR"),
+            from(
+                ws.join(".cargo/config"),
+                &ws.join("hw").display().to_string()
+            )
+        );
+        assert_eq!(setting("MISSING"), None);
+        assert!(matches!(
+            config.env_or("BAD", None),
+            Err(Error::ExtraConfig(FileAnnotatedError(Some(path), ConfigError::WrongType(..))))
+                if path == home.join("config.toml")
+        ));
+        assert_eq!(
+            config
+                .env_or("AZURE_SPHERE_ARV", Some("15".to_string()))
+                .unwrap(),
+            Some(Setting {
+                value: "15".to_string(),
+                source: Source::Environment("AZURE_SPHERE_ARV".to_string()),
+            })
+        );
+        assert_eq!(
+            config
+                .get_str(&["target", "armv7-unknown-linux-musleabihf", "linker"])
+                .unwrap(),
+            from(ws.join(".cargo/config"), "arm-gcc")
+        );
+
+        // No config files at all is fine; a malformed one isn't
+        let empty = CargoConfig::load_with_home(&home, None).unwrap();
+        assert_eq!(empty.env_or("AZURE_SPHERE_ARV", None).unwrap(), None);
+        fs::write(app.join(".cargo/config.toml"), "[env\n").unwrap();
+        assert!(matches!(
+            CargoConfig::load_with_home(&app, None),
+            Err(Error::ParseTomlFile(FileAnnotatedError(Some(path), _)))
+                if path == app.join(".cargo/config.toml")
+        ));
+    }
+}
@@ -3,7 +3,7 @@ use crate::error::{ConfigError, FileAnnotatedError};
 use crate::Error;
 use cargo_toml::Manifest;
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use toml::value::Table;
 use toml::Value;
 
@@ -101,6 +101,7 @@ mod toml_dotted_bare_key_parser {
 pub(crate) trait TomlValueHelper<'a> {
     fn get_str(&self, name: &str) -> Result<Option<&'a str>, ConfigError>;
     fn get_i64(&self, name: &str) -> Result<Option<i64>, ConfigError>;
+    #[allow(dead_code)]
     fn get_string_or_i64(&self, name: &str) -> Result<Option<String>, ConfigError>;
     fn get_table(&self, name: &str) -> Result<Option<&'a Table>, ConfigError>;
     fn get_array(&self, name: &str) -> Result<Option<&'a [Value]>, ConfigError>;
@@ -110,7 +111,8 @@ pub(crate) trait TomlValueHelper<'a> {
 pub(super) struct ExtraMetaData(Table, ExtraMetadataSource);
 
 impl ExtraMetaData {
-    pub(super) fn new(source: &ExtraMetadataSource) -> Result<Self, Error> {
+    /// Load `source`.  Variants are read from the package's Cargo.toml, at `manifest_path`.
+    pub(super) fn new(source: &ExtraMetadataSource, manifest_path: &Path) -> Result<Self, Error> {
         match source {
             ExtraMetadataSource::File(p, branch) => {
                 let annot: Option<PathBuf> = Some(p.clone());
@@ -130,6 +132,22 @@ impl ExtraMetaData {
                     .map_err(|e| FileAnnotatedError(annot, e))?;
                 Ok(Self(table.clone(), source.clone()))
             }
+            ExtraMetadataSource::Variant(variant) => {
+                let branch = format!("package.metadata.azsphere.variants.{variant}");
+                let source = ExtraMetadataSource::File(manifest_path.to_path_buf(), Some(branch));
+                Self::new(&source, manifest_path)
+            }
+            ExtraMetadataSource::Test => {
+                let branch = "package.metadata.azsphere.test".to_string();
+                let source = ExtraMetadataSource::File(manifest_path.to_path_buf(), Some(branch));
+                match Self::new(&source, manifest_path) {
+                    Err(Error::ExtraConfig(FileAnnotatedError(
+                        _,
+                        ConfigError::BranchPathNotFoundInToml(_),
+                    ))) => Ok(Self(Table::new(), source)),
+                    result => result,
+                }
+            }
         }
     }
 
@@ -145,8 +163,8 @@ impl ExtraMetaData {
             toml_dotted_bare_key_parser::parse_dotted_bare_keys(branch.as_ref())
                 .map_err(|e| ConfigError::WrongBranchPathOfToml(branch.clone(), e))?
                 .iter()
-                .fold(Some(root), |table, key| {
-                    table.and_then(|v| v.get(*key).and_then(|v| v.as_table()))
+                .try_fold(root, |table, key| {
+                    table.get(*key).and_then(|v| v.as_table())
                 })
                 .ok_or(ConfigError::BranchPathNotFoundInToml(branch.to_string()))
         } else {
@@ -206,7 +224,7 @@ impl<'a> MetadataConfig<'a> {
             ))?;
 
         Ok(Some(Self {
-            metadata: metadata,
+            metadata,
             branch_path: Some("package.metadata.azsphere".to_string()),
         }))
     }
@@ -258,7 +276,7 @@ impl<'a> TomlValueHelper<'a> for MetadataConfig<'a> {
             .get(name)
             .map(|val| match val {
                 Value::Table(v) => Ok(Some(v)),
-                _ => Err(self.create_config_error(name, "string or integer")),
+                _ => Err(self.create_config_error(name, "table")),
             })
             .unwrap_or(Ok(None))
     }
@@ -288,7 +306,7 @@ impl<'a> CompoundMetadataConfig<'a> {
         F: Fn(&MetadataConfig<'a>) -> Result<Option<T>, ConfigError>,
     {
         for item in self.config.iter().rev() {
-            match func(&item) {
+            match func(item) {
                 v @ (Ok(Some(_)) | Err(_)) => return v,
                 Ok(None) => continue,
             }
@@ -361,11 +379,11 @@ mod test {
         assert_eq!(metadata_config.get_str("not-exist").unwrap(), None);
         assert!(matches!(
             metadata_config.get_str("int"),
-            Err(ConfigError::WrongType(v, "string")) if v == "int".to_string()
+            Err(ConfigError::WrongType(v, "string")) if v == "int"
         ));
         assert!(matches!(
             metadata_config.get_string_or_i64("array"),
-            Err(ConfigError::WrongType(v, "string or integer")) if v == "array".to_string()
+            Err(ConfigError::WrongType(v, "string or integer")) if v == "array"
         ));
 
         let metadata_config = MetadataConfig {
@@ -374,11 +392,11 @@ mod test {
         };
         assert!(matches!(
             metadata_config.get_str("int"),
-            Err(ConfigError::WrongType(v, "string")) if v == "branch.int".to_string()
+            Err(ConfigError::WrongType(v, "string")) if v == "branch.int"
         ));
         assert!(matches!(
             metadata_config.get_string_or_i64("array"),
-            Err(ConfigError::WrongType(v, "string or integer")) if v == "branch.array".to_string()
+            Err(ConfigError::WrongType(v, "string or integer")) if v == "branch.array"
         ));
     }
 
@@ -1,33 +1,63 @@
 use cargo_toml::Error as CargoTomlError;
 use cargo_toml::Manifest;
 use std::path::{Path, PathBuf};
-use toml::value::Value;
+use toml::value::{Table, Value};
 
 use crate::error::{ConfigError, Error};
 use metadata::{CompoundMetadataConfig, ExtraMetaData, MetadataConfig, TomlValueHelper};
 
+mod cargo_config;
 mod metadata;
 
+pub use cargo_config::CargoConfig;
+
 #[derive(Debug, Clone)]
 pub enum ExtraMetadataSource {
     File(PathBuf, Option<String>),
     Text(String),
+    /// a table of `[package.metadata.azsphere.variants]` in the package's own Cargo.toml
+    Variant(String),
+    /// `[package.metadata.azsphere.test]` in the package's own Cargo.toml, if there is one, for
+    /// the app package of its tests
+    Test,
 }
 
 #[derive(Debug)]
 pub struct Config {
     manifest: Manifest,
-    _manifest_path: PathBuf,
+    manifest_path: PathBuf,
     extra_metadata: Vec<ExtraMetaData>,
+    variant: Option<String>,
+}
+
+/// A real-time app deployed with the app, which it connects to
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Partner {
+    /// a prebuilt app package
+    ImagePackage(PathBuf),
+}
+
+impl Partner {
+    pub fn to_json(&self) -> serde_json::Value {
+        match self {
+            Partner::ImagePackage(path) => serde_json::json!({ "image_package": path }),
+        }
+    }
 }
 
 /// Required fields, retrieved from the app's Cargo.toml
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct PackageConfig {
     /// Cargo.Toml package.name
     pub name: String,
-    /// app manifest filename
-    pub app_manifest: String,
+    /// path of the package's Cargo.toml
+    pub manifest_path: PathBuf,
+    /// build variant, from `[package.metadata.azsphere.variants]`
+    pub variant: Option<String>,
+    /// cargo features to build the variant with
+    pub features: Vec<String>,
+    /// app manifest path, resolved against the directory containing Cargo.toml
+    pub app_manifest: PathBuf,
     /// ARV version to target
     pub arv: String,
     /// subdir under the HardwareDefinitions directory tree to use (usually, mt3620_rdb)
@@ -36,21 +66,106 @@ pub struct PackageConfig {
     pub target_definition: Option<String>,
     /// extra files to include in the app package
     pub extra_files: Option<Vec<Value>>,
+    /// capabilities to generate the app manifest from, instead of using `app_manifest`
+    pub capabilities: Option<Table>,
+    /// ComponentId of a generated app manifest.  Derived from `manifest_path` and `name` if not
+    /// set.
+    pub component_id: Option<String>,
+    /// size budget of the stripped executable, in KB
+    pub max_binary_kb: Option<u64>,
+    /// size budget of the app package, in KB
+    pub max_package_kb: Option<u64>,
+    /// components deployed with the app, before it
+    pub partners: Vec<Partner>,
+}
+
+impl PackageConfig {
+    /// Base name of the app package and the files packaging writes: `name`, or `name-variant`
+    pub fn package_name(&self) -> String {
+        match &self.variant {
+            Some(variant) => format!("{}-{}", self.name, variant),
+            None => self.name.clone(),
+        }
+    }
+
+    /// The resolved settings, as JSON
+    pub fn to_json(&self) -> serde_json::Value {
+        serde_json::json!({
+            "name": self.name,
+            "variant": self.variant,
+            "features": self.features,
+            "app_manifest": self.app_manifest,
+            "arv": self.arv,
+            "target_hardware": self.target_hardware,
+            "target_definition": self.target_definition,
+            "extra_files": self.extra_files,
+            "capabilities": self.capabilities,
+            "component_id": self.component_id,
+            "max_binary_kb": self.max_binary_kb,
+            "max_package_kb": self.max_package_kb,
+            "partners": self.partners.iter().map(Partner::to_json).collect::<Vec<_>>(),
+        })
+    }
+
+    /// What diagnostics about the app manifest name: its file, or the metadata it is generated
+    /// from, as there is no file then
+    pub fn app_manifest_source(&self) -> String {
+        match self.capabilities {
+            Some(_) => "app manifest generated from package.metadata.azsphere.capabilities".into(),
+            None => self.app_manifest.display().to_string(),
+        }
+    }
+
+    /// Filename of the target hardware definition, such as `sample_appliance.json`
+    pub fn target_definition_file(&self) -> Option<String> {
+        self.target_definition
+            .as_ref()
+            .map(|target_definition| target_definition.to_owned() + ".json")
+    }
+}
+
+#[cfg(test)]
+impl PackageConfig {
+    /// The config of a crate `name` with nothing set, and the app manifest of tests/fixtures
+    pub fn for_test(name: &str) -> Self {
+        PackageConfig {
+            name: name.to_string(),
+            manifest_path: PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"),
+            variant: None,
+            features: vec![],
+            app_manifest: PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/app_manifest.json"),
+            arv: "16".to_string(),
+            target_hardware: None,
+            target_definition: None,
+            extra_files: None,
+            capabilities: None,
+            component_id: None,
+            max_binary_kb: None,
+            max_package_kb: None,
+            partners: vec![],
+        }
+    }
 }
 
 /// Parse the app's Cargo.toml, with optional overrides
 impl Config {
     pub fn new(path: &Path, extra_metadata: &[ExtraMetadataSource]) -> Result<Self, Error> {
         let manifest_path = path.to_path_buf();
+        let variant = extra_metadata.iter().rev().find_map(|source| match source {
+            ExtraMetadataSource::Variant(variant) => Some(variant.clone()),
+            _ => None,
+        });
         let extra_metadata = extra_metadata
             .iter()
-            .map(|v| ExtraMetaData::new(v))
+            .map(|source| ExtraMetaData::new(source, path))
             .collect::<Result<Vec<_>, _>>()?;
-        Manifest::from_path(&path)
+        Manifest::from_path(path)
             .map(|manifest| Config {
                 manifest,
-                _manifest_path: manifest_path,
+                manifest_path,
                 extra_metadata,
+                variant,
             })
             .map_err(|err| match err {
                 CargoTomlError::Io(e) => Error::FileIo(PathBuf::from(path), e),
@@ -58,26 +173,33 @@ impl Config {
             })
     }
 
-    fn get_value_from_config(package_root: &String, variable_name: &str) -> Option<String> {
-        let config_file = PathBuf::from(package_root).join(".cargo/config");
-        let str = std::fs::read_to_string(&config_file).expect("Error in reading the file");
-        let toml = str.parse::<Value>();
-        if let Ok(toml) = toml {
-            let env = toml["env"].as_table();
-            if let Some(env) = env {
-                let value = env[variable_name].as_str();
-                if let Some(value) = value {
-                    let value = value.to_string();
-                    return Some(value.replace("\"", ""));
-                }
-            }
+    /// Names of the tables in `[package.metadata.azsphere.variants]`
+    pub fn variants(&self) -> Result<Vec<String>, Error> {
+        let variants = MetadataConfig::new_from_manifest(&self.manifest)?
+            .map(|metadata| metadata.get_table("variants"))
+            .transpose()?
+            .flatten();
+        Ok(variants
+            .map(|variants| variants.keys().cloned().collect())
+            .unwrap_or_default())
+    }
+
+    /// `name` from Cargo's config, saying where it came from when `verbose`
+    fn get_value_from_config(
+        cargo_config: &CargoConfig,
+        name: &str,
+        verbose: bool,
+    ) -> Result<Option<String>, Error> {
+        let setting = cargo_config.env(name)?;
+        if let (Some(setting), true) = (&setting, verbose) {
+            println!("{} = {} (from {})", name, setting.value, setting.source);
         }
-        return None;
+        Ok(setting.map(|setting| setting.value))
     }
 
-    fn get_arv_from_config(package_root: &String, verbose: bool) -> String {
-        let arv = Self::get_value_from_config(&package_root, "AZURE_SPHERE_ARV");
-        match arv {
+    fn get_arv_from_config(cargo_config: &CargoConfig, verbose: bool) -> Result<String, Error> {
+        let arv = Self::get_value_from_config(cargo_config, "AZURE_SPHERE_ARV", verbose)?;
+        Ok(match arv {
             Some(arv) => arv,
             _ => {
                 let default_arv = "14".to_string();
@@ -86,12 +208,12 @@ impl Config {
                 }
                 default_arv
             }
-        }
+        })
     }
 
     pub fn package_config(
         &self,
-        package_root: String,
+        cargo_config: &CargoConfig,
         verbose: bool,
     ) -> Result<PackageConfig, Error> {
         let mut metadata_config = Vec::new();
@@ -112,54 +234,206 @@ impl Config {
             .as_ref()
             .ok_or(ConfigError::Missing("package".to_string()))?;
 
-        let name = metadata
-            .get_str("name")?
-            .unwrap_or_else(|| pkg.name.as_str());
+        let name = metadata.get_str("name")?.unwrap_or(pkg.name.as_str());
 
-        let app_manifest = metadata
-            .get_str("app_manifest")?
-            .unwrap_or_else(|| "app_manifest.json");
+        let capabilities = metadata.get_table("capabilities")?.cloned();
+        let app_manifest = metadata.get_str("app_manifest")?;
+        if capabilities.is_some() && app_manifest.is_some() {
+            return Err(ConfigError::Conflicting(
+                "package.metadata.azsphere.capabilities".to_string(),
+                "package.metadata.azsphere.app_manifest".to_string(),
+            )
+            .into());
+        }
+        let manifest_dir = self
+            .manifest_path
+            .parent()
+            .unwrap_or_else(|| Path::new("."));
+        let app_manifest = manifest_dir.join(app_manifest.unwrap_or("app_manifest.json"));
+        let component_id = metadata.get_str("component_id")?.map(|id| id.to_string());
 
-        let arv = metadata.get_str("arv")?;
-        let arv = if arv.is_none() {
-            Self::get_arv_from_config(&package_root, verbose)
-        } else {
-            arv.unwrap().to_string()
+        let arv = match metadata.get_str("arv")? {
+            Some(arv) => arv.to_string(),
+            None => Self::get_arv_from_config(cargo_config, verbose)?,
         };
 
-        let extra_files = metadata.get_array("extra_files")?;
-        let extra_files = if let Some(files) = extra_files {
-            Some(files.to_vec())
-        } else {
-            None
-        };
+        let extra_files = metadata
+            .get_array("extra_files")?
+            .map(|files| files.to_vec());
 
-        let target_definition = metadata.get_str("target_definition")?;
-        let target_definition = if target_definition.is_none() {
-            Self::get_value_from_config(&package_root, "AZURE_SPHERE_TARGET_DEFINITION")
-        } else {
-            Some(target_definition.unwrap().to_string())
+        let features = metadata
+            .get_array("features")?
+            .unwrap_or_default()
+            .iter()
+            .map(|feature| {
+                feature
+                    .as_str()
+                    .map(str::to_string)
+                    .ok_or_else(|| ConfigError::WrongType("features entry".to_string(), "string"))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let target_definition = match metadata.get_str("target_definition")? {
+            Some(target_definition) => Some(target_definition.to_string()),
+            None => Self::get_value_from_config(
+                cargo_config,
+                "AZURE_SPHERE_TARGET_DEFINITION",
+                verbose,
+            )?,
         };
-        let target_definition = if let Some(t) = target_definition {
-            Some(t)
-        } else {
-            None
+
+        let target_hardware = match metadata.get_str("target_hardware")? {
+            Some(target_hardware) => Some(target_hardware.to_string()),
+            None => {
+                Self::get_value_from_config(cargo_config, "AZURE_SPHERE_TARGET_HARDWARE", verbose)?
+            }
         };
 
-        let target_hardware = metadata.get_str("target_hardware")?;
-        let target_hardware = if target_hardware.is_none() {
-            Self::get_value_from_config(&package_root, "AZURE_SPHERE_TARGET_HARDWARE")
-        } else {
-            Some(target_hardware.unwrap().to_string())
+        let budget = |key: &str| -> Result<Option<u64>, ConfigError> {
+            metadata
+                .get_i64(key)?
+                .map(|kb| {
+                    u64::try_from(kb).map_err(|_| {
+                        ConfigError::WrongType(
+                            format!("package.metadata.azsphere.{key}"),
+                            "non-negative integer",
+                        )
+                    })
+                })
+                .transpose()
         };
+        let max_binary_kb = budget("max_binary_kb")?;
+        let max_package_kb = budget("max_package_kb")?;
+
+        // Each partner is `{ image_package = "path" }`.  `{ package = "name" }` is rejected, as
+        // crates of the workspace are built as high-level apps, which would replace the app
+        let partners = metadata
+            .get_array("partners")?
+            .unwrap_or_default()
+            .iter()
+            .map(|partner| {
+                let table = partner.as_table();
+                let field = |key: &str| table.and_then(|table| table.get(key)?.as_str());
+                match (field("package"), field("image_package")) {
+                    (Some(name), None) if table.is_some_and(|table| table.len() == 1) => {
+                        Err(ConfigError::HighLevelPartner(name.to_string()))
+                    }
+                    (None, Some(path)) if table.is_some_and(|table| table.len() == 1) => {
+                        Ok(Partner::ImagePackage(manifest_dir.join(path)))
+                    }
+                    _ => Err(ConfigError::WrongType(
+                        "package.metadata.azsphere.partners entry".to_string(),
+                        "a table of `package` or `image_package`",
+                    )),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
 
         Ok(PackageConfig {
             name: name.to_string(),
-            app_manifest: app_manifest.to_string(),
+            manifest_path: self.manifest_path.clone(),
+            variant: self.variant.clone(),
+            features,
+            app_manifest,
             arv,
             target_definition,
             target_hardware,
             extra_files,
+            capabilities,
+            component_id,
+            max_binary_kb,
+            max_package_kb,
+            partners,
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_variants() {
+        let dir = tempfile::tempdir().unwrap();
+        let manifest_path = dir.path().join("Cargo.toml");
+        fs::write(
+            &manifest_path,
+            r#"
+            [package]
+            name = "blink"
+            version = "0.1.0"
+
+            [package.metadata.azsphere]
+            arv = "16"
+            target_hardware = "mt3620_rdb"
+            target_definition = "sample_appliance"
+            partners = [{ image_package = "rt/i2c.imagepackage" }]
+
+            [package.metadata.azsphere.variants.avnet]
+            target_hardware = "avnet_mt3620_sk_rev2"
+            features = ["avnet"]
+
+            [package.metadata.azsphere.variants.seeed]
+            target_hardware = "seeed_mt3620_mdb"
+            arv = 17
+            "#,
+        )
+        .unwrap();
+        let cargo_config = CargoConfig::default();
+
+        let config = Config::new(&manifest_path, &[]).unwrap();
+        assert_eq!(config.variants().unwrap(), ["avnet", "seeed"]);
+        let base = config.package_config(&cargo_config, false).unwrap();
+        assert_eq!(base.package_name(), "blink");
+        assert_eq!(base.target_hardware.as_deref(), Some("mt3620_rdb"));
+        assert!(base.features.is_empty());
+        assert_eq!(
+            base.partners,
+            [Partner::ImagePackage(
+                dir.path().join("rt/i2c.imagepackage")
+            )]
+        );
+
+        let variant = |variant: &str, extra_metadata: &[ExtraMetadataSource]| {
+            let mut sources = vec![ExtraMetadataSource::Variant(variant.to_string())];
+            sources.extend_from_slice(extra_metadata);
+            Config::new(&manifest_path, &sources)?.package_config(&cargo_config, false)
+        };
+        let avnet = variant("avnet", &[]).unwrap();
+        assert_eq!(avnet.package_name(), "blink-avnet");
+        assert_eq!(
+            avnet.target_hardware.as_deref(),
+            Some("avnet_mt3620_sk_rev2")
+        );
+        assert_eq!(avnet.target_definition.as_deref(), Some("sample_appliance"));
+        assert_eq!(avnet.arv, "16");
+        assert_eq!(avnet.features, ["avnet"]);
+
+        // The command line overrides the variant
+        let text = ExtraMetadataSource::Text("target_hardware = \"custom\"".to_string());
+        let avnet = variant("avnet", &[text]).unwrap();
+        assert_eq!(avnet.target_hardware.as_deref(), Some("custom"));
+
+        assert!(matches!(
+            variant("seeed", &[]),
+            Err(Error::Config(ConfigError::WrongType(key, "string")))
+                if key == "package.metadata.azsphere.variants.seeed.arv"
+        ));
+        assert!(matches!(variant("nope", &[]), Err(Error::ExtraConfig(_))));
+
+        let text = ExtraMetadataSource::Text(
+            "partners = [{ package = \"a\", image_package = \"b\" }]".to_string(),
+        );
+        assert!(matches!(
+            variant("avnet", &[text]),
+            Err(Error::Config(ConfigError::WrongType(key, _)))
+                if key == "package.metadata.azsphere.partners entry"
+        ));
+        let text = ExtraMetadataSource::Text("partners = [{ package = \"uart_rtapp\" }]".into());
+        assert!(matches!(
+            variant("avnet", &[text]),
+            Err(Error::Config(ConfigError::HighLevelPartner(name))) if name == "uart_rtapp"
+        ));
+    }
+}
@@ -1,15 +1,18 @@
-use crate::config::{Config, ExtraMetadataSource};
+use crate::config::ExtraMetadataSource;
+use crate::device;
+use crate::dry_run::{self, Changes, DryRun, Step};
 use crate::error::Error;
+use crate::logs::{self, LogStream};
+use crate::manifest;
+use crate::package;
+use crate::partners;
+use crate::symbolize::Symbolizer;
 use crate::util;
-use serde_json::Value;
-use std::env;
-use std::fs;
-use std::io::Read;
-use std::io::{self, Write};
-use std::net::TcpStream;
-use std::path::PathBuf;
+use crate::vscode::Launch;
+use crate::workspace::Workspace;
+use std::io;
 use std::process::Command;
-use std::str::from_utf8;
+use std::sync::Arc;
 use std::thread;
 extern crate ctrlc;
 
@@ -24,130 +27,105 @@ pub(crate) struct CliArgs {
     /// Use VSCode to debug instead of console gdb
     #[arg(long)]
     use_vs_code: bool,
+    #[clap(flatten)]
+    dry_run: dry_run::CliArgs,
 }
 
 #[derive(Debug)]
 pub struct CliSetting {
+    package: Option<String>,
     verbose: bool,
     release: bool,
     use_vs_code: bool,
     device_opt: Option<String>,
     extra_metadata: Vec<ExtraMetadataSource>,
+    dry_run: Option<DryRun>,
 }
 
 impl CliSetting {
     pub(crate) fn new(args: CliArgs) -> Self {
         Self {
+            package: args.common.package.clone(),
             verbose: args.common.verbose,
             release: args.common.release,
             use_vs_code: args.use_vs_code,
             device_opt: args.device,
             extra_metadata: args.common.extra_metadata(),
+            dry_run: args.dry_run.dry_run(),
         }
     }
 
     pub fn do_debug(self) -> Result<(), Error> {
-        if self.verbose {
-            println!("Invoking 'cargo metadata'");
-        }
-        let output = Command::new("cargo")
-            .arg("metadata")
-            .arg("--format-version=1")
-            .output()
-            .expect("Failed to run 'cargo metadata'");
-        if self.verbose {
-            println!("Parsing metadata");
-        }
-        let cargo_metadata: Value = serde_json::from_slice(output.stdout.as_slice()).unwrap();
-
-        if self.verbose {
-            println!("Finding package config");
+        let workspace = Workspace::load(self.verbose)?;
+        let member = workspace.member(self.package.as_deref())?;
+        let package_config =
+            workspace.package_config(&member, &self.extra_metadata, self.verbose)?;
+        let dry_run = self.dry_run.as_ref();
+        if let Some(dry_run) = dry_run {
+            dry_run.record(Step::Config(package_config.to_json()));
         }
-        let manifest_file_dir = env::current_dir()?;
-        let manifest_file_path = manifest_file_dir.join("Cargo.toml");
-        let config = Config::new(&manifest_file_path, self.extra_metadata.as_slice())?;
-        let package_config = config.package_config(
-            cargo_metadata["workspace_root"]
-                .to_string()
-                .replace("\"", ""),
-            self.verbose,
-        )?;
 
-        let (azsphere, azsphere_args) = util::azsphere_tool_path();
-        let mut args: Vec<String> = vec![];
-        args.extend(azsphere_args);
-
-        let data = fs::read_to_string(package_config.app_manifest)
-            .expect("Unable to read app manifest file");
-        let app_manifest: serde_json::Value =
-            serde_json::from_str(&data).expect("Unable to parse app manifest file");
-        let component_id = app_manifest["ComponentId"].to_string();
-
-        args.push("device".to_string());
-        args.push("app".to_string());
-        args.push("start".to_string());
-        args.push("-i".to_string());
-        args.push(component_id.replace("\"", ""));
-
-        let device_ip = if let Some(device) = self.device_opt {
-            args.push("-d".to_string());
-            args.push(device.clone());
-            device
-        } else {
-            "192.168.35.2".to_string()
-        };
-        args.push("--debug-mode".to_string());
-        if self.verbose {
-            args.push("--verbose".to_string());
-        }
-        println!("Starting app");
-        if self.verbose {
-            println!("Program: {:?} Args {:?}", azsphere, args);
+        let component_id = manifest::component_id(&package_config)?;
+        let device_ip = self
+            .device_opt
+            .clone()
+            .unwrap_or_else(|| logs::DEFAULT_DEVICE.to_string());
+        let device = device::backend(dry_run, self.device_opt.clone(), self.verbose)?;
+        let target_path = workspace.target_path(self.release);
+        // The partners are started normally, before the app connects to them
+        for partner in partners::resolve(&package_config) {
+            if dry_run.is_none() {
+                println!("Starting partner {}", partner.name);
+            }
+            device.start(&partner.component_id()?, false)?;
         }
-        let output = Command::new(azsphere)
-            .args(args)
-            .output()
-            .expect("Failed to start app");
-        if !output.status.success() {
-            println!("azsphere command failed:");
-            io::stdout().write_all(&output.stdout).unwrap();
-            io::stderr().write_all(&output.stderr).unwrap();
-            std::process::exit(output.status.code().unwrap());
+        if dry_run.is_none() {
+            println!("Starting app");
         }
+        device.start(&component_id, true)?;
 
-        println!("Connecting to {}:2342...\n", device_ip);
+        // Prefer the copy that packaging kept with its symbols
+        let mut source_program =
+            package::unstripped_executable(&target_path, &package_config.package_name());
+        if !source_program.exists() {
+            source_program = target_path.join(&package_config.name);
+        }
 
-        // Now that the application has been started, connect to the device's port 2342 to receive its output stream
-        match TcpStream::connect((device_ip.clone(), 2342)) {
-            Ok(mut stream) => {
-                thread::spawn(move || loop {
-                    let mut data = [0 as u8; 2048];
-                    match stream.read(&mut data) {
-                        Ok(_) => match from_utf8(&data) {
-                            Ok(text) => {
-                                let text = text.trim_matches(char::from(0));
-                                if text.len() > 0 {
-                                    print!("{}", text)
-                                }
-                            }
-                            Err(e) => {
-                                println!("UTF8 conversion error: {:?}\n", e)
-                            }
-                        },
-                        Err(e) => {
-                            println!("Failed to read from the device on port 2342: {:?}", e);
-                            std::process::exit(1);
-                        }
+        let mut logs = LogStream::new(&device_ip);
+        logs.timestamps = false;
+        // Annotate the addresses of a crash
+        logs.symbolizer = Symbolizer::load(&source_program).ok().map(Arc::new);
+        match dry_run {
+            Some(dry_run) => dry_run.record(Step::Connect(logs.address.clone())),
+            None => {
+                println!("Connecting to {}...\n", logs.address);
+
+                // Now that the application has been started, connect to the device's port 2342 to receive its output stream
+                let stream = logs.connect()?;
+                thread::spawn(move || {
+                    // Stop showing output, but leave the debugger running
+                    if let Err(e) = logs.copy(stream, &mut io::stdout()) {
+                        println!("Failed to show the app's output: {e}");
                     }
                 });
             }
-            Err(e) => {
-                println!("Failed to connect to the device on port 2342: {:?}", e);
-                std::process::exit(1);
-            }
         }
 
         if self.use_vs_code {
+            let launch = Launch {
+                name: package_config.name.clone(),
+                variant: package_config.variant.clone(),
+                arv: package_config.arv.clone(),
+                sdk_path: util::sdk_path(),
+                crate_dir: member.dir().to_path_buf(),
+                target_path: target_path.clone(),
+                release: self.release,
+                device: self.device_opt.clone(),
+            };
+            launch.write(Changes(dry_run), self.verbose)?;
+            if dry_run.is_some() {
+                return Ok(());
+            }
             println!("Switch to Visual Studio Code and hit F5 to begin debugging\n");
             println!("Hit enter when finished debugging.\n");
             let mut input = String::new();
@@ -157,20 +135,11 @@ impl CliSetting {
                 println!("Starting debugger\n");
             }
 
-            let build_flavor = if self.release { "release" } else { "debug" };
-            let target_directory = cargo_metadata["target_directory"]
-                .to_string()
-                .replace("\"", "");
-            let mut target_path = PathBuf::from(target_directory);
-            target_path.push("armv7-unknown-linux-musleabihf");
-            target_path.push(build_flavor);
-            let source_program = target_path.join(&package_config.name);
-
             let target_remote = format!("target remote {}:2345", device_ip);
 
             // ${AzureSphereDefaultSDKDir}/Sysroots/${ARV}/tools/sysroots/x86_64-pokysdk-linux/usr/bin/arm-poky-linux-musleabi/arm-poky-linux-musleabi-gdb <args>
-            let sdk_path = PathBuf::from(std::env::var("AzureSphereDefaultSDKDir").unwrap());
-            let gdb_command = sdk_path.join("Sysroots").join(&package_config.arv).join("tools/sysroots/x86_64-pokysdk-linux/usr/bin/arm-poky-linux-musleabi/arm-poky-linux-musleabi-gdb");
+            let sdk_path = util::required_sdk_path()?;
+            let gdb_command = util::sysroot_tool(&sdk_path, &package_config.arv, "gdb");
             if self.verbose {
                 println!("Running arm-poky-linux-musleabi-gdb");
             }
@@ -186,14 +155,26 @@ impl CliSetting {
                     command.get_args(),
                 );
             }
+            if let Some(dry_run) = dry_run {
+                dry_run.record(Step::Run {
+                    program: command.get_program().into(),
+                    args: command
+                        .get_args()
+                        .map(|arg| arg.to_string_lossy().to_string())
+                        .collect(),
+                    env: vec![],
+                });
+                return Ok(());
+            }
 
             // Set an empty Ctrl+C handler, so that Ctrl+C is passed to gdb and handled there.  Otherwise,
             // the cargo-azsphere process is killed.
-            ctrlc::set_handler(move || {}).expect("Error setting Ctrl-C handler");
+            ctrlc::set_handler(move || {}).map_err(|e| Error::Io(io::Error::other(e)))?;
 
             // Launch gdb async, so that its stdout and stdin are unmodified, and wait for it to complete.
-            let mut child = command.spawn().expect("Failed to execute gdb");
-            let _ = child.wait().expect("Failed to run");
+            let gdb = command.get_program().into();
+            let mut child = command.spawn().map_err(|e| Error::FileIo(gdb, e))?;
+            child.wait()?;
             if self.verbose {
                 println!("Back from gdb\n");
             }
@@ -0,0 +1,504 @@
+//! Operations on an attached device, behind [`DeviceBackend`] so subcommands can be tested
+//! without the SDK.  Stripping happens in-process, in `elf`, and packing too with `--native-pack`.
+
+use crate::dry_run::{DryRun, DryRunner};
+use crate::error::Error;
+use crate::tool::{self, SystemRunner, ToolRunner};
+use crate::util;
+use serde_json::{json, Value};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+
+/// The state of an app, from `azsphere device app show-status`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppStatus {
+    pub component_id: String,
+    /// such as `running`, `stopped` or `debugging`
+    pub state: String,
+}
+
+/// An app's memory use, in KB, from `azsphere device app show-memory-stats`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryStats {
+    pub current: u64,
+    pub user_mode: u64,
+    pub peak_user_mode: u64,
+}
+
+/// A device attached to this computer, from `azsphere device list-attached`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AttachedDevice {
+    pub device_id: String,
+    pub ip_address: String,
+    pub connection_path: String,
+}
+
+/// The string `key` of `value`
+fn json_str(value: &Value, key: &str) -> Option<String> {
+    value[key].as_str().map(str::to_string)
+}
+
+impl AppStatus {
+    /// The CLI prints one object, or a list of them
+    fn from_json(value: &Value) -> Option<Self> {
+        let value = match value {
+            Value::Array(values) => values.first()?,
+            value => value,
+        };
+        Some(Self {
+            component_id: json_str(value, "componentId")?,
+            state: json_str(value, "state")?,
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({ "componentId": self.component_id, "state": self.state })
+    }
+}
+
+impl Display for AppStatus {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.state)
+    }
+}
+
+impl MemoryStats {
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(Self {
+            current: value["currentMemoryUsageInKB"].as_u64()?,
+            user_mode: value["userModeMemoryUsageInKB"].as_u64()?,
+            peak_user_mode: value["peakUserModeMemoryUsageInKB"].as_u64()?,
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "currentMemoryUsageInKB": self.current,
+            "userModeMemoryUsageInKB": self.user_mode,
+            "peakUserModeMemoryUsageInKB": self.peak_user_mode,
+        })
+    }
+}
+
+impl Display for MemoryStats {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} KB, of which user mode {} KB (peak {} KB)",
+            self.current, self.user_mode, self.peak_user_mode
+        )
+    }
+}
+
+impl AttachedDevice {
+    fn from_json(value: &Value) -> Option<Self> {
+        Some(Self {
+            device_id: json_str(value, "deviceId")?,
+            ip_address: json_str(value, "ipAddress")?,
+            connection_path: json_str(value, "connectionPath")?,
+        })
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "deviceId": self.device_id,
+            "ipAddress": self.ip_address,
+            "connectionPath": self.connection_path,
+        })
+    }
+}
+
+impl Display for AttachedDevice {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{:<15} {:<15} {}",
+            self.ip_address, self.connection_path, self.device_id
+        )
+    }
+}
+
+/// What the subcommands ask of a device
+pub trait DeviceBackend {
+    /// Sideload `app_package`, replacing any app with the same ComponentId
+    fn deploy(&self, app_package: &Path, force: bool, manual_start: bool) -> Result<(), Error>;
+    /// Start the app `component_id`, optionally waiting for a debugger to attach
+    fn start(&self, component_id: &str, debug_mode: bool) -> Result<(), Error>;
+    /// Stop the app `component_id`
+    fn stop(&self, component_id: &str) -> Result<(), Error>;
+    /// Remove the app `component_id` from the device
+    fn delete(&self, component_id: &str) -> Result<(), Error>;
+    /// The state of the app `component_id`
+    fn show_status(&self, component_id: &str) -> Result<AppStatus, Error>;
+    /// The memory the app `component_id` uses
+    fn show_memory_stats(&self, component_id: &str) -> Result<MemoryStats, Error>;
+    /// The devices attached to this computer
+    fn list_attached(&self) -> Result<Vec<AttachedDevice>, Error>;
+}
+
+/// Commands about every attached device, which don't take `-d <device>`
+const ALL_DEVICES_COMMANDS: &[&[&str]] = &[&["device", "list-attached"]];
+
+/// The SDK's azsphere CLI
+pub struct AzsphereCli<R: ToolRunner> {
+    pub runner: R,
+    program: PathBuf,
+    /// Arguments before the azsphere command, which the Windows CLI needs under WSL
+    base_args: Vec<String>,
+    device: Option<String>,
+    verbose: bool,
+    /// whether this is the Windows CLI under WSL, which needs Windows paths
+    wsl: bool,
+}
+
+impl AzsphereCli<SystemRunner> {
+    /// The SDK's CLI, or the Windows one under WSL, acting on `device`, or the only one attached
+    pub fn from_sdk(device: Option<String>, verbose: bool) -> Result<Self, Error> {
+        AzsphereCli::from_sdk_with(SystemRunner, device, verbose)
+    }
+}
+
+/// The SDK's CLI, which in a dry run only records what it would be asked to do
+pub fn backend(
+    dry_run: Option<&DryRun>,
+    device: Option<String>,
+    verbose: bool,
+) -> Result<Box<dyn DeviceBackend + '_>, Error> {
+    Ok(match dry_run {
+        Some(dry_run) => Box::new(AzsphereCli::from_sdk_with(
+            DryRunner(dry_run),
+            device,
+            verbose,
+        )?),
+        None => Box::new(AzsphereCli::from_sdk(device, verbose)?),
+    })
+}
+
+impl<R: ToolRunner> AzsphereCli<R> {
+    /// The SDK's CLI, run by `runner`
+    pub fn from_sdk_with(runner: R, device: Option<String>, verbose: bool) -> Result<Self, Error> {
+        let (program, base_args) = util::azsphere_tool_path()?;
+        Ok(Self::new(runner, program, base_args, device, verbose))
+    }
+
+    pub fn new(
+        runner: R,
+        program: PathBuf,
+        base_args: Vec<String>,
+        device: Option<String>,
+        verbose: bool,
+    ) -> Self {
+        Self {
+            runner,
+            program,
+            base_args,
+            device,
+            verbose,
+            wsl: util::is_wsl(),
+        }
+    }
+
+    /// Run `azsphere <command>` for the selected device, returning its stdout
+    fn run(&self, command: &[&str], options: &[String]) -> Result<String, Error> {
+        let mut args = self.base_args.clone();
+        args.extend(command.iter().map(|arg| arg.to_string()));
+        args.extend_from_slice(options);
+        if let Some(device) = self
+            .device
+            .as_ref()
+            .filter(|_| !ALL_DEVICES_COMMANDS.contains(&command))
+        {
+            args.push("-d".to_string());
+            args.push(device.clone());
+        }
+        if self.verbose {
+            args.push("--verbose".to_string());
+            println!("Program: {:?} Args {:?}", self.program, args);
+        }
+        let output = tool::run(&self.runner, &self.program, &args)?;
+        Ok(String::from_utf8_lossy(&output.stdout).to_string())
+    }
+
+    /// Run `azsphere <command> --output json`, and parse what it prints with `parse`
+    fn run_json<T>(
+        &self,
+        command: &[&str],
+        options: &[String],
+        parse: impl Fn(&Value) -> Option<T>,
+    ) -> Result<T, Error> {
+        let mut options = options.to_vec();
+        options.extend(["--output".to_string(), "json".to_string()]);
+        let stdout = self.run(command, &options)?;
+        let unexpected = |message: String| Error::UnexpectedOutput(command.join(" "), message);
+        let value = serde_json::from_str(&stdout).map_err(|e| unexpected(e.to_string()))?;
+        parse(&value).ok_or_else(|| unexpected(stdout.trim().to_string()))
+    }
+
+    /// `path` as the CLI needs it: under WSL, the Windows CLI needs a Windows path
+    fn cli_path(&self, path: &Path) -> Result<String, Error> {
+        if !self.wsl {
+            return Ok(path.display().to_string());
+        }
+        if self.verbose {
+            println!("calling wslpath");
+        }
+        windows_path(&self.runner, path)
+    }
+}
+
+impl<R: ToolRunner> DeviceBackend for AzsphereCli<R> {
+    fn deploy(&self, app_package: &Path, force: bool, manual_start: bool) -> Result<(), Error> {
+        let mut options = vec!["-p".to_string(), self.cli_path(app_package)?];
+        if force {
+            options.push("--force".to_string());
+        }
+        if manual_start {
+            options.push("-m".to_string());
+        }
+        self.run(&["device", "sideload", "deploy"], &options)?;
+        Ok(())
+    }
+
+    fn start(&self, component_id: &str, debug_mode: bool) -> Result<(), Error> {
+        let mut options = vec!["-i".to_string(), component_id.to_string()];
+        if debug_mode {
+            options.push("--debug-mode".to_string());
+        }
+        self.run(&["device", "app", "start"], &options)?;
+        Ok(())
+    }
+
+    fn stop(&self, component_id: &str) -> Result<(), Error> {
+        let options = ["-i".to_string(), component_id.to_string()];
+        self.run(&["device", "app", "stop"], &options)?;
+        Ok(())
+    }
+
+    fn delete(&self, component_id: &str) -> Result<(), Error> {
+        let options = ["-i".to_string(), component_id.to_string()];
+        self.run(&["device", "sideload", "delete"], &options)?;
+        Ok(())
+    }
+
+    fn show_status(&self, component_id: &str) -> Result<AppStatus, Error> {
+        let options = ["-i".to_string(), component_id.to_string()];
+        self.run_json(
+            &["device", "app", "show-status"],
+            &options,
+            AppStatus::from_json,
+        )
+    }
+
+    fn show_memory_stats(&self, component_id: &str) -> Result<MemoryStats, Error> {
+        let options = ["-i".to_string(), component_id.to_string()];
+        self.run_json(
+            &["device", "app", "show-memory-stats"],
+            &options,
+            MemoryStats::from_json,
+        )
+    }
+
+    fn list_attached(&self) -> Result<Vec<AttachedDevice>, Error> {
+        self.run_json(&["device", "list-attached"], &[], |value| {
+            value
+                .as_array()?
+                .iter()
+                .map(AttachedDevice::from_json)
+                .collect()
+        })
+    }
+}
+
+/// `path` as Windows sees it, from wslpath
+fn windows_path(runner: &dyn ToolRunner, path: &Path) -> Result<String, Error> {
+    let args = vec!["-w".to_string(), path.display().to_string()];
+    let output = tool::run(runner, Path::new("wslpath"), &args)?;
+    Ok(String::from_utf8_lossy(&output.stdout).replace('\n', ""))
+}
+
+#[cfg(test)]
+pub(crate) mod fake {
+    use super::*;
+    use crate::tool::fake::FakeRunner;
+
+    /// The azsphere CLI, run by a [`FakeRunner`] that records its argument lists
+    pub(crate) fn azsphere(device: Option<&str>, verbose: bool) -> AzsphereCli<FakeRunner> {
+        let mut cli = AzsphereCli::new(
+            FakeRunner::default(),
+            PathBuf::from("azsphere"),
+            vec![],
+            device.map(str::to_string),
+            verbose,
+        );
+        cli.wsl = false;
+        cli
+    }
+
+    /// The Windows CLI under WSL, whose runner answers wslpath too
+    pub(crate) fn azsphere_under_wsl(runner: FakeRunner) -> AzsphereCli<FakeRunner> {
+        let mut cli = azsphere(None, false);
+        cli.runner = runner;
+        cli.wsl = true;
+        cli
+    }
+
+    /// Argument lists the CLI was run with
+    pub(crate) fn calls(cli: &AzsphereCli<FakeRunner>) -> Vec<Vec<String>> {
+        let calls = cli.runner.calls.borrow();
+        calls.iter().map(|(_, args)| args.clone()).collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::fake::*;
+    use super::*;
+    use crate::tool::fake::{exited, FakeRunner};
+
+    #[test]
+    fn test_azsphere_cli() {
+        let cli = azsphere(None, false);
+        cli.deploy(Path::new("/t/app.imagepackage"), false, false)
+            .unwrap();
+        cli.start("c64ecd9e", false).unwrap();
+        cli.stop("c64ecd9e").unwrap();
+        cli.delete("c64ecd9e").unwrap();
+        assert_eq!(
+            calls(&cli),
+            [
+                vec!["device", "sideload", "deploy", "-p", "/t/app.imagepackage"],
+                vec!["device", "app", "start", "-i", "c64ecd9e"],
+                vec!["device", "app", "stop", "-i", "c64ecd9e"],
+                vec!["device", "sideload", "delete", "-i", "c64ecd9e"],
+            ]
+        );
+
+        let cli = azsphere_under_wsl(FakeRunner::default().reply(Ok(exited(
+            0,
+            "C:\\t\\app.imagepackage\n",
+            "",
+        ))));
+        cli.deploy(Path::new("/t/app.imagepackage"), false, false)
+            .unwrap();
+        assert_eq!(cli.runner.calls.borrow()[0].0, Path::new("wslpath"));
+        assert_eq!(
+            calls(&cli),
+            [
+                vec!["-w", "/t/app.imagepackage"],
+                vec![
+                    "device",
+                    "sideload",
+                    "deploy",
+                    "-p",
+                    "C:\\t\\app.imagepackage"
+                ],
+            ]
+        );
+
+        let mut cli = azsphere(Some("192.168.35.2"), true);
+        cli.runner = FakeRunner::default()
+            .reply(Ok(exited(0, "", "")))
+            .reply(Ok(exited(
+                0,
+                r#"{ "componentId": "c64ecd9e", "state": "running" }"#,
+                "",
+            )))
+            .reply(Ok(exited(
+                0,
+                r#"[{ "deviceId": "352fe1f5", "ipAddress": "192.168.35.2", "connectionPath": "21143" }]"#,
+                "",
+            )));
+        cli.start("c64ecd9e", true).unwrap();
+        assert_eq!(
+            cli.show_status("c64ecd9e").unwrap(),
+            AppStatus {
+                component_id: "c64ecd9e".to_string(),
+                state: "running".to_string()
+            }
+        );
+        assert_eq!(
+            cli.list_attached().unwrap(),
+            [AttachedDevice {
+                device_id: "352fe1f5".to_string(),
+                ip_address: "192.168.35.2".to_string(),
+                connection_path: "21143".to_string()
+            }]
+        );
+        assert_eq!(
+            calls(&cli),
+            [
+                vec![
+                    "device",
+                    "app",
+                    "start",
+                    "-i",
+                    "c64ecd9e",
+                    "--debug-mode",
+                    "-d",
+                    "192.168.35.2",
+                    "--verbose"
+                ],
+                vec![
+                    "device",
+                    "app",
+                    "show-status",
+                    "-i",
+                    "c64ecd9e",
+                    "--output",
+                    "json",
+                    "-d",
+                    "192.168.35.2",
+                    "--verbose"
+                ],
+                vec!["device", "list-attached", "--output", "json", "--verbose"],
+            ]
+        );
+    }
+
+    #[test]
+    fn test_azsphere_cli_failure() {
+        let mut cli = azsphere(None, false);
+        cli.runner = FakeRunner::default()
+            .reply(Ok(exited(
+                0,
+                r#"[{ "componentId": "c64ecd9e", "state": "stopped" }]"#,
+                "",
+            )))
+            .reply(Ok(exited(1, "", "error: the device is not attached")))
+            .reply(Ok(exited(0, "App state: running\n", "")))
+            .reply(Ok(exited(0, r#"{ "currentMemoryUsageInKB": 356 }"#, "")));
+        assert_eq!(cli.show_status("c64ecd9e").unwrap().state, "stopped");
+        assert!(matches!(
+            cli.stop("c64ecd9e"),
+            Err(Error::ExternalTool { stderr, .. }) if stderr == "error: the device is not attached"
+        ));
+        assert!(matches!(
+            cli.show_status("c64ecd9e"),
+            Err(Error::UnexpectedOutput(command, _)) if command == "device app show-status"
+        ));
+        assert_eq!(
+            cli.show_memory_stats("c64ecd9e").unwrap_err().to_string(),
+            "unexpected output from `azsphere device app show-memory-stats': \
+             { \"currentMemoryUsageInKB\": 356 }"
+        );
+    }
+
+    #[test]
+    fn test_windows_path() {
+        let runner = FakeRunner::default()
+            .reply(Ok(exited(0, "C:\\app.imagepackage\n", "")))
+            .reply(Ok(exited(1, "", "wslpath: /nope: No such file")));
+        let path = Path::new("/mnt/c/app.imagepackage");
+        assert_eq!(windows_path(&runner, path).unwrap(), "C:\\app.imagepackage");
+        let err = windows_path(&runner, path).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`wslpath -w /mnt/c/app.imagepackage' failed with exit status: 1:\n\
+             wslpath: /nope: No such file"
+        );
+        assert_eq!(
+            runner.calls.borrow()[0].1,
+            ["-w", "/mnt/c/app.imagepackage"]
+        );
+    }
+}
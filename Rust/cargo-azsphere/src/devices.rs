@@ -0,0 +1,239 @@
+//! The attached devices: `cargo azsphere devices`, which lists them, and acting on several of them
+//! at once, for the subcommands that take `--device` more than once or `--all-attached`.
+
+use crate::device::{AzsphereCli, DeviceBackend};
+use crate::dry_run::DryRun;
+use crate::error::Error;
+use serde_json::Value;
+use std::io::{self, Write};
+use std::sync::Mutex;
+use std::thread;
+
+#[derive(clap::Parser, Debug)]
+pub(crate) struct CliArgs {
+    /// print the devices as a JSON array
+    #[arg(long)]
+    json: bool,
+    /// enable verbose logging
+    #[arg(short)]
+    verbose: bool,
+}
+
+/// The devices a subcommand acts on
+#[derive(clap::Parser, Debug, Clone, Default)]
+#[group(skip)]
+pub(crate) struct TargetArgs {
+    /// the device to run the command on when multiple devices are attached; repeat it to run
+    /// the command on several at once.
+    /// Values from: azsphere device list-attached. Specify the ID, IP address, or Local Connection ID of the device.
+    #[arg(short, long)]
+    pub(crate) device: Vec<String>,
+    /// run the command on every attached device at once
+    #[arg(long, conflicts_with = "device")]
+    pub(crate) all_attached: bool,
+}
+
+impl TargetArgs {
+    /// The devices to act on, where `None` is the only one attached
+    pub fn devices(&self, verbose: bool) -> Result<Vec<Option<String>>, Error> {
+        if !self.all_attached {
+            if self.device.is_empty() {
+                return Ok(vec![None]);
+            }
+            return Ok(self.device.iter().cloned().map(Some).collect());
+        }
+        let attached = AzsphereCli::from_sdk(None, verbose)?.list_attached()?;
+        if attached.is_empty() {
+            return Err(Error::NoDevicesAttached);
+        }
+        Ok(attached
+            .into_iter()
+            .map(|device| Some(device.ip_address))
+            .collect())
+    }
+}
+
+/// Writes whole lines to `sink`, each prefixed, so that those of several threads don't mix
+struct Prefixed<'a, W: Write> {
+    prefix: String,
+    line: Vec<u8>,
+    sink: &'a Mutex<W>,
+}
+
+impl<W: Write> Prefixed<'_, W> {
+    fn write_line(&mut self) -> io::Result<()> {
+        let mut sink = self.sink.lock().unwrap();
+        sink.write_all(self.prefix.as_bytes())?;
+        sink.write_all(&self.line)?;
+        self.line.clear();
+        Ok(())
+    }
+}
+
+impl<W: Write> Write for Prefixed<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        for &byte in buf {
+            self.line.push(byte);
+            if byte == b'\n' {
+                self.write_line()?;
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        if !self.line.is_empty() {
+            self.line.push(b'\n');
+            self.write_line()?;
+        }
+        Ok(())
+    }
+}
+
+/// Run `action` on each of `devices`: directly when there is one, or else on all of them at once,
+/// with what each prints prefixed by its device, followed by whether it succeeded on each.  A dry
+/// run records the steps of each device in turn.
+pub(crate) fn for_each<F>(
+    devices: &[Option<String>],
+    dry_run: Option<&DryRun>,
+    action: F,
+) -> Result<(), Error>
+where
+    F: Fn(Option<String>, &mut dyn Write) -> Result<(), Error> + Sync,
+{
+    match devices {
+        [device] => action(device.clone(), &mut io::stdout()),
+        devices if dry_run.is_some() => devices
+            .iter()
+            .try_for_each(|device| action(device.clone(), &mut io::stdout())),
+        devices => for_each_to(devices, &Mutex::new(io::stdout()), action),
+    }
+}
+
+fn for_each_to<W, F>(devices: &[Option<String>], sink: &Mutex<W>, action: F) -> Result<(), Error>
+where
+    W: Write + Send,
+    F: Fn(Option<String>, &mut dyn Write) -> Result<(), Error> + Sync,
+{
+    let name = |device: &Option<String>| device.as_deref().unwrap_or("device").to_string();
+    let width = devices.iter().map(|device| name(device).len()).max();
+    let width = width.unwrap_or_default();
+    let results = thread::scope(|scope| {
+        let threads = devices
+            .iter()
+            .map(|device| {
+                let action = &action;
+                scope.spawn(move || {
+                    let mut out = Prefixed {
+                        prefix: format!("[{:<width$}] ", name(device)),
+                        line: vec![],
+                        sink,
+                    };
+                    let result = action(device.clone(), &mut out);
+                    let _ = out.flush();
+                    result
+                })
+            })
+            .collect::<Vec<_>>();
+        threads
+            .into_iter()
+            .map(|thread| thread.join().unwrap())
+            .collect::<Vec<_>>()
+    });
+
+    let mut sink = sink.lock().unwrap();
+    writeln!(sink, "Summary:")?;
+    let mut failed = 0;
+    for (device, result) in devices.iter().zip(&results) {
+        match result {
+            Ok(()) => writeln!(sink, "    {:<width$}  ok", name(device))?,
+            Err(e) => {
+                failed += 1;
+                writeln!(sink, "    {:<width$}  failed: {e}", name(device))?;
+            }
+        }
+    }
+    if failed > 0 {
+        return Err(Error::DevicesFailed(failed, devices.len()));
+    }
+    Ok(())
+}
+
+#[derive(Debug)]
+pub struct CliSetting {
+    json: bool,
+    verbose: bool,
+}
+
+impl CliSetting {
+    pub(crate) fn new(args: CliArgs) -> Self {
+        Self {
+            json: args.json,
+            verbose: args.verbose,
+        }
+    }
+
+    /// List the devices attached to this computer
+    pub fn do_devices(&self) -> Result<(), Error> {
+        let device = AzsphereCli::from_sdk(None, self.verbose)?;
+        let devices = device.list_attached()?;
+        if self.json {
+            let devices = devices.iter().map(|device| device.to_json()).collect();
+            println!("{:#}", Value::Array(devices));
+        } else if devices.is_empty() {
+            println!("No devices are attached");
+        } else {
+            println!("{:<15} {:<15} DeviceId", "IpAddress", "ConnectionPath");
+            for device in devices {
+                println!("{device}");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_for_each() {
+        let devices = [
+            Some("192.168.35.2".to_string()),
+            Some("192.168.36.2".to_string()),
+        ];
+        let sink = Mutex::new(vec![]);
+        let result = for_each_to(&devices, &sink, |device, out| {
+            let device = device.unwrap();
+            write!(out, "Sideloading ")?;
+            writeln!(out, "app.imagepackage")?;
+            if device == "192.168.36.2" {
+                write!(out, "unterminated")?;
+                return Err(Error::NoDevicesAttached);
+            }
+            Ok(())
+        });
+        assert!(matches!(result, Err(Error::DevicesFailed(1, 2))));
+        let out = String::from_utf8(sink.into_inner().unwrap()).unwrap();
+        let mut lines = out.lines().collect::<Vec<_>>();
+        let summary = lines.split_off(lines.len() - 3);
+        // The devices' lines may interleave, but not mix
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                "[192.168.35.2] Sideloading app.imagepackage",
+                "[192.168.36.2] Sideloading app.imagepackage",
+                "[192.168.36.2] unterminated",
+            ]
+        );
+        assert_eq!(
+            summary,
+            [
+                "Summary:",
+                "    192.168.35.2  ok",
+                "    192.168.36.2  failed: no devices are attached",
+            ]
+        );
+    }
+}
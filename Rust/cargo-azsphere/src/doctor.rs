@@ -0,0 +1,459 @@
+//! `cargo azsphere doctor`: check the prerequisites of the other subcommands, and say how to fix
+//! the ones that are missing.
+
+use crate::config::CargoConfig;
+use crate::error::Error;
+use crate::util;
+use crate::workspace::Workspace;
+use std::env;
+use std::ffi::OsStr;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::net::{SocketAddr, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::time::Duration;
+
+/// Rust target of Azure Sphere high-level apps
+const TARGET: &str = "armv7-unknown-linux-musleabihf";
+
+/// The device's address on the Azure Sphere network interface, and its REST API port
+const DEVICE_ADDRESS: &str = "192.168.35.2:443";
+
+const DEVICE_TIMEOUT: Duration = Duration::from_secs(2);
+
+#[derive(clap::Parser, Debug)]
+pub(crate) struct CliArgs {
+    /// package whose ARV to check the Sysroot of
+    #[arg(short, long)]
+    package: Option<String>,
+    /// Application Runtime Version to check the Sysroot of [DEFAULT = the crate's arv]
+    #[arg(long)]
+    arv: Option<String>,
+    /// display verbose output
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+#[derive(Debug)]
+pub struct CliSetting {
+    package: Option<String>,
+    arv: Option<String>,
+    verbose: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Status {
+    Ok,
+    Warning,
+    Failed,
+    Skipped,
+}
+
+impl Display for Status {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Status::Ok => "ok",
+            Status::Warning => "warning",
+            Status::Failed => "FAILED",
+            Status::Skipped => "skipped",
+        })
+    }
+}
+
+/// The outcome of one check
+#[derive(Debug)]
+struct Check {
+    name: &'static str,
+    status: Status,
+    detail: String,
+    /// how to fix it, when it didn't pass
+    fix: Option<String>,
+}
+
+impl Check {
+    fn ok(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Ok,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    fn skipped(name: &'static str, detail: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Skipped,
+            detail: detail.into(),
+            fix: None,
+        }
+    }
+
+    fn failed(name: &'static str, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            name,
+            status: Status::Failed,
+            detail: detail.into(),
+            fix: Some(fix.into()),
+        }
+    }
+
+    fn warning(name: &'static str, detail: impl Into<String>, fix: impl Into<String>) -> Self {
+        Self {
+            status: Status::Warning,
+            ..Self::failed(name, detail, fix)
+        }
+    }
+}
+
+impl CliSetting {
+    pub(crate) fn new(args: CliArgs) -> Self {
+        Self {
+            package: args.package,
+            arv: args.arv,
+            verbose: args.verbose,
+        }
+    }
+
+    /// The ARV from --arv, or from the selected crate
+    fn arv(&self) -> Option<String> {
+        if self.arv.is_some() {
+            return self.arv.clone();
+        }
+        let workspace = Workspace::load(self.verbose).ok()?;
+        let member = workspace.member(self.package.as_deref()).ok()?;
+        let package_config = workspace.package_config(&member, &[], self.verbose).ok()?;
+        Some(package_config.arv)
+    }
+
+    pub fn do_doctor(&self) -> Result<(), Error> {
+        let sdk = util::sdk_path();
+        let arv = self.arv();
+        let sysroot_arv = arv
+            .clone()
+            .or_else(|| sdk.as_deref().and_then(|sdk| installed_sysroots(sdk).pop()));
+        let device = DEVICE_ADDRESS.parse().unwrap();
+
+        let checks = vec![
+            check_sdk(sdk.as_deref()),
+            check_sysroot(sdk.as_deref(), arv.as_deref()),
+            check_sysroot_tool(sdk.as_deref(), sysroot_arv.as_deref(), "gdb"),
+            check_rustup_target(),
+            check_linker(&env::current_dir()?),
+            check_wsl(),
+            check_device(&device, DEVICE_TIMEOUT),
+        ];
+        print!("{}", table(&checks));
+
+        let failed = checks
+            .iter()
+            .filter(|check| check.status == Status::Failed)
+            .count();
+        if failed > 0 {
+            return Err(Error::DoctorFailed(failed));
+        }
+        Ok(())
+    }
+}
+
+fn check_sdk(sdk: Option<&Path>) -> Check {
+    const NAME: &str = "Azure Sphere SDK";
+    let fix = "install the Azure Sphere SDK, then set AzureSphereDefaultSDKDir to its directory, \
+               such as /opt/azurespheresdk";
+    match sdk {
+        None => Check::failed(NAME, "AzureSphereDefaultSDKDir is not set", fix),
+        Some(sdk) if !sdk.is_dir() => {
+            Check::failed(NAME, format!("{} does not exist", sdk.display()), fix)
+        }
+        Some(sdk) => Check::ok(NAME, sdk.display().to_string()),
+    }
+}
+
+/// ARVs with a Sysroot in the SDK, lowest first
+fn installed_sysroots(sdk: &Path) -> Vec<String> {
+    let mut arvs = fs::read_dir(sdk.join("Sysroots"))
+        .into_iter()
+        .flatten()
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| entry.path().is_dir())
+        .filter_map(|entry| entry.file_name().into_string().ok())
+        .collect::<Vec<_>>();
+    arvs.sort_by_key(|arv| (arv.parse::<u32>().ok(), arv.clone()));
+    arvs
+}
+
+fn check_sysroot(sdk: Option<&Path>, arv: Option<&str>) -> Check {
+    const NAME: &str = "Sysroot";
+    let Some(sdk) = sdk.filter(|sdk| sdk.is_dir()) else {
+        return Check::skipped(NAME, "needs the SDK");
+    };
+    let installed = installed_sysroots(sdk);
+    match arv {
+        None if installed.is_empty() => Check::failed(
+            NAME,
+            "no Sysroots are installed",
+            "reinstall the Azure Sphere SDK",
+        ),
+        None => Check::warning(
+            NAME,
+            format!("no crate found; installed: {}", installed.join(", ")),
+            "run from a crate, or choose an ARV with --arv",
+        ),
+        Some(arv) if installed.iter().any(|installed| installed == arv) => {
+            Check::ok(NAME, format!("ARV {arv}"))
+        }
+        Some(arv) => Check::failed(
+            NAME,
+            format!(
+                "ARV {arv} is not installed; installed: {}",
+                if installed.is_empty() {
+                    "none".to_string()
+                } else {
+                    installed.join(", ")
+                }
+            ),
+            format!(
+                "set arv in [package.metadata.azsphere] or AZURE_SPHERE_ARV in .cargo/config to \
+                 an installed ARV, or install an SDK that has Sysroot {arv}"
+            ),
+        ),
+    }
+}
+
+fn check_sysroot_tool(sdk: Option<&Path>, arv: Option<&str>, name: &'static str) -> Check {
+    let (Some(sdk), Some(arv)) = (sdk.filter(|sdk| sdk.is_dir()), arv) else {
+        return Check::skipped(name, "needs the SDK and a Sysroot");
+    };
+    let path = util::sysroot_tool(sdk, arv, name);
+    if path.is_file() {
+        Check::ok(name, path.display().to_string())
+    } else {
+        Check::failed(
+            name,
+            format!("{} does not exist", path.display()),
+            format!("reinstall the Azure Sphere SDK, or its Sysroot {arv}"),
+        )
+    }
+}
+
+/// `program` in one of the directories of `path_var`
+fn find_on_path(program: &str, path_var: Option<&OsStr>) -> Option<PathBuf> {
+    env::split_paths(path_var?)
+        .map(|dir| dir.join(program))
+        .find(|path| path.is_file())
+}
+
+fn check_rustup_target() -> Check {
+    const NAME: &str = "Rust target";
+    let fix = format!("rustup target add {TARGET}");
+    let output = match Command::new("rustup")
+        .args(["target", "list", "--installed"])
+        .output()
+    {
+        Ok(output) if output.status.success() => output,
+        _ => {
+            return Check::warning(
+                NAME,
+                "rustup not found, so the targets can't be listed",
+                format!("make sure the Rust standard library for {TARGET} is installed"),
+            )
+        }
+    };
+    if String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .any(|line| line.trim() == TARGET)
+    {
+        Check::ok(NAME, TARGET)
+    } else {
+        Check::failed(NAME, format!("{TARGET} is not installed"), fix)
+    }
+}
+
+/// The linker cargo uses for the Azure Sphere target, and where it is set
+fn configured_linker(dir: &Path) -> Result<Option<(String, String)>, Error> {
+    let var = format!(
+        "CARGO_TARGET_{}_LINKER",
+        TARGET.to_uppercase().replace('-', "_")
+    );
+    if let Ok(linker) = env::var(&var) {
+        return Ok(Some((linker, var)));
+    }
+    let linker = CargoConfig::load(dir)?.get_str(&["target", TARGET, "linker"])?;
+    Ok(linker.map(|linker| (linker.value, linker.source.to_string())))
+}
+
+fn check_linker(dir: &Path) -> Check {
+    const NAME: &str = "Linker";
+    match configured_linker(dir) {
+        Err(e) => Check::failed(NAME, e.to_string(), "correct the Cargo config file"),
+        Ok(None) => Check::failed(
+            NAME,
+            format!("no linker is configured for {TARGET}"),
+            format!(
+                "add [target.{TARGET}] linker = \"<SDK>/Sysroots/<ARV>/tools/sysroots/\
+                 x86_64-pokysdk-linux/usr/bin/arm-poky-linux-musleabi/arm-poky-linux-musleabi-gcc\" \
+                 to .cargo/config"
+            ),
+        ),
+        Ok(Some((linker, source))) => {
+            let found = Path::new(&linker).is_file()
+                || find_on_path(&linker, env::var_os("PATH").as_deref()).is_some();
+            if found {
+                Check::ok(NAME, format!("{linker} (from {source})"))
+            } else {
+                Check::failed(
+                    NAME,
+                    format!("{linker}, from {source}, does not exist"),
+                    format!("correct the linker in {source}, or install the SDK Sysroot it names"),
+                )
+            }
+        }
+    }
+}
+
+/// Whether this is Linux running under WSL
+fn is_wsl_kernel() -> bool {
+    fs::read_to_string("/proc/sys/kernel/osrelease")
+        .is_ok_and(|release| release.to_lowercase().contains("microsoft"))
+}
+
+fn check_wsl() -> Check {
+    const NAME: &str = "WSL interop";
+    if !is_wsl_kernel() {
+        return Check::skipped(NAME, "not running under WSL");
+    }
+    if !util::is_wsl() {
+        return Check::failed(
+            NAME,
+            "Windows interop is disabled, so the Windows azsphere CLI can't be run",
+            "enable interop in /etc/wsl.conf ([interop] enabled = true), then run 'wsl --shutdown'",
+        );
+    }
+    let azsphere = match util::azsphere_tool_path() {
+        Ok((azsphere, _)) => azsphere,
+        Err(e) => return Check::failed(NAME, e.to_string(), "install the Azure Sphere SDK"),
+    };
+    if azsphere.is_file() {
+        Check::ok(NAME, azsphere.display().to_string())
+    } else {
+        Check::failed(
+            NAME,
+            format!("{} does not exist", azsphere.display()),
+            "install the Azure Sphere SDK for Windows",
+        )
+    }
+}
+
+fn check_device(address: &SocketAddr, timeout: Duration) -> Check {
+    const NAME: &str = "Device";
+    match TcpStream::connect_timeout(address, timeout) {
+        Ok(_) => Check::ok(NAME, format!("{address} is reachable")),
+        Err(e) => Check::failed(
+            NAME,
+            format!("{address} is unreachable: {e}"),
+            "attach the device over USB, and check it's listed by 'azsphere device list-attached'; \
+             on Linux, run the SDK's azsphere_connect.sh",
+        ),
+    }
+}
+
+/// The checks as a table, followed by how to fix those that didn't pass
+fn table(checks: &[Check]) -> String {
+    let name_width = checks
+        .iter()
+        .map(|check| check.name.len())
+        .max()
+        .unwrap_or(0);
+    let mut text = String::new();
+    for check in checks {
+        text += &format!(
+            "{:name_width$}  {:7}  {}\n",
+            check.name, check.status, check.detail
+        );
+    }
+    let fixes = checks
+        .iter()
+        .filter_map(|check| Some((check.name, check.fix.as_ref()?)))
+        .collect::<Vec<_>>();
+    if !fixes.is_empty() {
+        text += "\nTo fix:\n";
+        for (name, fix) in fixes {
+            text += &format!("  {name}: {fix}\n");
+        }
+    }
+    text
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+
+    #[test]
+    fn test_checks() {
+        let sdk = tempfile::tempdir().unwrap();
+        let sdk = sdk.path();
+        assert_eq!(check_sdk(None).status, Status::Failed);
+        assert_eq!(check_sdk(Some(&sdk.join("nope"))).status, Status::Failed);
+        assert_eq!(check_sdk(Some(sdk)).status, Status::Ok);
+        assert_eq!(check_sysroot(None, Some("16")).status, Status::Skipped);
+        assert_eq!(check_sysroot(Some(sdk), Some("16")).status, Status::Failed);
+
+        let strip = util::sysroot_tool(sdk, "16", "strip");
+        fs::create_dir_all(strip.parent().unwrap()).unwrap();
+        fs::write(&strip, "").unwrap();
+        fs::create_dir_all(sdk.join("Sysroots/9")).unwrap();
+        assert_eq!(installed_sysroots(sdk), ["9", "16"]);
+        assert_eq!(check_sysroot(Some(sdk), Some("16")).status, Status::Ok);
+        assert_eq!(check_sysroot(Some(sdk), None).status, Status::Warning);
+        let check = check_sysroot(Some(sdk), Some("15"));
+        assert_eq!(check.detail, "ARV 15 is not installed; installed: 9, 16");
+        assert_eq!(
+            check_sysroot_tool(Some(sdk), Some("16"), "strip").status,
+            Status::Ok
+        );
+        assert_eq!(
+            check_sysroot_tool(Some(sdk), Some("16"), "gdb").status,
+            Status::Failed
+        );
+
+        let crate_dir = sdk.join("app");
+        fs::create_dir_all(crate_dir.join("src")).unwrap();
+        fs::create_dir_all(sdk.join(".cargo")).unwrap();
+        fs::write(
+            sdk.join(".cargo/config"),
+            format!(
+                "[target.{TARGET}]\nlinker = \"{}\"\n",
+                util::sysroot_tool(sdk, "16", "gcc").display()
+            ),
+        )
+        .unwrap();
+        let check = check_linker(&crate_dir.join("src"));
+        assert_eq!(check.status, Status::Failed, "{check:?}");
+        fs::write(util::sysroot_tool(sdk, "16", "gcc"), "").unwrap();
+        assert_eq!(check_linker(&crate_dir).status, Status::Ok);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let timeout = Duration::from_millis(500);
+        assert_eq!(check_device(&address, timeout).status, Status::Ok);
+        drop(listener);
+        assert_eq!(check_device(&address, timeout).status, Status::Failed);
+    }
+
+    #[test]
+    fn test_table() {
+        let checks = [
+            Check::ok("SDK", "/opt/azurespheresdk"),
+            Check::failed("patchelf", "not found on PATH", "install it"),
+        ];
+        assert_eq!(
+            table(&checks),
+            "SDK       ok       /opt/azurespheresdk\n\
+             patchelf  FAILED   not found on PATH\n\
+             \n\
+             To fix:\n  patchelf: install it\n"
+        );
+    }
+}
@@ -0,0 +1,295 @@
+//! `--dry-run`: report what a subcommand would do, without changing the target directory or the
+//! device.  Each step is printed as it is reached, as text or as a line of JSON, with the
+//! resolved package config first and every external command fully expanded.
+//!
+//! Tools that only answer questions, such as wslpath, still run, so that the commands they
+//! feed show the arguments they would really get.
+
+use crate::error::{self, Error};
+use crate::tool::{SystemRunner, ToolRunner};
+use serde_json::{json, Value};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::{ExitStatus, Output};
+use std::sync::Mutex;
+
+/// Tools that run even in a dry run
+const READ_ONLY_TOOLS: [&str; 1] = ["wslpath"];
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Format {
+    Text,
+    /// one JSON object per line
+    Json,
+}
+
+#[derive(clap::Parser, Debug, Clone, Default)]
+#[group(skip)]
+pub(crate) struct CliArgs {
+    /// print what would be done, as text or JSON, without doing it
+    #[arg(
+        long,
+        value_enum,
+        value_name = "FORMAT",
+        num_args = 0..=1,
+        require_equals = true,
+        default_missing_value = "text"
+    )]
+    dry_run: Option<Format>,
+}
+
+impl CliArgs {
+    pub(crate) fn dry_run(&self) -> Option<DryRun> {
+        self.dry_run.map(DryRun::new)
+    }
+}
+
+/// Something a subcommand does
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Step {
+    /// the resolved package config of a crate
+    Config(Value),
+    RemoveDir(PathBuf),
+    CreateDir(PathBuf),
+    Write(PathBuf),
+    Copy(PathBuf, PathBuf),
+    /// set the interpreter of `from`, keep that as `unstripped`, and strip it into `to`
+    Strip {
+        from: PathBuf,
+        unstripped: PathBuf,
+        to: PathBuf,
+    },
+    /// pack a directory into an app package
+    Pack(PathBuf, PathBuf),
+    Run {
+        program: PathBuf,
+        args: Vec<String>,
+        /// environment variables set for it
+        env: Vec<(String, String)>,
+    },
+    /// connect to the app's output
+    Connect(String),
+}
+
+impl Step {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Step::Config(config) => json!({ "step": "config", "config": config }),
+            Step::RemoveDir(path) => json!({ "step": "remove_dir", "path": path }),
+            Step::CreateDir(path) => json!({ "step": "create_dir", "path": path }),
+            Step::Write(path) => json!({ "step": "write", "path": path }),
+            Step::Copy(from, to) => json!({ "step": "copy", "from": from, "to": to }),
+            Step::Strip {
+                from,
+                unstripped,
+                to,
+            } => json!({ "step": "strip", "from": from, "unstripped": unstripped, "to": to }),
+            Step::Pack(dir, package) => json!({ "step": "pack", "dir": dir, "package": package }),
+            Step::Run { program, args, env } => json!({
+                "step": "run",
+                "program": program,
+                "args": args,
+                "env": env
+                    .iter()
+                    .map(|(name, value)| (name.clone(), Value::from(value.as_str())))
+                    .collect::<serde_json::Map<_, _>>(),
+            }),
+            Step::Connect(address) => json!({ "step": "connect", "address": address }),
+        }
+    }
+}
+
+impl Display for Step {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Step::Config(config) => {
+                let config = serde_json::to_string_pretty(config).map_err(|_| std::fmt::Error)?;
+                write!(f, "package config: {config}")
+            }
+            Step::RemoveDir(path) => write!(f, "remove {}", path.display()),
+            Step::CreateDir(path) => write!(f, "create {}", path.display()),
+            Step::Write(path) => write!(f, "write {}", path.display()),
+            Step::Copy(from, to) => write!(f, "copy {} to {}", from.display(), to.display()),
+            Step::Strip {
+                from,
+                unstripped,
+                to,
+            } => write!(
+                f,
+                "set the interpreter of {}, keeping it as {}, and strip it into {}",
+                from.display(),
+                unstripped.display(),
+                to.display()
+            ),
+            Step::Pack(dir, package) => {
+                write!(f, "pack {} into {}", dir.display(), package.display())
+            }
+            Step::Run { program, args, env } => {
+                write!(f, "run ")?;
+                for (name, value) in env {
+                    write!(f, "{name}={value} ")?;
+                }
+                write!(f, "{}", error::command_line(program, args))
+            }
+            Step::Connect(address) => write!(f, "connect to {address}"),
+        }
+    }
+}
+
+/// The steps of a dry run, which are printed as they are recorded, by any thread
+#[derive(Debug)]
+pub(crate) struct DryRun {
+    format: Format,
+    pub steps: Mutex<Vec<Step>>,
+}
+
+impl DryRun {
+    pub fn new(format: Format) -> Self {
+        Self {
+            format,
+            steps: Mutex::new(vec![]),
+        }
+    }
+
+    pub fn record(&self, step: Step) {
+        let mut steps = self.steps.lock().unwrap();
+        match self.format {
+            Format::Text => println!("[dry run] {step}"),
+            Format::Json => println!("{}", step.to_json()),
+        }
+        steps.push(step);
+    }
+}
+
+/// Changes to files, which in a dry run are only recorded
+#[derive(Clone, Copy)]
+pub(crate) struct Changes<'a>(pub Option<&'a DryRun>);
+
+impl Changes<'_> {
+    /// Remove `path` and its contents, if it exists
+    pub fn remove_dir_all(&self, path: &Path) {
+        match self.0 {
+            Some(dry_run) => dry_run.record(Step::RemoveDir(path.to_path_buf())),
+            // Failures, such as the directory not existing, are ignored
+            None => {
+                let _ = fs::remove_dir_all(path);
+            }
+        }
+    }
+
+    pub fn create_dir_all(&self, path: &Path) -> Result<(), Error> {
+        match self.0 {
+            Some(dry_run) => {
+                dry_run.record(Step::CreateDir(path.to_path_buf()));
+                Ok(())
+            }
+            None => fs::create_dir_all(path).map_err(|e| Error::FileIo(path.to_path_buf(), e)),
+        }
+    }
+
+    pub fn write(&self, path: &Path, contents: impl AsRef<[u8]>) -> Result<(), Error> {
+        match self.0 {
+            Some(dry_run) => {
+                dry_run.record(Step::Write(path.to_path_buf()));
+                Ok(())
+            }
+            None => fs::write(path, contents).map_err(|e| Error::FileIo(path.to_path_buf(), e)),
+        }
+    }
+
+    /// Copy `from` to `to`, returning its size
+    pub fn copy(&self, from: &Path, to: &Path) -> Result<u64, Error> {
+        match self.0 {
+            Some(dry_run) => {
+                dry_run.record(Step::Copy(from.to_path_buf(), to.to_path_buf()));
+                let metadata = fs::metadata(from).map_err(|e| Error::FileIo(from.into(), e))?;
+                Ok(metadata.len())
+            }
+            None => fs::copy(from, to).map_err(|e| Error::FileIo(from.to_path_buf(), e)),
+        }
+    }
+}
+
+/// Runs only the read-only tools, and records the rest as they would be run
+pub(crate) struct DryRunner<'a>(pub &'a DryRun);
+
+impl ToolRunner for DryRunner<'_> {
+    fn output(&self, program: &Path, args: &[String]) -> io::Result<Output> {
+        self.0.record(Step::Run {
+            program: program.to_path_buf(),
+            args: args.to_vec(),
+            env: vec![],
+        });
+        let name = program.file_name().unwrap_or_default();
+        if READ_ONLY_TOOLS.iter().any(|tool| name == *tool) {
+            return SystemRunner.output(program, args);
+        }
+        Ok(Output {
+            status: ExitStatus::default(),
+            stdout: vec![],
+            stderr: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::{AzsphereCli, DeviceBackend};
+
+    #[test]
+    fn test_dry_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("README.md");
+        fs::write(&source, "readme").unwrap();
+        let out = dir.path().join("out");
+        let dry_run = DryRun::new(Format::Json);
+        let changes = Changes(Some(&dry_run));
+        changes.remove_dir_all(dir.path());
+        changes.create_dir_all(&out).unwrap();
+        assert_eq!(changes.copy(&source, &out.join("README.md")).unwrap(), 6);
+        assert!(changes.copy(&out.join("nope"), &out).is_err());
+        changes.write(&out.join("app_manifest.json"), "{}").unwrap();
+        // Nothing changed
+        assert!(source.exists());
+        assert!(!out.exists());
+
+        let cli = AzsphereCli::new(
+            DryRunner(&dry_run),
+            PathBuf::from("/opt/azurespheresdk/Tools_v2/azsphere"),
+            vec![],
+            Some("192.168.35.2".to_string()),
+            false,
+        );
+        cli.start("c64ecd9e", true).unwrap();
+        assert_eq!(
+            dry_run.steps.lock().unwrap()[5].to_string(),
+            "run /opt/azurespheresdk/Tools_v2/azsphere device app start -i c64ecd9e \
+             --debug-mode -d 192.168.35.2"
+        );
+
+        let steps = dry_run.steps.lock().unwrap();
+        assert_eq!(steps.len(), 6);
+        assert_eq!(
+            steps[2].to_json(),
+            json!({ "step": "copy", "from": source, "to": out.join("README.md") })
+        );
+        let run = Step::Run {
+            program: PathBuf::from("cargo"),
+            args: vec!["build".to_string()],
+            env: vec![("AZURE_SPHERE_ARV".to_string(), "16".to_string())],
+        };
+        assert_eq!(run.to_string(), "run AZURE_SPHERE_ARV=16 cargo build");
+        assert_eq!(
+            run.to_json(),
+            json!({
+                "step": "run",
+                "program": "cargo",
+                "args": ["build"],
+                "env": { "AZURE_SPHERE_ARV": "16" }
+            })
+        );
+    }
+}
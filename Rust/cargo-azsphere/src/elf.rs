@@ -0,0 +1,195 @@
+//! In-process rewriting of the app executable, in place of `patchelf --set-interpreter` and the
+//! SDK's `strip --strip-debug --strip-unneeded`.
+//!
+//! The Rust toolchain links against the SDK's glibc-flavoured sysroot, so executables name
+//! `/lib/ld-linux-armhf.so.3` as their interpreter, while the device has musl's.  The
+//! interpreter is rewritten in place, so the new one can be no longer than the old.
+
+use crate::error::ImageError;
+use object::build::elf::{Builder, SectionData};
+use object::build::Bytes;
+use object::elf;
+
+/// Interpreter of Azure Sphere high-level apps
+pub const MUSL_INTERPRETER: &str = "/lib/ld-musl-armhf.so.1";
+
+fn read(data: &[u8]) -> Result<Builder<'_>, ImageError> {
+    Builder::read(data).map_err(|e| ImageError::Elf(e.to_string()))
+}
+
+fn write(builder: Builder<'_>) -> Result<Vec<u8>, ImageError> {
+    let mut buffer = Vec::new();
+    builder
+        .write(&mut buffer)
+        .map_err(|e| ImageError::Elf(e.to_string()))?;
+    Ok(buffer)
+}
+
+/// The interpreter named by PT_INTERP, if there is one
+pub fn interpreter(data: &[u8]) -> Result<Option<String>, ImageError> {
+    let builder = read(data)?;
+    Ok(builder.interp_data().map(|interp| {
+        let interp = interp.split(|&b| b == 0).next().unwrap_or_default();
+        String::from_utf8_lossy(interp).to_string()
+    }))
+}
+
+/// Rewrite the PT_INTERP of the executable `data` to `interpreter`
+pub fn set_interpreter(data: &[u8], interpreter: &str) -> Result<Vec<u8>, ImageError> {
+    let mut builder = read(data)?;
+    let id = builder
+        .interp_section()
+        .ok_or_else(|| ImageError::Elf("no PT_INTERP segment".to_string()))?;
+
+    let mut interp = interpreter.as_bytes().to_vec();
+    interp.push(0);
+    let section = builder.sections.get_mut(id);
+    if interp.len() as u64 > section.sh_size {
+        return Err(ImageError::InterpreterTooLong(
+            interpreter.to_string(),
+            section.sh_size.saturating_sub(1),
+        ));
+    }
+    section.sh_size = interp.len() as u64;
+    section.data = SectionData::Data(Bytes::from(interp));
+
+    for segment in builder.segments.iter_mut() {
+        if segment.p_type == elf::PT_INTERP {
+            segment.recalculate_ranges(&builder.sections);
+        }
+    }
+    write(builder)
+}
+
+/// Debug sections, and the symbol table, which nothing needs at run time
+fn is_unneeded(name: &[u8], sh_type: u32, sh_flags: u64) -> bool {
+    sh_flags & u64::from(elf::SHF_ALLOC) == 0
+        && (name.starts_with(b".debug")
+            || name.starts_with(b".zdebug")
+            || sh_type == elf::SHT_SYMTAB
+            || name == b".strtab")
+}
+
+/// Remove debug sections and the symbol table from the executable `data`
+pub fn strip(data: &[u8]) -> Result<Vec<u8>, ImageError> {
+    let mut builder = read(data)?;
+    for section in builder.sections.iter_mut() {
+        if is_unneeded(&section.name, section.sh_type, section.sh_flags) {
+            section.delete = true;
+        }
+    }
+    for symbol in builder.symbols.iter_mut() {
+        symbol.delete = true;
+    }
+    // Relocations of the deleted sections, which only linking with --emit-relocs leaves behind
+    let deleted = builder
+        .sections
+        .iter()
+        .filter(|section| section.delete)
+        .map(|section| section.id())
+        .collect::<Vec<_>>();
+    for section in builder.sections.iter_mut() {
+        if section
+            .sh_info_section
+            .is_some_and(|target| deleted.contains(&target))
+            && matches!(section.sh_type, elf::SHT_REL | elf::SHT_RELA)
+        {
+            section.delete = true;
+        }
+    }
+    builder.delete_orphans();
+    write(builder)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::applibs;
+    use object::{Object, ObjectSection};
+    use std::fs;
+    use std::path::Path;
+
+    /// An ARM executable, linked by rust-lld against a stub shared library, that imports
+    /// GPIO_SetValue and Log_Debug, with debug sections and a symbol table
+    fn arm_app() -> Vec<u8> {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/elf/arm_app");
+        fs::read(path).unwrap()
+    }
+
+    fn section_names(data: &[u8]) -> Vec<String> {
+        let file = object::File::parse(data).unwrap();
+        file.sections()
+            .filter_map(|section| section.name().ok().map(|name| name.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn test_set_interpreter() {
+        let original = arm_app();
+        assert_eq!(
+            interpreter(&original).unwrap().as_deref(),
+            Some("/lib/ld-linux-armhf.so.3")
+        );
+
+        let rewritten = set_interpreter(&original, MUSL_INTERPRETER).unwrap();
+        assert_eq!(
+            interpreter(&rewritten).unwrap().as_deref(),
+            Some(MUSL_INTERPRETER)
+        );
+        let file = object::File::parse(rewritten.as_slice()).unwrap();
+        assert_eq!(
+            file.entry(),
+            object::File::parse(original.as_slice()).unwrap().entry()
+        );
+        assert_eq!(
+            applibs::imported_symbols(&rewritten).unwrap(),
+            applibs::imported_symbols(&original).unwrap()
+        );
+        assert_eq!(section_names(&rewritten), section_names(&original));
+
+        // Setting it again changes nothing
+        assert_eq!(
+            set_interpreter(&rewritten, MUSL_INTERPRETER).unwrap(),
+            rewritten
+        );
+        assert!(matches!(
+            set_interpreter(&original, "/lib/a-much-longer-interpreter-name.so.1"),
+            Err(ImageError::InterpreterTooLong(_, 24))
+        ));
+        assert!(matches!(
+            set_interpreter(b"not an elf", MUSL_INTERPRETER),
+            Err(ImageError::Elf(_))
+        ));
+    }
+
+    #[test]
+    fn test_strip() {
+        let original = set_interpreter(&arm_app(), MUSL_INTERPRETER).unwrap();
+        let stripped = strip(&original).unwrap();
+        assert!(stripped.len() < original.len() - 4096);
+
+        let names = section_names(&stripped);
+        assert!(names.contains(&".text".to_string()));
+        assert!(names.contains(&".dynsym".to_string()));
+        for name in [".debug_info", ".debug_str", ".symtab", ".strtab"] {
+            assert!(section_names(&original).contains(&name.to_string()));
+            assert!(!names.contains(&name.to_string()), "{name}");
+        }
+        assert_eq!(
+            interpreter(&stripped).unwrap().as_deref(),
+            Some(MUSL_INTERPRETER)
+        );
+        assert_eq!(
+            applibs::imported_symbols(&stripped).unwrap(),
+            applibs::imported_symbols(&original).unwrap()
+        );
+        // Loadable contents are unchanged
+        let text = |data: &[u8]| {
+            let file = object::File::parse(data).unwrap();
+            let section = file.section_by_name(".text").unwrap();
+            (section.address(), section.data().unwrap().to_vec())
+        };
+        assert_eq!(text(&stripped), text(&original));
+        assert_eq!(strip(&stripped).unwrap(), stripped);
+    }
+}
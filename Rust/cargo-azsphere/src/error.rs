@@ -2,7 +2,8 @@ use cargo_toml::Error as CargoTomlError;
 use std::error::Error as StdError;
 use std::fmt::{Debug, Display, Formatter};
 use std::io::Error as IoError;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::ExitStatus;
 use toml::de::Error as TomlDeError;
 
 #[derive(thiserror::Error, Debug, Clone, PartialEq, Eq, Hash)]
@@ -27,6 +28,44 @@ pub enum ConfigError {
     BranchPathNotFoundInToml(String),
     #[error("Expected one string or two in array")]
     MalFormedArray,
+    #[error("Unknown capability `{0}'")]
+    UnknownCapability(String),
+    #[error("Peripheral `{0}' needs target_hardware and target_definition to be set")]
+    NoHardwareDefinition(String),
+    #[error("{0} and {1} cannot both be set")]
+    Conflicting(String, String),
+    #[error("extra_files[{0}]: {1}")]
+    ExtraFile(usize, String),
+    #[error("Partner `{0}' would be built as a high-level app, which would replace the app; partners must be prebuilt real-time apps, given by `image_package'")]
+    HighLevelPartner(String),
+}
+
+#[derive(thiserror::Error, Debug, Clone)]
+pub enum ImageError {
+    #[error("{0} is missing from the package directory")]
+    MissingFile(String),
+    #[error("app manifest: {0}")]
+    InvalidManifest(String),
+    #[error("hardware definition `{0}' not found")]
+    HardwareDefinitionNotFound(String),
+    #[error("{0}: {1}")]
+    InvalidHardwareDefinition(PathBuf, String),
+    #[error("peripheral `{0}' is not defined by the target hardware definition")]
+    UnknownPeripheral(String),
+    #[error("file name `{0}' is too long for the image file system")]
+    NameTooLong(String),
+    #[error("file `{0}' is too large for the image file system")]
+    FileTooLarge(String),
+    #[error("invalid target API set `{0}'")]
+    InvalidTargetApiSet(String),
+    #[error("malformed image package: {0}")]
+    Malformed(String),
+    #[error("executable: {0}")]
+    Elf(String),
+    #[error("interpreter `{0}' doesn't fit in the executable's {1} bytes; link with -Wl,--dynamic-linker={0}")]
+    InterpreterTooLong(String, u64),
+    #[error("{0} is {1} bytes, over its budget of {2} KB ({3})")]
+    OverBudget(&'static str, u64, u64, &'static str),
 }
 
 #[derive(thiserror::Error, Debug)]
@@ -41,6 +80,25 @@ impl<E: StdError + Display> Display for FileAnnotatedError<E> {
     }
 }
 
+/// `program` and its arguments, as they'd be typed
+pub(crate) fn command_line(program: &Path, args: &[String]) -> String {
+    let mut line = program.display().to_string();
+    for arg in args {
+        line.push(' ');
+        line.push_str(arg);
+    }
+    line
+}
+
+/// What a failed tool printed, preferring its stderr
+fn tool_output(stdout: &str, stderr: &str) -> String {
+    match (stdout, stderr) {
+        ("", "") => String::new(),
+        (stdout, "") => format!(":\n{stdout}"),
+        (_, stderr) => format!(":\n{stderr}"),
+    }
+}
+
 #[derive(thiserror::Error, Debug)]
 pub enum Error {
     #[error(transparent)]
@@ -58,6 +116,78 @@ pub enum Error {
     #[error(transparent)]
     Io(#[from] IoError),
 
+    #[error(transparent)]
+    Image(#[from] ImageError),
+
+    #[error("{0}: {1}")]
+    Json(PathBuf, #[source] serde_json::Error),
+
     #[error(transparent)]
     ParseTomlFile(#[from] FileAnnotatedError<TomlDeError>),
+
+    #[error("'cargo metadata' failed: {0}")]
+    CargoMetadata(String),
+
+    #[error("package `{0}' is not a member of the workspace")]
+    UnknownPackage(String),
+
+    #[error("{0} packages match; choose one with --package")]
+    AmbiguousPackage(usize),
+
+    #[error("{0} already exists")]
+    AlreadyExists(PathBuf),
+
+    #[error("`{0}' is not a valid package name")]
+    InvalidPackageName(String),
+
+    #[error("the hardware crate was not found; specify its directory with --hardware")]
+    HardwareCrateNotFound,
+
+    #[error("{0} check(s) failed")]
+    DoctorFailed(usize),
+
+    #[error("`{}' failed with {status}{}", command_line(.program, .args), tool_output(.stdout, .stderr))]
+    ExternalTool {
+        program: PathBuf,
+        args: Vec<String>,
+        status: ExitStatus,
+        stdout: String,
+        stderr: String,
+    },
+
+    #[error("the Azure Sphere SDK was not found: {0}; install it and set AzureSphereDefaultSDKDir to its directory")]
+    MissingSdk(String),
+
+    #[error("app manifest {0}: {1}")]
+    BadAppManifest(PathBuf, String),
+
+    #[error("symbols of {0}: {1}")]
+    Symbols(PathBuf, String),
+
+    #[error("device {0} is unreachable")]
+    DeviceUnreachable(String, #[source] IoError),
+
+    #[error("unexpected output from `azsphere {0}': {1}")]
+    UnexpectedOutput(String, String),
+
+    #[error("no devices are attached")]
+    NoDevicesAttached,
+
+    #[error("{0} of {1} devices failed")]
+    DevicesFailed(usize, usize),
+
+    #[error("{0} needs a single device; choose one with --device")]
+    SeveralDevices(&'static str),
+
+    #[error("{0} test(s) failed")]
+    TestsFailed(usize),
+
+    #[error("the output of {0} lacks the start or the result of its test run")]
+    TestsIncomplete(String),
+
+    #[error("{0} could not be built or run")]
+    TestsNotRun(String),
+
+    #[error("{0} problem(s) found with the partners")]
+    Partners(usize),
 }
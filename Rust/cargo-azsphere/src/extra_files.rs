@@ -0,0 +1,354 @@
+//! The files `extra_files` adds to the app package.  Each entry names a file, a glob pattern or a
+//! directory, relative to Cargo.toml or, with `from_build_script`, to the build script's
+//! `OUT_DIR`, and where in the package to put it:
+//!
+//! ```toml
+//! extra_files = [
+//!     ["README.md", "files/README.md"],
+//!     ["certs/*.pem"],
+//!     { source = "web", dest = "www", exclude = ["*.map"] },
+//!     { from_build_script = "config.bin", dest = "data/config.bin" },
+//! ]
+//! ```
+
+use crate::error::ConfigError;
+use glob::Pattern;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use toml::Value;
+
+/// A file to copy into the app package
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct ExtraFile {
+    pub source: PathBuf,
+    /// path in the app package
+    pub destination: PathBuf,
+}
+
+/// An `extra_files` entry
+#[derive(Debug)]
+struct Entry {
+    source: String,
+    from_build_script: bool,
+    /// the file's path in the package, or the directory the files of a pattern or directory
+    /// go in
+    destination: Option<String>,
+    exclude: Vec<Pattern>,
+}
+
+const KEYS: [&str; 4] = ["source", "from_build_script", "dest", "exclude"];
+
+fn is_pattern(path: &str) -> bool {
+    path.contains(['*', '?', '['])
+}
+
+/// The leading components of `pattern` that have no wildcards
+fn literal_base(pattern: &str) -> PathBuf {
+    Path::new(pattern)
+        .components()
+        .take_while(|component| !is_pattern(&component.as_os_str().to_string_lossy()))
+        .collect()
+}
+
+impl Entry {
+    fn parse(value: &Value) -> Result<Self, String> {
+        match value {
+            Value::Array(array) => {
+                let strings = array.iter().map(Value::as_str).collect::<Option<Vec<_>>>();
+                let (source, destination) = match strings.as_deref() {
+                    Some([source]) => (source.to_string(), None),
+                    Some([source, destination]) => {
+                        (source.to_string(), Some(destination.to_string()))
+                    }
+                    _ => return Err(ConfigError::MalFormedArray.to_string()),
+                };
+                Ok(Self {
+                    source,
+                    from_build_script: false,
+                    destination,
+                    exclude: vec![],
+                })
+            }
+            Value::Table(table) => {
+                if let Some(key) = table.keys().find(|key| !KEYS.contains(&key.as_str())) {
+                    return Err(format!("unknown key `{key}'"));
+                }
+                let string = |key: &str| match table.get(key) {
+                    None => Ok(None),
+                    Some(Value::String(value)) => Ok(Some(value.clone())),
+                    Some(_) => Err(format!("`{key}' must be a string")),
+                };
+                let (source, from_build_script) =
+                    match (string("source")?, string("from_build_script")?) {
+                        (Some(source), None) => (source, false),
+                        (None, Some(source)) => (source, true),
+                        _ => return Err("set one of `source' and `from_build_script'".to_string()),
+                    };
+                let exclude = match table.get("exclude") {
+                    None => vec![],
+                    Some(Value::Array(patterns)) => patterns
+                        .iter()
+                        .map(|pattern| {
+                            let pattern = pattern
+                                .as_str()
+                                .ok_or("`exclude' must be an array of strings")?;
+                            Pattern::new(pattern)
+                                .map_err(|e| format!("exclude pattern `{pattern}': {e}"))
+                        })
+                        .collect::<Result<_, String>>()?,
+                    Some(_) => return Err("`exclude' must be an array of strings".to_string()),
+                };
+                Ok(Self {
+                    source,
+                    from_build_script,
+                    destination: string("dest")?,
+                    exclude,
+                })
+            }
+            _ => Err("expected an array or a table".to_string()),
+        }
+    }
+
+    /// The files the entry names, under `base_dir`
+    fn expand(&self, base_dir: &Path) -> Result<Vec<ExtraFile>, String> {
+        let source = base_dir.join(&self.source);
+        if !is_pattern(&self.source) && source.is_file() {
+            let destination = self.destination.as_deref().unwrap_or(&self.source);
+            return Ok(vec![ExtraFile {
+                source,
+                destination: PathBuf::from(destination),
+            }]);
+        }
+        // A pattern, or a directory, whose files go under the destination directory as they
+        // are under its base
+        let (base, pattern) = if is_pattern(&self.source) {
+            (literal_base(&self.source), self.source.clone())
+        } else if source.is_dir() {
+            (PathBuf::from(&self.source), format!("{}/**/*", self.source))
+        } else {
+            return Err(format!("`{}' does not exist", source.display()));
+        };
+        let pattern = format!(
+            "{}/{pattern}",
+            Pattern::escape(&base_dir.display().to_string())
+        );
+        let prefix = self
+            .destination
+            .as_ref()
+            .map(PathBuf::from)
+            .unwrap_or_else(|| base.clone());
+        let base = base_dir.join(base);
+        let mut files = vec![];
+        let paths = glob::glob(&pattern).map_err(|e| format!("`{}': {e}", self.source))?;
+        for path in paths {
+            let path = path.map_err(|e| e.to_string())?;
+            if !path.is_file() {
+                continue;
+            }
+            let relative = path.strip_prefix(&base).unwrap_or(&path).to_path_buf();
+            if self
+                .exclude
+                .iter()
+                .any(|exclude| exclude.matches_path(&relative))
+            {
+                continue;
+            }
+            files.push(ExtraFile {
+                source: path,
+                destination: prefix.join(relative),
+            });
+        }
+        if files.is_empty() {
+            return Err(format!("`{}' matches no files", self.source));
+        }
+        Ok(files)
+    }
+}
+
+/// Whether `destination` stays inside the package
+fn is_inside(destination: &Path) -> bool {
+    destination
+        .components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+        && destination
+            .components()
+            .any(|component| matches!(component, Component::Normal(_)))
+}
+
+/// The files of the `extra_files` entries.  Sources are relative to `crate_dir`, or to the
+/// directory `out_dir` finds, for `from_build_script`.
+pub(crate) fn resolve(
+    entries: &[Value],
+    crate_dir: &Path,
+    out_dir: &dyn Fn() -> Result<PathBuf, String>,
+) -> Result<Vec<ExtraFile>, ConfigError> {
+    let mut files = vec![];
+    let mut added = HashMap::new();
+    for (index, value) in entries.iter().enumerate() {
+        let error = |message: String| ConfigError::ExtraFile(index, message);
+        let entry = Entry::parse(value).map_err(error)?;
+        let base_dir = if entry.from_build_script {
+            out_dir().map_err(error)?
+        } else {
+            crate_dir.to_path_buf()
+        };
+        for file in entry.expand(&base_dir).map_err(error)? {
+            if !is_inside(&file.destination) {
+                return Err(error(format!(
+                    "destination `{}' is not inside the package",
+                    file.destination.display()
+                )));
+            }
+            if let Some(other) = added.insert(file.destination.clone(), index) {
+                return Err(error(format!(
+                    "`{}' is also added by extra_files[{other}]",
+                    file.destination.display()
+                )));
+            }
+            files.push(file);
+        }
+    }
+    Ok(files)
+}
+
+/// `OUT_DIR` of the build script of the crate `name`, in `target_path`: the one that ran last,
+/// when there are several
+pub(crate) fn build_script_out_dir(target_path: &Path, name: &str) -> Result<PathBuf, String> {
+    let prefix = format!("{name}-");
+    let entries = fs::read_dir(target_path.join("build"))
+        .into_iter()
+        .flatten();
+    // Named `<name>-<hash>`, which the directories of a crate `<name>-common` don't match
+    let is_own = |file_name: &str| {
+        file_name
+            .strip_prefix(&prefix)
+            .is_some_and(|hash| !hash.is_empty() && hash.chars().all(|c| c.is_ascii_hexdigit()))
+    };
+    entries
+        .filter_map(|entry| entry.ok())
+        .filter(|entry| is_own(&entry.file_name().to_string_lossy()))
+        .filter_map(|entry| {
+            // The build script's captured output, which is rewritten each time it runs
+            let modified = fs::metadata(entry.path().join("output"))
+                .and_then(|metadata| metadata.modified())
+                .ok()?;
+            Some((modified, entry.path().join("out")))
+        })
+        .filter(|(_, out_dir)| out_dir.is_dir())
+        .max_by_key(|(modified, _)| *modified)
+        .map(|(_, out_dir)| out_dir)
+        .ok_or_else(|| format!("no build script output of {name}; build it first"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, SystemTime};
+
+    #[test]
+    fn test_resolve() {
+        let dir = tempfile::tempdir().unwrap();
+        let crate_dir = dir.path().join("app");
+        let out_dir = dir.path().join("target/build/app-1234/out");
+        for (path, contents) in [
+            ("app/README.md", "readme"),
+            ("app/certs/a.pem", "a"),
+            ("app/certs/b.pem", "b"),
+            ("app/certs/notes.txt", "notes"),
+            ("app/web/index.html", "<html>"),
+            ("app/web/js/app.js", "js"),
+            ("app/web/js/app.js.map", "map"),
+            ("target/build/app-1234/out/config.bin", "config"),
+        ] {
+            let path = dir.path().join(path);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, contents).unwrap();
+        }
+        let found_out_dir = || Ok(out_dir.clone());
+        let entries = toml::toml! {
+            extra_files = [
+                ["README.md", "files/README.md"],
+                ["certs/*.pem"],
+                { source = "web", dest = "www", exclude = ["*.map"] },
+                { from_build_script = "config.bin", dest = "data/config.bin" },
+            ]
+        };
+        let entries = entries["extra_files"].as_array().unwrap();
+        let files = resolve(entries, &crate_dir, &found_out_dir).unwrap();
+        let file = |source: &Path, destination: &str| ExtraFile {
+            source: source.to_path_buf(),
+            destination: PathBuf::from(destination),
+        };
+        assert_eq!(
+            files,
+            [
+                file(&crate_dir.join("README.md"), "files/README.md"),
+                file(&crate_dir.join("certs/a.pem"), "certs/a.pem"),
+                file(&crate_dir.join("certs/b.pem"), "certs/b.pem"),
+                file(&crate_dir.join("web/index.html"), "www/index.html"),
+                file(&crate_dir.join("web/js/app.js"), "www/js/app.js"),
+                file(&out_dir.join("config.bin"), "data/config.bin"),
+            ]
+        );
+
+        let no_out_dir = || Err("no build script output of app; build it first".to_string());
+        let error = |entry: Value| {
+            resolve(&[entry], &crate_dir, &no_out_dir)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            error(toml::toml! { x = ["nope.txt"] }["x"].clone()),
+            format!(
+                "extra_files[0]: `{}' does not exist",
+                crate_dir.join("nope.txt").display()
+            )
+        );
+        assert_eq!(
+            error(toml::toml! { x = ["certs/*.der"] }["x"].clone()),
+            "extra_files[0]: `certs/*.der' matches no files"
+        );
+        assert_eq!(
+            error(toml::toml! { x = ["README.md", "../README.md"] }["x"].clone()),
+            "extra_files[0]: destination `../README.md' is not inside the package"
+        );
+        assert_eq!(
+            error(toml::toml! { x = { source = "web", exlude = ["*.map"] } }["x"].clone()),
+            "extra_files[0]: unknown key `exlude'"
+        );
+        assert_eq!(
+            error(toml::toml! { x = { from_build_script = "config.bin" } }["x"].clone()),
+            "extra_files[0]: no build script output of app; build it first"
+        );
+        assert_eq!(
+            error(Value::Array(vec![])),
+            "extra_files[0]: Expected one string or two in array"
+        );
+        let duplicate = toml::toml! { x = [["README.md", "certs/a.pem"], ["certs/*.pem"]] };
+        assert_eq!(
+            resolve(duplicate["x"].as_array().unwrap(), &crate_dir, &no_out_dir)
+                .unwrap_err()
+                .to_string(),
+            "extra_files[1]: `certs/a.pem' is also added by extra_files[0]"
+        );
+
+        let target_path = dir.path().join("target");
+        assert!(build_script_out_dir(&target_path, "app").is_err());
+        fs::write(target_path.join("build/app-1234/output"), "").unwrap();
+        assert_eq!(build_script_out_dir(&target_path, "app").unwrap(), out_dir);
+
+        // The newer output of a crate whose name starts with `app-` isn't taken for app's
+        let other = target_path.join("build/app-common-5678");
+        fs::create_dir_all(other.join("out")).unwrap();
+        let output = fs::File::create(other.join("output")).unwrap();
+        output
+            .set_modified(SystemTime::now() + Duration::from_secs(60))
+            .unwrap();
+        assert_eq!(build_script_out_dir(&target_path, "app").unwrap(), out_dir);
+        assert_eq!(
+            build_script_out_dir(&target_path, "app-common").unwrap(),
+            other.join("out")
+        );
+    }
+}
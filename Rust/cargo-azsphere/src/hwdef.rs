@@ -0,0 +1,232 @@
+//! Azure Sphere hardware definition files.
+//!
+//! A hardware definition is a JSON file listing named peripherals.  Each peripheral either carries
+//! an `AppManifestValue` directly, or a `Mapping` to a peripheral defined by one of its `Imports`.
+//! App manifests refer to peripherals by name, prefixed with `$`.
+
+use crate::error::{Error, ImageError};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Imports nested deeper than this are assumed to be circular
+const MAX_IMPORT_DEPTH: usize = 16;
+
+#[derive(Debug, Clone)]
+pub struct Peripheral {
+    /// peripheral type, such as Gpio, Uart or I2cMaster
+    pub peripheral_type: String,
+    /// name of the peripheral this one is an alias for
+    pub mapping: Option<String>,
+    /// value to use in the app manifest
+    pub app_manifest_value: Option<Value>,
+    /// number of imports between the top-level file and the file defining this peripheral
+    pub depth: usize,
+}
+
+#[derive(Debug)]
+pub struct HardwareDefinition {
+    peripherals: HashMap<String, Peripheral>,
+    /// the files loaded: the top-level file, then its imports
+    files: Vec<PathBuf>,
+}
+
+impl HardwareDefinition {
+    /// Load `filename` and its imports, searching each of `search_dirs` in order
+    pub fn load(search_dirs: &[PathBuf], filename: &str) -> Result<Self, Error> {
+        let path = search_dirs
+            .iter()
+            .map(|dir| dir.join(filename))
+            .find(|path| path.exists())
+            .ok_or_else(|| ImageError::HardwareDefinitionNotFound(filename.to_string()))?;
+        let mut definition = Self {
+            peripherals: HashMap::new(),
+            files: vec![],
+        };
+        definition.load_file(&path, search_dirs, 0)?;
+        Ok(definition)
+    }
+
+    fn load_file(
+        &mut self,
+        path: &Path,
+        search_dirs: &[PathBuf],
+        depth: usize,
+    ) -> Result<(), Error> {
+        if depth > MAX_IMPORT_DEPTH {
+            return Err(ImageError::InvalidHardwareDefinition(
+                path.to_path_buf(),
+                "imports are nested too deeply".to_string(),
+            )
+            .into());
+        }
+        let invalid = |message: &str| {
+            ImageError::InvalidHardwareDefinition(path.to_path_buf(), message.to_string())
+        };
+        let text = fs::read_to_string(path).map_err(|e| Error::FileIo(path.to_path_buf(), e))?;
+        self.files.push(path.to_path_buf());
+        let json: Value =
+            serde_json::from_str(&text).map_err(|e| Error::Json(path.to_path_buf(), e))?;
+
+        for peripheral in json["Peripherals"].as_array().into_iter().flatten() {
+            let name = peripheral["Name"]
+                .as_str()
+                .ok_or_else(|| invalid("peripheral without a Name"))?;
+            let peripheral_type = peripheral["Type"]
+                .as_str()
+                .ok_or_else(|| invalid("peripheral without a Type"))?;
+            // Definitions closer to the top-level file take precedence over imported ones
+            self.peripherals
+                .entry(name.to_string())
+                .or_insert_with(|| Peripheral {
+                    peripheral_type: peripheral_type.to_string(),
+                    mapping: peripheral["Mapping"].as_str().map(|s| s.to_string()),
+                    app_manifest_value: peripheral.get("AppManifestValue").cloned(),
+                    depth,
+                });
+        }
+
+        let base_dir = path.parent().unwrap_or_else(|| Path::new("."));
+        for import in json["Imports"].as_array().into_iter().flatten() {
+            let import = import["Path"]
+                .as_str()
+                .ok_or_else(|| invalid("import without a Path"))?;
+            let import_path = std::iter::once(base_dir.to_path_buf())
+                .chain(search_dirs.iter().cloned())
+                .map(|dir| dir.join(import))
+                .find(|path| path.exists())
+                .ok_or_else(|| ImageError::HardwareDefinitionNotFound(import.to_string()))?;
+            self.load_file(&import_path, search_dirs, depth + 1)?;
+        }
+        Ok(())
+    }
+
+    /// The files the definition was loaded from
+    pub fn files(&self) -> &[PathBuf] {
+        &self.files
+    }
+
+    pub fn peripheral(&self, name: &str) -> Option<&Peripheral> {
+        self.peripherals.get(name)
+    }
+
+    /// Follow the chain of mappings from `name` to the value used in an app manifest
+    pub fn app_manifest_value(&self, name: &str) -> Result<&Value, ImageError> {
+        let mut current = name;
+        for _ in 0..=MAX_IMPORT_DEPTH {
+            let peripheral = self
+                .peripheral(current)
+                .ok_or_else(|| ImageError::UnknownPeripheral(current.to_string()))?;
+            if let Some(value) = &peripheral.app_manifest_value {
+                return Ok(value);
+            }
+            current = peripheral
+                .mapping
+                .as_deref()
+                .ok_or_else(|| ImageError::UnknownPeripheral(name.to_string()))?;
+        }
+        Err(ImageError::UnknownPeripheral(name.to_string()))
+    }
+
+    /// Names of the peripherals of `peripheral_type` that resolve to `value`, keeping only those
+    /// defined closest to the top-level file, as they carry the most specific names
+    pub fn peripherals_for_value(&self, peripheral_type: &str, value: &Value) -> Vec<&str> {
+        let mut matches = self
+            .peripherals
+            .iter()
+            .filter(|(name, peripheral)| {
+                peripheral.peripheral_type == peripheral_type
+                    && self.app_manifest_value(name).ok() == Some(value)
+            })
+            .map(|(name, peripheral)| (peripheral.depth, name.as_str()))
+            .collect::<Vec<_>>();
+        matches.sort();
+        let depth = matches.first().map(|(depth, _)| *depth);
+        matches
+            .into_iter()
+            .filter(|(d, _)| Some(*d) == depth)
+            .map(|(_, name)| name)
+            .collect()
+    }
+
+    /// Replace each `$NAME` reference under the manifest's `Capabilities` with its value
+    pub fn resolve_manifest(&self, manifest: &mut Value) -> Result<(), ImageError> {
+        if let Some(capabilities) = manifest.get_mut("Capabilities") {
+            self.resolve_value(capabilities)?;
+        }
+        Ok(())
+    }
+
+    fn resolve_value(&self, value: &mut Value) -> Result<(), ImageError> {
+        match value {
+            Value::String(s) if s.starts_with('$') => {
+                *value = self.app_manifest_value(&s[1..])?.clone();
+            }
+            Value::Array(items) => {
+                for item in items {
+                    self.resolve_value(item)?;
+                }
+            }
+            Value::Object(map) => {
+                for (_, item) in map.iter_mut() {
+                    self.resolve_value(item)?;
+                }
+            }
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    fn fixtures() -> Vec<PathBuf> {
+        vec![PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/HardwareDefinitions")]
+    }
+
+    #[test]
+    fn test_resolve_manifest() {
+        let definition =
+            HardwareDefinition::load(&fixtures(), "test_board/sample_appliance.json").unwrap();
+        assert_eq!(definition.files().len(), 3);
+        assert!(definition.files()[0].ends_with("test_board/sample_appliance.json"));
+        assert_eq!(
+            definition.app_manifest_value("SAMPLE_LED").unwrap(),
+            &json!(8)
+        );
+        assert!(definition
+            .peripheral("SAMPLE_UART")
+            .unwrap()
+            .mapping
+            .is_some());
+
+        let mut manifest = json!({
+            "Name": "$SAMPLE_LED",
+            "Capabilities": {
+                "Gpio": ["$SAMPLE_LED", "$SAMPLE_BUTTON", 12],
+                "Uart": ["$SAMPLE_UART"]
+            }
+        });
+        definition.resolve_manifest(&mut manifest).unwrap();
+        assert_eq!(
+            manifest,
+            json!({
+                "Name": "$SAMPLE_LED",
+                "Capabilities": {
+                    "Gpio": [8, 12, 12],
+                    "Uart": ["ISU0"]
+                }
+            })
+        );
+
+        let mut manifest = json!({ "Capabilities": { "Gpio": ["$NOT_THERE"] } });
+        assert!(matches!(
+            definition.resolve_manifest(&mut manifest),
+            Err(ImageError::UnknownPeripheral(name)) if name == "NOT_THERE"
+        ));
+    }
+}
@@ -0,0 +1,390 @@
+//! ASXipFS, the read-only file system at the start of every image package.
+//!
+//! The layout follows cramfs, except that file data is stored uncompressed and contiguously so
+//! that executables can run in place from flash:
+//!
+//! ```text
+//! superblock | root inode | directory entries | file data | padding to a page boundary
+//! ```
+//!
+//! An inode is three little-endian words: `mode | uid << 16`, `size | gid << 24` and
+//! `namelen / 4 | (offset / 4) << 6`, followed by the entry name padded to four bytes.  A
+//! directory's offset points at its entries and its size is their total length.  A file's offset
+//! points at its data, which is page-aligned for anything under `bin/`.
+
+use crate::error::{Error, ImageError};
+use std::collections::{HashSet, VecDeque};
+use std::fs;
+use std::ops::Range;
+use std::path::Path;
+
+pub(crate) const MAGIC: u32 = 0x28cd3d45;
+pub(crate) const SIGNATURE: &[u8; 16] = b"Compressed ROMFS";
+pub(crate) const SUPERBLOCK_SIZE: usize = 64;
+pub(crate) const INODE_SIZE: usize = 12;
+pub(crate) const PAGE_SIZE: usize = 4096;
+pub(crate) const CRC_OFFSET: usize = 32;
+
+const FLAG_FSID_VERSION_2: u32 = 0x1;
+const FLAG_SORTED_DIRS: u32 = 0x2;
+const VOLUME_NAME: &[u8] = b"ASXipFS";
+const MAX_FILE_SIZE: usize = (1 << 24) - 1;
+const MAX_NAME_LEN: usize = 63 * 4;
+const MAX_OFFSET: usize = ((1 << 26) - 1) * 4;
+/// Deepest directory nesting `list` follows, as images may come from anywhere
+const MAX_DEPTH: usize = 64;
+
+pub(crate) const MODE_DIR: u16 = 0o040555;
+const MODE_FILE: u16 = 0o100444;
+const MODE_EXECUTABLE: u16 = 0o100555;
+
+enum Kind {
+    Dir(Vec<usize>),
+    File(Vec<u8>),
+}
+
+struct Entry {
+    name: String,
+    /// path relative to the package root, for error messages
+    path: String,
+    kind: Kind,
+    executable: bool,
+    offset: usize,
+    size: usize,
+}
+
+impl Entry {
+    fn mode(&self) -> u16 {
+        match self.kind {
+            Kind::Dir(_) => MODE_DIR,
+            Kind::File(_) if self.executable => MODE_EXECUTABLE,
+            Kind::File(_) => MODE_FILE,
+        }
+    }
+}
+
+fn padded_name_len(name: &str) -> usize {
+    (name.len() + 3) & !3
+}
+
+fn align(offset: usize, alignment: usize) -> usize {
+    offset.div_ceil(alignment) * alignment
+}
+
+/// A file in a file system image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileEntry {
+    /// path relative to the package root, using `/` separators
+    pub path: String,
+    pub mode: u16,
+    pub size: usize,
+    offset: usize,
+}
+
+impl FileEntry {
+    pub fn is_executable(&self) -> bool {
+        self.mode & 0o111 != 0
+    }
+
+    /// Byte range of the file's data within the file system image
+    pub fn range(&self) -> Range<usize> {
+        self.offset..self.offset + self.size
+    }
+}
+
+pub(crate) fn encode_inode(
+    mode: u16,
+    size: usize,
+    namelen: usize,
+    offset: usize,
+) -> [u8; INODE_SIZE] {
+    let mut inode = [0u8; INODE_SIZE];
+    inode[0..4].copy_from_slice(&(mode as u32).to_le_bytes());
+    inode[4..8].copy_from_slice(&(size as u32 & 0x00ff_ffff).to_le_bytes());
+    let word = ((namelen / 4) as u32 & 0x3f) | (((offset / 4) as u32) << 6);
+    inode[8..12].copy_from_slice(&word.to_le_bytes());
+    inode
+}
+
+fn decode_inode(inode: &[u8]) -> (u16, usize, usize, usize) {
+    let word = |i: usize| u32::from_le_bytes(inode[i * 4..i * 4 + 4].try_into().unwrap());
+    let mode = (word(0) & 0xffff) as u16;
+    let size = (word(1) & 0x00ff_ffff) as usize;
+    let namelen = ((word(2) & 0x3f) * 4) as usize;
+    let offset = ((word(2) >> 6) * 4) as usize;
+    (mode, size, namelen, offset)
+}
+
+/// Read `dir` and everything below it into `entries`, returning the index of `dir`
+fn read_tree(
+    entries: &mut Vec<Entry>,
+    dir: &Path,
+    name: String,
+    path: String,
+) -> Result<usize, Error> {
+    let index = entries.len();
+    entries.push(Entry {
+        name,
+        path: path.clone(),
+        kind: Kind::Dir(vec![]),
+        executable: false,
+        offset: 0,
+        size: 0,
+    });
+
+    let mut children = fs::read_dir(dir)
+        .map_err(|e| Error::FileIo(dir.to_path_buf(), e))?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| Error::FileIo(dir.to_path_buf(), e))?;
+    // Directories are stored sorted by name, as cramfs lookups rely on it
+    children.sort_by(|a, b| a.file_name().cmp(&b.file_name()));
+
+    let mut child_indexes = vec![];
+    for child in children {
+        let child_name = child.file_name().unwrap().to_string_lossy().to_string();
+        let child_path = if path.is_empty() {
+            child_name.clone()
+        } else {
+            format!("{path}/{child_name}")
+        };
+        if child_name.len() > MAX_NAME_LEN {
+            return Err(ImageError::NameTooLong(child_path).into());
+        }
+        if child.is_dir() {
+            child_indexes.push(read_tree(entries, &child, child_name, child_path)?);
+        } else {
+            let data = fs::read(&child).map_err(|e| Error::FileIo(child.clone(), e))?;
+            if data.len() > MAX_FILE_SIZE {
+                return Err(ImageError::FileTooLarge(child_path).into());
+            }
+            child_indexes.push(entries.len());
+            entries.push(Entry {
+                name: child_name,
+                executable: child_path.starts_with("bin/"),
+                path: child_path,
+                size: data.len(),
+                kind: Kind::File(data),
+                offset: 0,
+            });
+        }
+    }
+    entries[index].kind = Kind::Dir(child_indexes);
+    Ok(index)
+}
+
+/// Build a file system image holding the contents of `root`
+pub(crate) fn build(root: &Path) -> Result<Vec<u8>, Error> {
+    let mut entries = vec![];
+    read_tree(&mut entries, root, String::new(), String::new())?;
+
+    // Directory entries come first, breadth first, followed by the file data in the same order
+    let mut order = vec![];
+    let mut queue = VecDeque::from([0usize]);
+    while let Some(index) = queue.pop_front() {
+        order.push(index);
+        if let Kind::Dir(children) = &entries[index].kind {
+            queue.extend(children.iter().copied());
+        }
+    }
+
+    let mut offset = SUPERBLOCK_SIZE + INODE_SIZE;
+    for &index in &order {
+        if let Kind::Dir(children) = &entries[index].kind {
+            let size: usize = children
+                .iter()
+                .map(|&child| INODE_SIZE + padded_name_len(&entries[child].name))
+                .sum();
+            entries[index].size = size;
+            entries[index].offset = if size == 0 { 0 } else { offset };
+            offset += size;
+        }
+    }
+    for &index in &order {
+        let entry = &mut entries[index];
+        if matches!(entry.kind, Kind::File(_)) && entry.size > 0 {
+            offset = align(offset, if entry.executable { PAGE_SIZE } else { 4 });
+            entry.offset = offset;
+            offset += entry.size;
+        }
+    }
+    if offset > MAX_OFFSET {
+        return Err(ImageError::FileTooLarge(entries[*order.last().unwrap()].path.clone()).into());
+    }
+
+    let mut image = vec![0u8; align(offset, PAGE_SIZE)];
+    for &index in &order {
+        let entry = &entries[index];
+        match &entry.kind {
+            Kind::Dir(children) => {
+                let mut position = entry.offset;
+                for &child in children {
+                    let child = &entries[child];
+                    let namelen = padded_name_len(&child.name);
+                    image[position..position + INODE_SIZE].copy_from_slice(&encode_inode(
+                        child.mode(),
+                        child.size,
+                        namelen,
+                        child.offset,
+                    ));
+                    position += INODE_SIZE;
+                    image[position..position + child.name.len()]
+                        .copy_from_slice(child.name.as_bytes());
+                    position += namelen;
+                }
+            }
+            Kind::File(data) => {
+                image[entry.offset..entry.offset + data.len()].copy_from_slice(data);
+            }
+        }
+    }
+
+    let size = image.len();
+    image[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+    image[4..8].copy_from_slice(&(size as u32).to_le_bytes());
+    image[8..12].copy_from_slice(&(FLAG_FSID_VERSION_2 | FLAG_SORTED_DIRS).to_le_bytes());
+    image[16..32].copy_from_slice(SIGNATURE);
+    // fsid: crc, edition, blocks, files
+    image[36..40].copy_from_slice(&0u32.to_le_bytes());
+    image[40..44].copy_from_slice(&((size / PAGE_SIZE) as u32).to_le_bytes());
+    image[44..48].copy_from_slice(&(entries.len() as u32).to_le_bytes());
+    image[48..48 + VOLUME_NAME.len()].copy_from_slice(VOLUME_NAME);
+    let root = &entries[0];
+    image[SUPERBLOCK_SIZE..SUPERBLOCK_SIZE + INODE_SIZE].copy_from_slice(&encode_inode(
+        MODE_DIR,
+        root.size,
+        0,
+        root.offset,
+    ));
+
+    let crc = crc32fast::hash(&image);
+    image[CRC_OFFSET..CRC_OFFSET + 4].copy_from_slice(&crc.to_le_bytes());
+    Ok(image)
+}
+
+/// List the files in a file system image, in directory order.  Returns the size of the image and
+/// its files.
+pub(crate) fn list(image: &[u8]) -> Result<(usize, Vec<FileEntry>), ImageError> {
+    let malformed = |what: &str| ImageError::Malformed(format!("file system {what}"));
+    let superblock = image
+        .get(..SUPERBLOCK_SIZE + INODE_SIZE)
+        .ok_or_else(|| malformed("superblock is truncated"))?;
+    if u32::from_le_bytes(superblock[0..4].try_into().unwrap()) != MAGIC
+        || &superblock[16..32] != SIGNATURE
+    {
+        return Err(malformed("magic not found"));
+    }
+    let size = u32::from_le_bytes(superblock[4..8].try_into().unwrap()) as usize;
+    let image = image
+        .get(..size)
+        .ok_or_else(|| malformed("image is truncated"))?;
+
+    let mut files = vec![];
+    let (_, root_size, _, root_offset) = decode_inode(&superblock[SUPERBLOCK_SIZE..]);
+    let mut dirs = VecDeque::from([(String::new(), root_offset, root_size, 0)]);
+    // Each directory's entries are read once, so that one that contains itself can't loop
+    let mut visited = HashSet::new();
+    while let Some((dir, offset, dir_size, depth)) = dirs.pop_front() {
+        if depth > MAX_DEPTH {
+            return Err(malformed("directories are nested too deeply"));
+        }
+        if dir_size > 0 && !visited.insert(offset) {
+            return Err(malformed("directory contains itself"));
+        }
+        let mut position = offset;
+        while position < offset + dir_size {
+            let inode = image
+                .get(position..position + INODE_SIZE)
+                .ok_or_else(|| malformed("directory is truncated"))?;
+            let (mode, entry_size, namelen, entry_offset) = decode_inode(inode);
+            position += INODE_SIZE;
+            let name = image
+                .get(position..position + namelen)
+                .ok_or_else(|| malformed("directory is truncated"))?;
+            position += namelen;
+            let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+            let name = String::from_utf8_lossy(&name[..len]);
+            let path = if dir.is_empty() {
+                name.to_string()
+            } else {
+                format!("{dir}/{name}")
+            };
+            if mode & 0o170000 == MODE_DIR & 0o170000 {
+                dirs.push_back((path, entry_offset, entry_size, depth + 1));
+            } else {
+                if entry_offset + entry_size > image.len() {
+                    return Err(malformed("file data is truncated"));
+                }
+                files.push(FileEntry {
+                    path,
+                    mode,
+                    size: entry_size,
+                    offset: entry_offset,
+                });
+            }
+        }
+    }
+    Ok((size, files))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_build_and_list() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("bin")).unwrap();
+        fs::create_dir_all(dir.path().join("data/empty")).unwrap();
+        fs::write(dir.path().join("bin/app"), b"executable").unwrap();
+        fs::write(dir.path().join("data/b.txt"), b"bb").unwrap();
+        fs::write(dir.path().join("data/a.txt"), b"a").unwrap();
+        fs::write(dir.path().join("empty.txt"), b"").unwrap();
+
+        let image = build(dir.path()).unwrap();
+        let (size, files) = list(&image).unwrap();
+        assert_eq!(size, image.len());
+        assert_eq!(
+            files
+                .iter()
+                .map(|f| (f.path.as_str(), f.size, f.is_executable()))
+                .collect::<Vec<_>>(),
+            [
+                ("empty.txt", 0, false),
+                ("bin/app", 10, true),
+                ("data/a.txt", 1, false),
+                ("data/b.txt", 2, false),
+            ]
+        );
+        assert_eq!(&image[files[1].range()], b"executable");
+        assert_eq!(files[1].range().start % PAGE_SIZE, 0);
+        assert_eq!(&image[files[3].range()], b"bb");
+
+        let mut crc_image = image.clone();
+        crc_image[CRC_OFFSET..CRC_OFFSET + 4].fill(0);
+        assert_eq!(
+            crc32fast::hash(&crc_image).to_le_bytes(),
+            image[CRC_OFFSET..CRC_OFFSET + 4]
+        );
+
+        assert!(list(&image[..100]).is_err());
+
+        // A directory whose entries are its parent's
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(dir.path().join("d")).unwrap();
+        fs::write(dir.path().join("d/f"), b"f").unwrap();
+        let mut image = build(dir.path()).unwrap();
+        let root_entries = SUPERBLOCK_SIZE + INODE_SIZE;
+        image[root_entries..root_entries + INODE_SIZE].copy_from_slice(&encode_inode(
+            MODE_DIR,
+            INODE_SIZE + 4,
+            4,
+            root_entries,
+        ));
+        assert!(matches!(list(&image), Err(ImageError::Malformed(_))));
+        assert!(list(
+            b"not a file system image, but long enough to hold a superblock and root inode"
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,309 @@
+//! Image metadata, appended after the file system image.
+//!
+//! The metadata starts with a header (magic, section count), followed by the sections.  Each
+//! section is a `u16` id made of two ASCII characters, a `u16` payload length and the payload.
+//! All integers are little-endian.
+
+use crate::error::ImageError;
+use std::fmt::{Display, Formatter};
+
+pub(crate) const MAGIC: u32 = 0x4D345834;
+pub(crate) const HEADER_SIZE: usize = 8;
+
+const fn section_id(code: &[u8; 2]) -> u16 {
+    u16::from_le_bytes(*code)
+}
+
+pub(crate) const IDENTITY: u16 = section_id(b"ID");
+pub(crate) const SIGNATURE: u16 = section_id(b"SG");
+pub(crate) const DEBUG: u16 = section_id(b"DB");
+pub(crate) const TEMP_IMAGE: u16 = section_id(b"TM");
+pub(crate) const ABI_DEPENDS: u16 = section_id(b"ND");
+
+/// Image type of a high-level or real-time application
+pub(crate) const IMAGE_TYPE_APPLICATIONS: u16 = 10;
+/// Signing type of an ECDSA P-256 signature
+pub(crate) const SIGNING_TYPE_ECDSA256: u32 = 1;
+/// The image was sideloaded for development and may be replaced at any time
+pub(crate) const TEMP_IMAGE_UNDER_DEVELOPMENT: u32 = 0x2;
+/// ABI type of the application runtime, versioned by the ARV
+pub(crate) const ABI_TYPE_APPLICATION_RUNTIME: u32 = 1;
+/// ABI type of the beta APIs that accompany an application runtime version
+pub(crate) const ABI_TYPE_BETA_API_SET: u32 = 2;
+
+const DEBUG_NAME_SIZE: usize = 32;
+
+/// A GUID, stored in the Windows mixed-endian layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub struct Guid(pub [u8; 16]);
+
+impl Guid {
+    pub fn parse(text: &str) -> Option<Self> {
+        let groups = text.split('-').collect::<Vec<_>>();
+        let lengths = groups.iter().map(|g| g.len()).collect::<Vec<_>>();
+        if lengths != [8, 4, 4, 4, 12] || !text.chars().all(|c| c == '-' || c.is_ascii_hexdigit()) {
+            return None;
+        }
+        let hex = groups.concat();
+        let mut bytes = [0u8; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).ok()?;
+        }
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        Some(Self(bytes))
+    }
+}
+
+impl Display for Guid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let b = &self.0;
+        write!(
+            f,
+            "{:08x}-{:04x}-{:04x}-",
+            u32::from_le_bytes([b[0], b[1], b[2], b[3]]),
+            u16::from_le_bytes([b[4], b[5]]),
+            u16::from_le_bytes([b[6], b[7]])
+        )?;
+        for byte in &b[8..10] {
+            write!(f, "{byte:02x}")?;
+        }
+        write!(f, "-")?;
+        for byte in &b[10..] {
+            write!(f, "{byte:02x}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AbiVersion {
+    pub abi_type: u32,
+    pub version: u32,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Section {
+    Identity {
+        image_type: u16,
+        component_id: Guid,
+        image_id: Guid,
+    },
+    Signature {
+        signing_type: u32,
+        thumbprint: [u8; 20],
+    },
+    Debug {
+        /// seconds since the Unix epoch
+        build_date: u64,
+        name: String,
+    },
+    TempImage {
+        flags: u32,
+    },
+    AbiDepends(Vec<AbiVersion>),
+    Unknown {
+        id: u16,
+        data: Vec<u8>,
+    },
+}
+
+impl Section {
+    pub fn id(&self) -> u16 {
+        match self {
+            Section::Identity { .. } => IDENTITY,
+            Section::Signature { .. } => SIGNATURE,
+            Section::Debug { .. } => DEBUG,
+            Section::TempImage { .. } => TEMP_IMAGE,
+            Section::AbiDepends(_) => ABI_DEPENDS,
+            Section::Unknown { id, .. } => *id,
+        }
+    }
+
+    /// The two-character code of the section, such as `ID`
+    pub fn code(&self) -> String {
+        String::from_utf8_lossy(&self.id().to_le_bytes()).to_string()
+    }
+
+    fn payload(&self) -> Vec<u8> {
+        let mut data = vec![];
+        match self {
+            Section::Identity {
+                image_type,
+                component_id,
+                image_id,
+            } => {
+                data.extend(image_type.to_le_bytes());
+                data.extend(0u16.to_le_bytes());
+                data.extend(component_id.0);
+                data.extend(image_id.0);
+            }
+            Section::Signature {
+                signing_type,
+                thumbprint,
+            } => {
+                data.extend(signing_type.to_le_bytes());
+                data.extend(thumbprint);
+            }
+            Section::Debug { build_date, name } => {
+                data.extend(build_date.to_le_bytes());
+                let mut name_bytes = [0u8; DEBUG_NAME_SIZE];
+                // Keep the terminating NUL
+                let len = name.len().min(DEBUG_NAME_SIZE - 1);
+                name_bytes[..len].copy_from_slice(&name.as_bytes()[..len]);
+                data.extend(name_bytes);
+            }
+            Section::TempImage { flags } => data.extend(flags.to_le_bytes()),
+            Section::AbiDepends(versions) => {
+                for v in versions {
+                    data.extend(v.version.to_le_bytes());
+                    data.extend(v.abi_type.to_le_bytes());
+                }
+            }
+            Section::Unknown { data: d, .. } => data.extend(d),
+        }
+        data
+    }
+
+    fn parse(id: u16, data: &[u8]) -> Result<Self, ImageError> {
+        let malformed =
+            || ImageError::Malformed(format!("metadata section {id:#06x} is truncated"));
+        let u16_at = |o: usize| data.get(o..o + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+        let u32_at = |o: usize| {
+            data.get(o..o + 4)
+                .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        };
+        let guid_at = |o: usize| data.get(o..o + 16).map(|b| Guid(b.try_into().unwrap()));
+        Ok(match id {
+            IDENTITY => Section::Identity {
+                image_type: u16_at(0).ok_or_else(malformed)?,
+                component_id: guid_at(4).ok_or_else(malformed)?,
+                image_id: guid_at(20).ok_or_else(malformed)?,
+            },
+            SIGNATURE => Section::Signature {
+                signing_type: u32_at(0).ok_or_else(malformed)?,
+                thumbprint: data.get(4..24).ok_or_else(malformed)?.try_into().unwrap(),
+            },
+            DEBUG => {
+                let date = data.get(0..8).ok_or_else(malformed)?;
+                let name = data.get(8..8 + DEBUG_NAME_SIZE).ok_or_else(malformed)?;
+                let len = name.iter().position(|&b| b == 0).unwrap_or(name.len());
+                Section::Debug {
+                    build_date: u64::from_le_bytes(date.try_into().unwrap()),
+                    name: String::from_utf8_lossy(&name[..len]).to_string(),
+                }
+            }
+            TEMP_IMAGE => Section::TempImage {
+                flags: u32_at(0).ok_or_else(malformed)?,
+            },
+            ABI_DEPENDS => Section::AbiDepends(
+                data.chunks_exact(8)
+                    .map(|c| AbiVersion {
+                        version: u32::from_le_bytes(c[0..4].try_into().unwrap()),
+                        abi_type: u32::from_le_bytes(c[4..8].try_into().unwrap()),
+                    })
+                    .collect(),
+            ),
+            _ => Section::Unknown {
+                id,
+                data: data.to_vec(),
+            },
+        })
+    }
+}
+
+/// Serialize the metadata header and `sections`
+pub(crate) fn write(sections: &[Section]) -> Vec<u8> {
+    let mut data = vec![];
+    data.extend(MAGIC.to_le_bytes());
+    data.extend((sections.len() as u32).to_le_bytes());
+    for section in sections {
+        let payload = section.payload();
+        data.extend(section.id().to_le_bytes());
+        data.extend((payload.len() as u16).to_le_bytes());
+        data.extend(payload);
+    }
+    data
+}
+
+/// Parse the metadata at the start of `data`, returning the sections and the metadata length
+pub(crate) fn parse(data: &[u8]) -> Result<(Vec<Section>, usize), ImageError> {
+    let header = data
+        .get(0..HEADER_SIZE)
+        .ok_or_else(|| ImageError::Malformed("metadata header is truncated".to_string()))?;
+    if u32::from_le_bytes(header[0..4].try_into().unwrap()) != MAGIC {
+        return Err(ImageError::Malformed(
+            "metadata magic not found".to_string(),
+        ));
+    }
+    let count = u32::from_le_bytes(header[4..8].try_into().unwrap());
+    let mut offset = HEADER_SIZE;
+    let mut sections = vec![];
+    for _ in 0..count {
+        let section_header = data
+            .get(offset..offset + 4)
+            .ok_or_else(|| ImageError::Malformed("metadata section is truncated".to_string()))?;
+        let id = u16::from_le_bytes([section_header[0], section_header[1]]);
+        let len = u16::from_le_bytes([section_header[2], section_header[3]]) as usize;
+        offset += 4;
+        let payload = data
+            .get(offset..offset + len)
+            .ok_or_else(|| ImageError::Malformed("metadata section is truncated".to_string()))?;
+        sections.push(Section::parse(id, payload)?);
+        offset += len;
+    }
+    Ok((sections, offset))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_guid() {
+        let text = "c64ecd9e-17f0-45fc-8165-f7b7ae2d5815";
+        let guid = Guid::parse(text).unwrap();
+        assert_eq!(guid.0[0..4], [0x9e, 0xcd, 0x4e, 0xc6]);
+        assert_eq!(guid.0[8..10], [0x81, 0x65]);
+        assert_eq!(guid.to_string(), text);
+        assert_eq!(Guid::parse("c64ecd9e-17f0-45fc-8165"), None);
+        assert_eq!(Guid::parse("c64ecd9e-17f0-45fc-8165-f7b7ae2d581g"), None);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let sections = vec![
+            Section::Identity {
+                image_type: IMAGE_TYPE_APPLICATIONS,
+                component_id: Guid::parse("c64ecd9e-17f0-45fc-8165-f7b7ae2d5815").unwrap(),
+                image_id: Guid([7; 16]),
+            },
+            Section::Debug {
+                build_date: 1_700_000_000,
+                name: "a name that is longer than thirty-one characters".to_string(),
+            },
+            Section::AbiDepends(vec![AbiVersion {
+                abi_type: ABI_TYPE_APPLICATION_RUNTIME,
+                version: 16,
+            }]),
+            Section::Unknown {
+                id: section_id(b"ZZ"),
+                data: vec![1, 2, 3],
+            },
+        ];
+        let data = write(&sections);
+        let (parsed, len) = parse(&data).unwrap();
+        assert_eq!(len, data.len());
+        assert_eq!(parsed[0], sections[0]);
+        assert_eq!(
+            parsed[1],
+            Section::Debug {
+                build_date: 1_700_000_000,
+                name: "a name that is longer than thir".to_string(),
+            }
+        );
+        assert_eq!(parsed[2..], sections[2..]);
+        assert_eq!(parsed[3].code(), "ZZ");
+    }
+}
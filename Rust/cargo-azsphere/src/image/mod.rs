@@ -0,0 +1,525 @@
+//! Azure Sphere image packages.
+//!
+//! An image package is a file system image, followed by the metadata sections and a signature
+//! over everything before it:
+//!
+//! ```text
+//! +----------------------+
+//! | file system image    |  app_manifest.json, bin/<name>, extra files
+//! +----------------------+
+//! | metadata             |  identity, signature, debug, temp image, ABI depends
+//! +----------------------+
+//! | signature (64 bytes) |
+//! +----------------------+
+//! ```
+//!
+//! Packages built here are not signed: the signature section names the all-zero thumbprint and
+//! the signature holds the SHA-256 of the signed bytes, zero-padded.  That is this crate's guess
+//! at what `azsphere image-package pack-application` writes, which nothing has checked against
+//! the SDK's packages yet, so packaging only packs here with `--native-pack`.
+
+use crate::error::{Error, ImageError};
+use crate::hwdef::HardwareDefinition;
+use metadata::{AbiVersion, Guid, Section};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::fs as stdfs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod fs;
+pub mod metadata;
+
+pub const SIGNATURE_SIZE: usize = 64;
+pub const APP_MANIFEST: &str = "app_manifest.json";
+
+#[derive(Debug, Default)]
+pub struct PackOptions {
+    /// target API set, such as `16` or `14+Beta2204`
+    pub target_api_set: String,
+    /// directories searched, in order, for the hardware definition and its imports
+    pub hardware_definition_dirs: Vec<PathBuf>,
+    /// hardware definition filename, such as `sample_appliance.json`
+    pub target_definition: Option<String>,
+    /// build date to record, in seconds since the Unix epoch.  Defaults to `SOURCE_DATE_EPOCH`,
+    /// or the current time.
+    pub build_date: Option<u64>,
+}
+
+/// A target API set: an ARV, optionally with a set of beta APIs
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TargetApiSet {
+    pub arv: u32,
+    pub beta: Option<u32>,
+}
+
+impl TargetApiSet {
+    pub fn parse(text: &str) -> Result<Self, ImageError> {
+        let invalid = || ImageError::InvalidTargetApiSet(text.to_string());
+        let (arv, beta) = match text.split_once("+Beta") {
+            Some((arv, beta)) => (arv, Some(beta.parse().map_err(|_| invalid())?)),
+            None => (text, None),
+        };
+        Ok(Self {
+            arv: arv.parse().map_err(|_| invalid())?,
+            beta,
+        })
+    }
+}
+
+fn build_date(options: &PackOptions) -> u64 {
+    options.build_date.unwrap_or_else(|| {
+        std::env::var("SOURCE_DATE_EPOCH")
+            .ok()
+            .and_then(|v| v.parse().ok())
+            .unwrap_or_else(|| {
+                SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map(|d| d.as_secs())
+                    .unwrap_or_default()
+            })
+    })
+}
+
+/// Derive the image ID from the content, so that identical inputs produce identical packages
+fn image_id(fs_image: &[u8], component_id: &Guid) -> Guid {
+    let digest = Sha256::new()
+        .chain_update(component_id.0)
+        .chain_update(fs_image)
+        .finalize();
+    let mut id: [u8; 16] = digest[..16].try_into().unwrap();
+    // Mark it as a version 4, RFC 4122 variant GUID
+    id[7] = (id[7] & 0x0f) | 0x40;
+    id[8] = (id[8] & 0x3f) | 0x80;
+    Guid(id)
+}
+
+fn temporary_signature(signed: &[u8]) -> [u8; SIGNATURE_SIZE] {
+    let mut signature = [0u8; SIGNATURE_SIZE];
+    signature[..32].copy_from_slice(&Sha256::digest(signed));
+    signature
+}
+
+/// Prepare the staged app manifest: resolve hardware definition references and record the
+/// target API set, as `azsphere image-package pack-application` does
+fn prepare_app_manifest(
+    manifest: &mut Value,
+    target_api_set: &TargetApiSet,
+    options: &PackOptions,
+) -> Result<(), Error> {
+    if !manifest.is_object() {
+        return Err(ImageError::InvalidManifest("expected a JSON object".to_string()).into());
+    }
+    if let Some(target_definition) = &options.target_definition {
+        let definition =
+            HardwareDefinition::load(&options.hardware_definition_dirs, target_definition)?;
+        definition.resolve_manifest(manifest)?;
+    }
+    manifest["TargetApplicationRuntimeVersion"] = Value::from(target_api_set.arv);
+    if let Some(beta) = target_api_set.beta {
+        manifest["TargetBetaApis"] = Value::from(format!("Beta{beta}"));
+    }
+    Ok(())
+}
+
+/// A parsed image package
+#[derive(Debug)]
+pub struct ImagePackage {
+    /// files in the file system image
+    pub files: Vec<fs::FileEntry>,
+    /// metadata sections, in order
+    pub sections: Vec<Section>,
+    pub signature: Vec<u8>,
+    fs_image: Vec<u8>,
+}
+
+impl ImagePackage {
+    pub fn parse(mut data: Vec<u8>) -> Result<Self, ImageError> {
+        let (fs_size, files) = fs::list(&data)?;
+        let (sections, metadata_size) = metadata::parse(&data[fs_size..])?;
+        let signature = data.split_off(fs_size + metadata_size);
+        if signature.len() != SIGNATURE_SIZE {
+            return Err(ImageError::Malformed(format!(
+                "the signature is {} bytes, not {SIGNATURE_SIZE}",
+                signature.len()
+            )));
+        }
+        data.truncate(fs_size);
+        Ok(Self {
+            files,
+            sections,
+            signature,
+            fs_image: data,
+        })
+    }
+
+    pub fn open(path: &Path) -> Result<Self, Error> {
+        let data = stdfs::read(path).map_err(|e| Error::FileIo(path.to_path_buf(), e))?;
+        Ok(Self::parse(data)?)
+    }
+
+    /// Contents of the file at `path`, relative to the package root
+    pub fn file(&self, path: &str) -> Option<&[u8]> {
+        self.files
+            .iter()
+            .find(|f| f.path == path)
+            .map(|f| &self.fs_image[f.range()])
+    }
+
+    pub fn app_manifest(&self) -> Result<Value, ImageError> {
+        let data = self
+            .file(APP_MANIFEST)
+            .ok_or_else(|| ImageError::MissingFile(APP_MANIFEST.to_string()))?;
+        serde_json::from_slice(data).map_err(|e| ImageError::InvalidManifest(e.to_string()))
+    }
+
+    pub fn component_id(&self) -> Option<Guid> {
+        self.sections.iter().find_map(|section| match section {
+            Section::Identity { component_id, .. } => Some(*component_id),
+            _ => None,
+        })
+    }
+
+    pub fn abi_depends(&self) -> &[AbiVersion] {
+        self.sections
+            .iter()
+            .find_map(|section| match section {
+                Section::AbiDepends(versions) => Some(versions.as_slice()),
+                _ => None,
+            })
+            .unwrap_or_default()
+    }
+
+    /// The target API set the package was built for, such as `14+Beta2204`
+    pub fn target_api_set(&self) -> Option<String> {
+        let version = |abi_type| {
+            self.abi_depends()
+                .iter()
+                .find(|v| v.abi_type == abi_type)
+                .map(|v| v.version)
+        };
+        let arv = version(metadata::ABI_TYPE_APPLICATION_RUNTIME)?;
+        Some(match version(metadata::ABI_TYPE_BETA_API_SET) {
+            Some(beta) => format!("{arv}+Beta{beta}"),
+            None => arv.to_string(),
+        })
+    }
+}
+
+/// Pack the staged `package_dir` into the image package `destination`
+pub fn pack_application(
+    package_dir: &Path,
+    destination: &Path,
+    options: &PackOptions,
+) -> Result<(), Error> {
+    let target_api_set = TargetApiSet::parse(&options.target_api_set)?;
+
+    let manifest_path = package_dir.join(APP_MANIFEST);
+    if !manifest_path.exists() {
+        return Err(ImageError::MissingFile(APP_MANIFEST.to_string()).into());
+    }
+    let text = stdfs::read_to_string(&manifest_path)
+        .map_err(|e| Error::FileIo(manifest_path.clone(), e))?;
+    let mut manifest: Value =
+        serde_json::from_str(&text).map_err(|e| Error::Json(manifest_path.clone(), e))?;
+    prepare_app_manifest(&mut manifest, &target_api_set, options)?;
+    let text = serde_json::to_string_pretty(&manifest)
+        .map_err(|e| Error::Json(manifest_path.clone(), e))?;
+    stdfs::write(&manifest_path, text).map_err(|e| Error::FileIo(manifest_path.clone(), e))?;
+
+    let component_id = manifest["ComponentId"]
+        .as_str()
+        .and_then(Guid::parse)
+        .ok_or_else(|| ImageError::InvalidManifest("ComponentId must be a GUID".to_string()))?;
+    let name = manifest["Name"].as_str().unwrap_or_default().to_string();
+
+    let mut image = fs::build(package_dir)?;
+
+    let mut abi_depends = vec![AbiVersion {
+        abi_type: metadata::ABI_TYPE_APPLICATION_RUNTIME,
+        version: target_api_set.arv,
+    }];
+    if let Some(beta) = target_api_set.beta {
+        abi_depends.push(AbiVersion {
+            abi_type: metadata::ABI_TYPE_BETA_API_SET,
+            version: beta,
+        });
+    }
+    let sections = [
+        Section::Identity {
+            image_type: metadata::IMAGE_TYPE_APPLICATIONS,
+            component_id,
+            image_id: image_id(&image, &component_id),
+        },
+        Section::Signature {
+            signing_type: metadata::SIGNING_TYPE_ECDSA256,
+            thumbprint: [0; 20],
+        },
+        Section::Debug {
+            build_date: build_date(options),
+            name,
+        },
+        Section::TempImage {
+            flags: metadata::TEMP_IMAGE_UNDER_DEVELOPMENT,
+        },
+        Section::AbiDepends(abi_depends),
+    ];
+    image.extend(metadata::write(&sections));
+    let signature = temporary_signature(&image);
+    image.extend(signature);
+
+    stdfs::write(destination, image).map_err(|e| Error::FileIo(destination.to_path_buf(), e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::process::Command;
+
+    fn fixtures() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures")
+    }
+
+    /// Stage a package directory holding `manifest` and a stand-in executable
+    fn stage(dir: &Path, manifest: &Path) -> String {
+        let text = stdfs::read_to_string(manifest).unwrap();
+        let json: Value = serde_json::from_str(&text).unwrap();
+        let entry_point = json["EntryPoint"].as_str().unwrap().trim_start_matches('/');
+        stdfs::create_dir_all(dir.join("bin")).unwrap();
+        stdfs::write(dir.join(APP_MANIFEST), text).unwrap();
+        stdfs::write(dir.join(entry_point), b"\x7fELF stand-in executable").unwrap();
+        entry_point.to_string()
+    }
+
+    /// Split a package into its file system image and metadata, clearing the fields that vary
+    /// from one build to the next
+    fn normalize(package: &[u8]) -> (Vec<u8>, Vec<Section>) {
+        let fs_size = u32::from_le_bytes(package[4..8].try_into().unwrap()) as usize;
+        let (mut sections, len) = metadata::parse(&package[fs_size..]).unwrap();
+        assert_eq!(fs_size + len + SIGNATURE_SIZE, package.len());
+        for section in &mut sections {
+            match section {
+                Section::Identity { image_id, .. } => *image_id = Guid::default(),
+                Section::Debug { build_date, .. } => *build_date = 0,
+                _ => {}
+            }
+        }
+        (package[..fs_size].to_vec(), sections)
+    }
+
+    #[test]
+    fn test_target_api_set() {
+        assert_eq!(
+            TargetApiSet::parse("16").unwrap(),
+            TargetApiSet {
+                arv: 16,
+                beta: None
+            }
+        );
+        assert_eq!(
+            TargetApiSet::parse("14+Beta2204").unwrap(),
+            TargetApiSet {
+                arv: 14,
+                beta: Some(2204)
+            }
+        );
+        assert!(TargetApiSet::parse("latest").is_err());
+    }
+
+    #[test]
+    fn test_pack_application() {
+        let dir = tempfile::tempdir().unwrap();
+        let package_dir = dir.path().join("out");
+        let manifest = fixtures().join("app_manifest.json");
+        let entry_point = stage(&package_dir, &manifest);
+        stdfs::write(package_dir.join("README.md"), "readme").unwrap();
+
+        let options = PackOptions {
+            target_api_set: "16".to_string(),
+            hardware_definition_dirs: vec![fixtures().join("HardwareDefinitions/test_board")],
+            target_definition: Some("sample_appliance.json".to_string()),
+            build_date: Some(1_700_000_000),
+        };
+        let destination = dir.path().join("first.imagepackage");
+        pack_application(&package_dir, &destination, &options).unwrap();
+        let first = stdfs::read(&destination).unwrap();
+
+        // Repacking the same inputs is reproducible
+        stage(&package_dir, &manifest);
+        pack_application(&package_dir, &destination, &options).unwrap();
+        assert_eq!(stdfs::read(&destination).unwrap(), first);
+
+        let staged: Value =
+            serde_json::from_str(&stdfs::read_to_string(package_dir.join(APP_MANIFEST)).unwrap())
+                .unwrap();
+        assert_eq!(staged["Capabilities"]["Gpio"], serde_json::json!([8, 12]));
+        assert_eq!(staged["TargetApplicationRuntimeVersion"], 16);
+
+        let package = ImagePackage::parse(first.clone()).unwrap();
+        assert_eq!(package.target_api_set().as_deref(), Some("16"));
+        assert_eq!(package.app_manifest().unwrap(), staged);
+        assert_eq!(
+            package
+                .files
+                .iter()
+                .map(|f| (f.path.as_str(), f.is_executable()))
+                .collect::<Vec<_>>(),
+            [
+                ("README.md", false),
+                ("app_manifest.json", false),
+                ("bin/test_app", true)
+            ]
+        );
+        assert_eq!(package.file("README.md"), Some(&b"readme"[..]));
+        // The executable is page-aligned, so that it can run in place
+        let executable = b"\x7fELF stand-in executable";
+        let position = first
+            .windows(executable.len())
+            .position(|w| w == executable)
+            .unwrap();
+        assert_eq!(position % fs::PAGE_SIZE, 0);
+        assert!(entry_point.starts_with("bin/"));
+
+        let sections = &package.sections;
+        assert_eq!(
+            sections.iter().map(|s| s.code()).collect::<Vec<_>>(),
+            ["ID", "SG", "DB", "TM", "ND"]
+        );
+        assert_eq!(
+            package.component_id().unwrap().to_string(),
+            "911fa4f1-8fd7-4bd0-a4a7-6b5b2a2b4a12"
+        );
+        assert_eq!(
+            sections[2],
+            Section::Debug {
+                build_date: 1_700_000_000,
+                name: "TestApp".to_string()
+            }
+        );
+        assert_eq!(
+            package.signature[..32],
+            Sha256::digest(&first[..first.len() - SIGNATURE_SIZE])[..]
+        );
+        assert_rebuilds(&destination);
+        assert!(ImagePackage::parse(first[..first.len() - 1].to_vec()).is_err());
+        assert!(ImagePackage::parse([first.as_slice(), b"trailing"].concat()).is_err());
+    }
+
+    #[test]
+    fn test_pack_application_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let options = PackOptions {
+            target_api_set: "16".to_string(),
+            ..Default::default()
+        };
+        assert!(matches!(
+            pack_application(dir.path(), &dir.path().join("x.imagepackage"), &options),
+            Err(Error::Image(ImageError::MissingFile(_)))
+        ));
+
+        stdfs::write(dir.path().join(APP_MANIFEST), r#"{"ComponentId": "nope"}"#).unwrap();
+        assert!(matches!(
+            pack_application(dir.path(), &dir.path().join("x.imagepackage"), &options),
+            Err(Error::Image(ImageError::InvalidManifest(_)))
+        ));
+    }
+
+    /// Check each part of the package at `path` against what this crate writes for the same
+    /// contents: the file system image, the metadata and the signature, byte for byte
+    fn assert_rebuilds(path: &Path) {
+        let data = stdfs::read(path).unwrap();
+        let package = ImagePackage::parse(data.clone()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        for file in &package.files {
+            let destination = dir.path().join(&file.path);
+            stdfs::create_dir_all(destination.parent().unwrap()).unwrap();
+            stdfs::write(destination, package.file(&file.path).unwrap()).unwrap();
+        }
+        let fs_size = package.fs_image.len();
+        let signed = &data[..data.len() - SIGNATURE_SIZE];
+        let name = path.display();
+        assert!(
+            fs::build(dir.path()).unwrap() == package.fs_image,
+            "file system image of {name} differs"
+        );
+        assert!(
+            metadata::write(&package.sections) == signed[fs_size..],
+            "metadata of {name} differs"
+        );
+        assert!(
+            temporary_signature(signed)[..] == package.signature[..],
+            "signature of {name} differs"
+        );
+    }
+
+    /// Compare against the packages the SDK produces for each sample in `rust/samples`.  The
+    /// image ID and build date differ from build to build, so they are compared by rebuilding
+    /// the SDK's package, signature included.
+    #[test]
+    #[ignore = "needs the Azure Sphere SDK, set AzureSphereDefaultSDKDir and run with --ignored"]
+    fn test_matches_sdk_packages() {
+        let sdk_path = PathBuf::from(std::env::var("AzureSphereDefaultSDKDir").unwrap());
+        let repo = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("../rust");
+        let hardware_definition_dirs = vec![
+            repo.join("hardware/HardwareDefinitions/mt3620_rdb"),
+            sdk_path.join("HardwareDefinitions"),
+        ];
+
+        let mut manifests = vec![];
+        let mut dirs = vec![repo.join("samples")];
+        while let Some(dir) = dirs.pop() {
+            for entry in stdfs::read_dir(dir).unwrap() {
+                let path = entry.unwrap().path();
+                if path.is_dir() {
+                    dirs.push(path);
+                } else if path.file_name().unwrap() == APP_MANIFEST {
+                    manifests.push(path);
+                }
+            }
+        }
+        assert!(!manifests.is_empty());
+
+        for manifest in manifests {
+            let dir = tempfile::tempdir().unwrap();
+            let sdk_dir = dir.path().join("sdk");
+            let native_dir = dir.path().join("native");
+            stage(&sdk_dir, &manifest);
+            stage(&native_dir, &manifest);
+
+            let sdk_package = dir.path().join("sdk.imagepackage");
+            let status = Command::new(sdk_path.join("Tools_v2/azsphere"))
+                .args(["image-package", "pack-application", "--package-directory"])
+                .arg(&sdk_dir)
+                .arg("--destination")
+                .arg(&sdk_package)
+                .args(["--target-api-set", "16", "--target-definition-filename"])
+                .arg("sample_appliance.json")
+                .arg("--hardware-definitions")
+                .args(&hardware_definition_dirs)
+                .status()
+                .unwrap();
+            assert!(
+                status.success(),
+                "SDK failed to pack {}",
+                manifest.display()
+            );
+
+            let native_package = dir.path().join("native.imagepackage");
+            let options = PackOptions {
+                target_api_set: "16".to_string(),
+                hardware_definition_dirs: hardware_definition_dirs.clone(),
+                target_definition: Some("sample_appliance.json".to_string()),
+                build_date: None,
+            };
+            pack_application(&native_dir, &native_package, &options).unwrap();
+
+            assert!(
+                normalize(&stdfs::read(&sdk_package).unwrap())
+                    == normalize(&stdfs::read(&native_package).unwrap()),
+                "package for {} differs from the SDK's",
+                manifest.display()
+            );
+            assert_rebuilds(&sdk_package);
+        }
+    }
+}
@@ -0,0 +1,368 @@
+use crate::config::ExtraMetadataSource;
+use crate::hwdef::HardwareDefinition;
+use crate::image::metadata::Section;
+use crate::image::ImagePackage;
+use crate::package;
+use crate::util;
+use crate::workspace::Workspace;
+use serde_json::{json, Value};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, UNIX_EPOCH};
+
+#[derive(clap::Parser, Debug)]
+pub(crate) struct CliArgs {
+    #[clap(flatten)]
+    common: super::package::CliArgs,
+    /// the image package to inspect [DEFAULT = the app package of the current crate]
+    image_package: Option<PathBuf>,
+    /// print the contents as JSON
+    #[arg(long)]
+    json: bool,
+}
+
+#[derive(Debug)]
+pub struct CliSetting {
+    package: Option<String>,
+    verbose: bool,
+    release: bool,
+    json: bool,
+    image_package: Option<PathBuf>,
+    extra_metadata: Vec<ExtraMetadataSource>,
+}
+
+/// What the current crate says about its app package
+struct CrateContext {
+    app_package: PathBuf,
+    /// display name of the target hardware definition, and the definition itself
+    hardware_definition: Option<(String, HardwareDefinition)>,
+}
+
+impl CliSetting {
+    pub(crate) fn new(args: CliArgs) -> Self {
+        Self {
+            package: args.common.package.clone(),
+            verbose: args.common.verbose,
+            release: args.common.release,
+            json: args.json,
+            image_package: args.image_package,
+            extra_metadata: args.common.extra_metadata(),
+        }
+    }
+
+    fn crate_context(&self) -> anyhow::Result<CrateContext> {
+        let workspace = Workspace::load(self.verbose)?;
+        let member = workspace.member(self.package.as_deref())?;
+        let package_config =
+            workspace.package_config(&member, &self.extra_metadata, self.verbose)?;
+        let app_package =
+            package::app_package(&workspace.target_path(self.release), &package_config);
+
+        let sdk_path = util::sdk_path();
+        let dirs = package::hardware_definition_dirs(
+            &workspace.metadata,
+            &member.name,
+            &package_config,
+            sdk_path.as_deref(),
+        );
+        let hardware_definition = match (dirs, package_config.target_definition_file()) {
+            (Some(dirs), Some(file)) => {
+                let definition = HardwareDefinition::load(&dirs, &file)?;
+                let target_hardware = package_config.target_hardware.unwrap_or_default();
+                Some((format!("{target_hardware}/{file}"), definition))
+            }
+            _ => None,
+        };
+
+        Ok(CrateContext {
+            app_package,
+            hardware_definition,
+        })
+    }
+
+    pub fn do_inspect(self) -> anyhow::Result<()> {
+        let (image_package, hardware_definition) = match &self.image_package {
+            // Outside of a crate, there is just no hardware definition to compare against
+            Some(path) => match self.crate_context() {
+                Ok(context) => (path.clone(), context.hardware_definition),
+                Err(e) => {
+                    if self.verbose {
+                        println!("Not using a hardware definition: {e}");
+                    }
+                    (path.clone(), None)
+                }
+            },
+            None => {
+                let context = self.crate_context()?;
+                (context.app_package, context.hardware_definition)
+            }
+        };
+
+        let package = ImagePackage::open(&image_package)?;
+        let report = report(&image_package, &package, hardware_definition.as_ref())?;
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        } else {
+            print_report(&report);
+        }
+        Ok(())
+    }
+}
+
+/// Seconds from the Unix epoch to the end of 9999, the last year RFC 3339 can write
+const MAX_RFC3339_SECS: u64 = 253_402_300_799;
+
+/// The build date as RFC 3339, or as the raw seconds when it is too large to be a date, as in a
+/// corrupt package
+fn build_date_json(build_date: u64) -> Value {
+    match UNIX_EPOCH.checked_add(Duration::from_secs(build_date)) {
+        Some(time) if build_date <= MAX_RFC3339_SECS => {
+            json!(humantime::format_rfc3339_seconds(time).to_string())
+        }
+        _ => json!(build_date),
+    }
+}
+
+fn section_json(section: &Section) -> Value {
+    let fields = match section {
+        Section::Identity {
+            image_type,
+            component_id,
+            image_id,
+        } => json!({
+            "image_type": image_type,
+            "component_id": component_id.to_string(),
+            "image_id": image_id.to_string(),
+        }),
+        Section::Signature {
+            signing_type,
+            thumbprint,
+        } => json!({
+            "signing_type": signing_type,
+            "thumbprint": thumbprint.iter().map(|b| format!("{b:02x}")).collect::<String>(),
+        }),
+        Section::Debug { build_date, name } => json!({
+            "build_date": build_date_json(*build_date),
+            "name": name,
+        }),
+        Section::TempImage { flags } => json!({ "flags": flags }),
+        Section::AbiDepends(versions) => json!({
+            "versions": versions
+                .iter()
+                .map(|v| json!({ "type": v.abi_type, "version": v.version }))
+                .collect::<Vec<_>>(),
+        }),
+        Section::Unknown { data, .. } => json!({ "size": data.len() }),
+    };
+    let mut value = json!({ "section": section.code() });
+    value
+        .as_object_mut()
+        .unwrap()
+        .extend(fields.as_object().unwrap().clone());
+    value
+}
+
+/// For each capability value, the peripherals of the hardware definition it corresponds to
+fn capability_peripherals(manifest: &Value, definition: &HardwareDefinition) -> Value {
+    let mut capabilities = serde_json::Map::new();
+    for (capability, values) in manifest["Capabilities"].as_object().into_iter().flatten() {
+        let Some(values) = values.as_array() else {
+            continue;
+        };
+        let values = values
+            .iter()
+            .map(|value| match value.as_str() {
+                Some(name) if name.starts_with('$') => json!({ "value": value, "resolved": false }),
+                _ => json!({
+                    "value": value,
+                    "resolved": true,
+                    "peripherals": definition.peripherals_for_value(capability, value),
+                }),
+            })
+            .collect::<Vec<_>>();
+        capabilities.insert(capability.clone(), Value::Array(values));
+    }
+    Value::Object(capabilities)
+}
+
+fn report(
+    path: &Path,
+    package: &ImagePackage,
+    hardware_definition: Option<&(String, HardwareDefinition)>,
+) -> anyhow::Result<Value> {
+    let manifest = package.app_manifest()?;
+    let hardware_definition = hardware_definition.map(|(name, definition)| {
+        json!({
+            "name": name,
+            "capabilities": capability_peripherals(&manifest, definition),
+        })
+    });
+    Ok(json!({
+        "path": path.display().to_string(),
+        "component_id": package.component_id().map(|id| id.to_string()),
+        "target_api_set": package.target_api_set(),
+        "hardware_definition": hardware_definition,
+        "app_manifest": manifest,
+        "files": package
+            .files
+            .iter()
+            .map(|f| json!({ "path": f.path, "size": f.size, "executable": f.is_executable() }))
+            .collect::<Vec<_>>(),
+        "metadata": package.sections.iter().map(section_json).collect::<Vec<_>>(),
+        "signature_size": package.signature.len(),
+    }))
+}
+
+fn print_report(report: &Value) {
+    let text = |v: &Value| match v {
+        Value::String(s) => s.clone(),
+        Value::Null => "-".to_string(),
+        v => v.to_string(),
+    };
+    println!("Package:             {}", text(&report["path"]));
+    println!("Component ID:        {}", text(&report["component_id"]));
+    println!("Target API set:      {}", text(&report["target_api_set"]));
+
+    let hardware_definition = &report["hardware_definition"];
+    println!(
+        "Hardware definition: {}",
+        text(&hardware_definition["name"])
+    );
+    for (capability, values) in hardware_definition["capabilities"]
+        .as_object()
+        .into_iter()
+        .flatten()
+    {
+        let values = values
+            .as_array()
+            .into_iter()
+            .flatten()
+            .map(|v| {
+                let peripherals = v["peripherals"]
+                    .as_array()
+                    .into_iter()
+                    .flatten()
+                    .map(text)
+                    .collect::<Vec<_>>();
+                if v["resolved"] == false {
+                    format!("{} (unresolved)", text(&v["value"]))
+                } else if peripherals.is_empty() {
+                    text(&v["value"])
+                } else {
+                    format!("{} ({})", text(&v["value"]), peripherals.join(", "))
+                }
+            })
+            .collect::<Vec<_>>();
+        println!("    {capability}: {}", values.join(", "));
+    }
+
+    println!("App manifest:");
+    let manifest = serde_json::to_string_pretty(&report["app_manifest"]).unwrap_or_default();
+    for line in manifest.lines() {
+        println!("    {line}");
+    }
+
+    println!("Files:");
+    for file in report["files"].as_array().into_iter().flatten() {
+        println!(
+            "    {:>10}  {}{}",
+            text(&file["size"]),
+            text(&file["path"]),
+            if file["executable"] == true { " *" } else { "" }
+        );
+    }
+
+    println!("Metadata sections:");
+    for section in report["metadata"].as_array().into_iter().flatten() {
+        let fields = section
+            .as_object()
+            .into_iter()
+            .flatten()
+            .filter(|(key, _)| key.as_str() != "section")
+            .map(|(key, value)| format!("{key}={}", text(value)))
+            .collect::<Vec<_>>();
+        println!("    {}  {}", text(&section["section"]), fields.join(" "));
+    }
+    println!(
+        "Signature:           {} bytes",
+        text(&report["signature_size"])
+    );
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::image::{pack_application, PackOptions};
+    use std::fs;
+
+    #[test]
+    fn test_report() {
+        let fixtures = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures");
+        let dir = tempfile::tempdir().unwrap();
+        let package_dir = dir.path().join("out");
+        fs::create_dir_all(package_dir.join("bin")).unwrap();
+        fs::copy(
+            fixtures.join("app_manifest.json"),
+            package_dir.join("app_manifest.json"),
+        )
+        .unwrap();
+        fs::write(package_dir.join("bin/test_app"), b"app").unwrap();
+        let hardware_definition_dirs = vec![fixtures.join("HardwareDefinitions/test_board")];
+        let options = PackOptions {
+            target_api_set: "14+Beta2204".to_string(),
+            hardware_definition_dirs: hardware_definition_dirs.clone(),
+            target_definition: Some("sample_appliance.json".to_string()),
+            build_date: Some(0),
+        };
+        let path = dir.path().join("test.imagepackage");
+        pack_application(&package_dir, &path, &options).unwrap();
+
+        let definition =
+            HardwareDefinition::load(&hardware_definition_dirs, "sample_appliance.json").unwrap();
+        let hardware_definition = ("test_board/sample_appliance.json".to_string(), definition);
+        let package = ImagePackage::open(&path).unwrap();
+        let report = report(&path, &package, Some(&hardware_definition)).unwrap();
+
+        assert_eq!(
+            report["component_id"],
+            "911fa4f1-8fd7-4bd0-a4a7-6b5b2a2b4a12"
+        );
+        assert_eq!(report["target_api_set"], "14+Beta2204");
+        assert_eq!(report["app_manifest"]["TargetBetaApis"], "Beta2204");
+        assert_eq!(
+            report["hardware_definition"]["capabilities"]["Gpio"],
+            json!([
+                { "value": 8, "resolved": true, "peripherals": ["SAMPLE_LED"] },
+                { "value": 12, "resolved": true, "peripherals": ["SAMPLE_BUTTON"] },
+            ])
+        );
+        assert_eq!(
+            report["files"],
+            json!([
+                { "path": "app_manifest.json", "size": package.file("app_manifest.json").unwrap().len(), "executable": false },
+                { "path": "bin/test_app", "size": 3, "executable": true },
+            ])
+        );
+        assert_eq!(
+            report["metadata"][2],
+            json!({ "section": "DB", "build_date": "1970-01-01T00:00:00Z", "name": "TestApp" })
+        );
+        assert_eq!(report["signature_size"], 64);
+    }
+
+    #[test]
+    fn test_section_json_build_date() {
+        let debug = |build_date| Section::Debug {
+            build_date,
+            name: "TestApp".to_string(),
+        };
+        assert_eq!(
+            section_json(&debug(MAX_RFC3339_SECS))["build_date"],
+            "9999-12-31T23:59:59Z"
+        );
+        assert_eq!(
+            section_json(&debug(MAX_RFC3339_SECS + 1))["build_date"],
+            MAX_RFC3339_SECS + 1
+        );
+        assert_eq!(section_json(&debug(u64::MAX))["build_date"], u64::MAX);
+    }
+}
@@ -0,0 +1,400 @@
+//! `cargo azsphere logs`: stream the output of the app on the device, which it serves on port
+//! 2342 to whoever connects, reconnecting when the app restarts.
+
+use crate::error::Error;
+use crate::symbolize::{package_symbols, Symbolizer};
+use regex::Regex;
+use std::fs::{File, OpenOptions};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::TcpStream;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The device's address on the Azure Sphere network interface
+pub(crate) const DEFAULT_DEVICE: &str = "192.168.35.2";
+
+/// Port the device serves the output of an app on
+pub(crate) const OUTPUT_PORT: u16 = 2342;
+
+#[derive(clap::Parser, Debug)]
+pub(crate) struct CliArgs {
+    /// IP address of the device [DEFAULT = 192.168.35.2]
+    #[arg(short, long)]
+    device: Option<String>,
+    /// only show lines that match this regular expression; may be repeated
+    #[arg(long, value_parser = Regex::new)]
+    include: Vec<Regex>,
+    /// hide lines that match this regular expression; may be repeated
+    #[arg(long, value_parser = Regex::new)]
+    exclude: Vec<Regex>,
+    /// also append the output to this file
+    #[arg(long)]
+    tee: Option<PathBuf>,
+    /// don't prefix lines with the time they were received
+    #[arg(long)]
+    no_timestamps: bool,
+    /// exit when the app's output ends, rather than waiting for it to restart
+    #[arg(long)]
+    once: bool,
+    /// package whose unstripped executable to annotate code addresses from
+    #[arg(short, long)]
+    package: Option<String>,
+    /// use the release build of the package for symbols
+    #[arg(short, long)]
+    release: bool,
+    /// unstripped executable to annotate code addresses from [DEFAULT = the package's]
+    #[arg(long)]
+    symbols: Option<PathBuf>,
+    /// display verbose output
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+#[derive(Debug)]
+pub struct CliSetting {
+    stream: LogStream,
+    package: Option<String>,
+    release: bool,
+    symbols: Option<PathBuf>,
+    tee: Option<PathBuf>,
+    once: bool,
+}
+
+impl CliSetting {
+    pub(crate) fn new(args: CliArgs) -> Self {
+        let mut stream = LogStream::new(args.device.as_deref().unwrap_or(DEFAULT_DEVICE));
+        stream.include = args.include;
+        stream.exclude = args.exclude;
+        stream.timestamps = !args.no_timestamps;
+        stream.verbose = args.verbose;
+        Self {
+            stream,
+            package: args.package,
+            release: args.release,
+            symbols: args.symbols,
+            tee: args.tee,
+            once: args.once,
+        }
+    }
+
+    pub fn do_logs(&self) -> Result<(), Error> {
+        let mut stream = self.stream.clone();
+        stream.symbolizer = match &self.symbols {
+            Some(symbols) => Some(Arc::new(Symbolizer::load(symbols)?)),
+            None => self.package_symbolizer(),
+        };
+        let file = match &self.tee {
+            Some(path) => Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .map_err(|e| Error::FileIo(path.clone(), e))?,
+            ),
+            None => None,
+        };
+        let mut out = Tee {
+            out: io::stdout(),
+            file,
+        };
+        stream.follow(&mut out, if self.once { Some(1) } else { None })
+    }
+
+    /// Symbols of the package in the current directory, if it has been packaged
+    fn package_symbolizer(&self) -> Option<Arc<Symbolizer>> {
+        let verbose = self.stream.verbose;
+        let symbols = package_symbols(self.package.as_deref(), self.release, &[], verbose);
+        match symbols.and_then(|symbols| Symbolizer::load(&symbols)) {
+            Ok(symbolizer) => Some(Arc::new(symbolizer)),
+            Err(e) => {
+                if verbose || self.package.is_some() {
+                    eprintln!("Code addresses won't be annotated: {e}");
+                }
+                None
+            }
+        }
+    }
+}
+
+/// Writes to `out`, and to `file` too if there is one
+struct Tee<W: Write> {
+    out: W,
+    file: Option<File>,
+}
+
+impl<W: Write> Write for Tee<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.out.write_all(buf)?;
+        if let Some(file) = &mut self.file {
+            file.write_all(buf)?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()?;
+        if let Some(file) = &mut self.file {
+            file.flush()?;
+        }
+        Ok(())
+    }
+}
+
+/// The output of the app on a device, as lines
+#[derive(Debug, Clone)]
+pub(crate) struct LogStream {
+    /// host:port to connect to
+    pub address: String,
+    /// if any are given, only lines that match one of them are shown
+    pub include: Vec<Regex>,
+    /// lines that match any of these are hidden
+    pub exclude: Vec<Regex>,
+    pub timestamps: bool,
+    /// how long to wait before connecting again, after the app's output ended
+    pub retry_delay: Duration,
+    /// annotates code addresses, if there is one
+    pub symbolizer: Option<Arc<Symbolizer>>,
+    pub verbose: bool,
+}
+
+impl LogStream {
+    pub fn new(device: &str) -> Self {
+        Self {
+            address: format!("{device}:{OUTPUT_PORT}"),
+            include: vec![],
+            exclude: vec![],
+            timestamps: true,
+            retry_delay: Duration::from_secs(1),
+            symbolizer: None,
+            verbose: false,
+        }
+    }
+
+    pub fn connect(&self) -> Result<TcpStream, Error> {
+        TcpStream::connect(&self.address)
+            .map_err(|e| Error::DeviceUnreachable(self.address.clone(), e))
+    }
+
+    /// Connect, trying again every `retry_delay` until `timeout` has passed, for an app that is
+    /// starting
+    pub fn connect_within(&self, timeout: Duration) -> Result<TcpStream, Error> {
+        let start = Instant::now();
+        loop {
+            match self.connect() {
+                Err(_) if start.elapsed() < timeout => thread::sleep(self.retry_delay),
+                result => return result,
+            }
+        }
+    }
+
+    /// Copy the app's output to `out` until `connections` have ended, or forever.  When the
+    /// output ends, the app is waited for to restart.
+    pub fn follow(&self, out: &mut dyn Write, connections: Option<usize>) -> Result<(), Error> {
+        let mut ended = 0;
+        let mut waiting = false;
+        loop {
+            let stream = match self.connect() {
+                Ok(stream) => stream,
+                // Only a limited number of connections is expected when the app is known to run
+                Err(e) if connections.is_some() => return Err(e),
+                Err(_) => {
+                    if !waiting {
+                        eprintln!("Waiting for the app on {}...", self.address);
+                        waiting = true;
+                    }
+                    thread::sleep(self.retry_delay);
+                    continue;
+                }
+            };
+            if self.verbose || waiting {
+                eprintln!("Connected to {}", self.address);
+            }
+            waiting = false;
+            self.copy(stream, out)?;
+            ended += 1;
+            if Some(ended) == connections {
+                return Ok(());
+            }
+            eprintln!("The app's output ended");
+            thread::sleep(self.retry_delay);
+        }
+    }
+
+    /// Copy the lines of `input` to `out`, until it ends.  Lines are only decoded once complete, so
+    /// characters split across reads are kept whole.
+    pub fn copy(&self, mut input: impl Read, out: &mut dyn Write) -> Result<(), Error> {
+        let mut pending = Vec::new();
+        let mut data = [0_u8; 2048];
+        loop {
+            let n = match input.read(&mut data) {
+                Ok(0) => break,
+                Ok(n) => n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => {
+                    eprintln!("Failed to read from {}: {e}", self.address);
+                    break;
+                }
+            };
+            pending.extend_from_slice(&data[..n]);
+            while let Some(end) = pending.iter().position(|&b| b == b'\n') {
+                let line = pending.drain(..=end).collect::<Vec<_>>();
+                self.write_line(&line[..end], out)?;
+            }
+        }
+        if pending.iter().any(|&b| b != 0) {
+            self.write_line(&pending, out)?;
+        }
+        Ok(())
+    }
+
+    fn write_line(&self, line: &[u8], out: &mut dyn Write) -> io::Result<()> {
+        // The device pads its output with NULs
+        let line = String::from_utf8_lossy(line).replace('\0', "");
+        let line = line.trim_end_matches('\r');
+        let included =
+            self.include.is_empty() || self.include.iter().any(|regex| regex.is_match(line));
+        if !included || self.exclude.iter().any(|regex| regex.is_match(line)) {
+            return Ok(());
+        }
+        if self.timestamps {
+            write!(
+                out,
+                "[{}] ",
+                humantime::format_rfc3339_millis(SystemTime::now())
+            )?;
+        }
+        match &self.symbolizer {
+            Some(symbolizer) => writeln!(out, "{}", symbolizer.annotate(line))?,
+            None => writeln!(out, "{line}")?,
+        }
+        out.flush()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::net::TcpListener;
+    use std::path::Path;
+
+    /// Reads one byte at a time, as a slow connection might
+    struct Trickle<'a>(&'a [u8]);
+
+    impl Read for Trickle<'_> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let Some((&first, rest)) = self.0.split_first() else {
+                return Ok(0);
+            };
+            buf[0] = first;
+            self.0 = rest;
+            Ok(1)
+        }
+    }
+
+    fn stream(address: &str) -> LogStream {
+        let mut stream = LogStream::new("127.0.0.1");
+        stream.address = address.to_string();
+        stream.timestamps = false;
+        stream.retry_delay = Duration::from_millis(10);
+        stream
+    }
+
+    #[test]
+    fn test_copy() {
+        let mut stream = stream("device");
+        let input = "Café ☕\r\nGPIO 8 = 1\n\0\0warning: low memory\nno newline";
+        let mut out = vec![];
+        stream.copy(Trickle(input.as_bytes()), &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Café ☕\nGPIO 8 = 1\nwarning: low memory\nno newline\n"
+        );
+
+        stream.include = vec![Regex::new("GPIO|warning").unwrap()];
+        stream.exclude = vec![Regex::new("^warning").unwrap()];
+        let mut out = vec![];
+        stream.copy(input.as_bytes(), &mut out).unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "GPIO 8 = 1\n");
+
+        stream.include.clear();
+        stream.exclude.clear();
+        stream.timestamps = true;
+        let mut out = vec![];
+        stream.copy(&b"started\n"[..], &mut out).unwrap();
+        let timestamped = Regex::new(r"^\[\d{4}-\d\d-\d\dT\d\d:\d\d:\d\d\.\d{3}Z\] started\n$");
+        assert!(timestamped
+            .unwrap()
+            .is_match(&String::from_utf8(out).unwrap()));
+
+        let symbols = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/elf/sym_app");
+        stream.symbolizer = Some(Arc::new(Symbolizer::load(&symbols).unwrap()));
+        stream.timestamps = false;
+        let mut out = vec![];
+        stream
+            .copy(&b"panicked at pc=0x0002010c\n"[..], &mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        assert!(
+            out.starts_with("panicked at pc=0x0002010c (fail at "),
+            "{out}"
+        );
+    }
+
+    #[test]
+    fn test_follow() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap().to_string();
+        // The app writes a character split across two packets, then restarts
+        let device = thread::spawn(move || {
+            let (mut connection, _) = listener.accept().unwrap();
+            connection.write_all(b"caf\xc3").unwrap();
+            connection.flush().unwrap();
+            thread::sleep(Duration::from_millis(50));
+            connection.write_all(b"\xa9\n").unwrap();
+            drop(connection);
+            let (mut connection, _) = listener.accept().unwrap();
+            connection.write_all(b"restarted\n").unwrap();
+        });
+
+        let mut out = vec![];
+        stream(&address).follow(&mut out, Some(2)).unwrap();
+        device.join().unwrap();
+        assert_eq!(String::from_utf8(out).unwrap(), "café\nrestarted\n");
+
+        // Nothing is listening any more
+        assert!(matches!(
+            stream(&address).follow(&mut vec![], Some(1)),
+            Err(Error::DeviceUnreachable(unreachable, _)) if unreachable == address
+        ));
+        assert!(stream(&address)
+            .connect_within(Duration::from_millis(30))
+            .is_err());
+
+        // An app that starts listening a little later is waited for
+        let listening = address.clone();
+        let device = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            let listener = TcpListener::bind(listening).unwrap();
+            listener.accept().unwrap();
+        });
+        stream(&address)
+            .connect_within(Duration::from_secs(10))
+            .unwrap();
+        device.join().unwrap();
+    }
+
+    #[test]
+    fn test_tee() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        let mut tee = Tee {
+            out: vec![],
+            file: Some(File::create(&path).unwrap()),
+        };
+        stream("device").copy(&b"one\ntwo\n"[..], &mut tee).unwrap();
+        assert_eq!(tee.out, b"one\ntwo\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "one\ntwo\n");
+    }
+}
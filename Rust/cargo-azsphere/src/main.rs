@@ -3,13 +3,37 @@ use clap::Parser;
 
 use crate::error::Error;
 
+mod applibs;
+mod build;
 mod config;
 mod debug;
+mod device;
+mod devices;
+mod doctor;
+mod dry_run;
+mod elf;
 mod error;
+mod extra_files;
+mod hwdef;
+mod image;
+mod inspect;
+mod logs;
+mod manifest;
+mod new;
 mod package;
+mod partners;
 mod sideload;
+mod size;
 mod start;
+mod status;
+mod stop;
+mod symbolize;
+mod test;
+mod tool;
 mod util;
+mod vscode;
+mod watch;
+mod workspace;
 
 #[derive(Parser, Debug)]
 #[command(version, about)]
@@ -20,14 +44,40 @@ struct Cli {
 
 #[derive(clap::Subcommand, Debug)]
 enum Command {
+    /// Compile for Azure Sphere and generate the app package, optionally deploying it
+    Build(build::CliArgs),
+    /// Create a crate for Azure Sphere from a template
+    New(new::CliArgs),
     /// Generate an app package.  Customize via [package.metadata.azsphere] in Cargo.toml
-    Package(package::CliArgs),
+    Package(package::PackageArgs),
     /// Sideload an app package
     Sideload(sideload::CliArgs),
     /// Start a sideloaded program
     Start(start::CliArgs),
+    /// Stop the program of a package
+    Stop(stop::CliArgs),
+    /// Show whether the program of a package is running
+    Status(status::CliArgs),
+    /// Show the memory use of the program of a package
+    Memory(status::CliArgs),
+    /// List the devices attached to this computer
+    Devices(devices::CliArgs),
     /// Debug a program
     Debug(debug::CliArgs),
+    /// Stream the output of the app on the device
+    Logs(logs::CliArgs),
+    /// Show the contents of an app package
+    Inspect(inspect::CliArgs),
+    /// Annotate code addresses in device output with function, file and line
+    Symbolize(symbolize::CliArgs),
+    /// Generate or update the VS Code launch configuration and tasks that debug the app
+    Vscode(vscode::CliArgs),
+    /// Check the SDK, tools and device the other subcommands need
+    Doctor(doctor::CliArgs),
+    /// Build, package and deploy the app each time its files change, streaming its output
+    Watch(watch::CliArgs),
+    /// Build the crate's tests, and run them on the device
+    Test(test::CliArgs),
 }
 
 fn main() {
@@ -54,10 +104,22 @@ fn run() -> anyhow::Result<()> {
         Cli::parse_from(args)
     };
     match cli.command {
+        Command::Build(args) => {
+            let settings = build::CliSetting::new(args);
+            settings.do_build().context("error building app")?;
+        }
         Command::Debug(args) => {
             let settings = debug::CliSetting::new(args);
             settings.do_debug().context("error debugging")?;
         }
+        Command::Logs(args) => {
+            let setting = logs::CliSetting::new(args);
+            setting.do_logs().context("error streaming app output")?;
+        }
+        Command::New(args) => {
+            let setting = new::CliSetting::new(args);
+            setting.do_new().context("error creating crate")?;
+        }
         Command::Package(args) => {
             let settings = package::CliSetting::new(args);
             settings
@@ -72,6 +134,56 @@ fn run() -> anyhow::Result<()> {
             let setting = start::CliSetting::new(args);
             setting.do_start().context("error starting app")?;
         }
+        Command::Stop(args) => {
+            let setting = stop::CliSetting::new(args);
+            setting.do_stop().context("error stopping app")?;
+        }
+        Command::Status(args) => {
+            let setting = status::CliSetting::new(args);
+            setting
+                .do_query(status::Query::Status)
+                .context("error getting app status")?;
+        }
+        Command::Memory(args) => {
+            let setting = status::CliSetting::new(args);
+            setting
+                .do_query(status::Query::Memory)
+                .context("error getting app memory stats")?;
+        }
+        Command::Devices(args) => {
+            let setting = devices::CliSetting::new(args);
+            setting
+                .do_devices()
+                .context("error listing attached devices")?;
+        }
+        Command::Symbolize(args) => {
+            let setting = symbolize::CliSetting::new(args);
+            setting.do_symbolize().context("error symbolizing")?;
+        }
+        Command::Vscode(args) => {
+            let setting = vscode::CliSetting::new(args);
+            setting
+                .do_vscode()
+                .context("error writing VS Code settings")?;
+        }
+        Command::Doctor(args) => {
+            let setting = doctor::CliSetting::new(args);
+            setting.do_doctor().context("environment problems found")?;
+        }
+        Command::Watch(args) => {
+            let setting = watch::CliSetting::new(args);
+            setting.do_watch().context("error watching for changes")?;
+        }
+        Command::Test(args) => {
+            let setting = test::CliSetting::new(args);
+            setting.do_test().context("error running tests")?;
+        }
+        Command::Inspect(args) => {
+            let setting = inspect::CliSetting::new(args);
+            setting
+                .do_inspect()
+                .context("error inspecting app package")?;
+        }
     };
     Ok(())
 }
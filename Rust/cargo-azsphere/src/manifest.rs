@@ -0,0 +1,613 @@
+//! App manifests: generated from `[package.metadata.azsphere.capabilities]`, and validated
+//! before packaging.
+//!
+//! Capability keys are the snake_case forms of the app manifest's capability names.  Peripheral
+//! capabilities (`gpio`, `uart`, ...) accept hardware definition names, such as `SAMPLE_LED`,
+//! which are resolved to the values the OS expects.
+
+use crate::config::PackageConfig;
+use crate::error::{ConfigError, Error};
+use crate::hwdef::HardwareDefinition;
+use crate::image::metadata::Guid;
+use serde_json::{json, Map, Value};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::hash::{BuildHasher, RandomState};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use toml::value::Table;
+
+/// Largest `MutableStorage.SizeKB` the OS grants an app
+pub const MAX_MUTABLE_STORAGE_KB: i64 = 64;
+
+#[derive(PartialEq, Eq)]
+enum Kind {
+    /// array of peripheral names or raw values, resolved through the hardware definition
+    Peripherals,
+    /// array of strings or integers
+    List,
+    /// boolean
+    Flag,
+    /// any other value, copied to the manifest as-is
+    Plain,
+    /// integer, written as `{ "SizeKB": n }`
+    StorageSize,
+}
+
+/// Capability keys accepted in Cargo.toml, with their app manifest names
+const CAPABILITIES: &[(&str, &str, Kind)] = &[
+    ("adc", "Adc", Kind::Peripherals),
+    ("gpio", "Gpio", Kind::Peripherals),
+    ("i2c_master", "I2cMaster", Kind::Peripherals),
+    ("i2s_subordinate", "I2sSubordinate", Kind::Peripherals),
+    ("pwm", "Pwm", Kind::Peripherals),
+    ("spi_master", "SpiMaster", Kind::Peripherals),
+    ("uart", "Uart", Kind::Peripherals),
+    (
+        "allowed_application_connections",
+        "AllowedApplicationConnections",
+        Kind::List,
+    ),
+    ("allowed_connections", "AllowedConnections", Kind::List),
+    (
+        "allowed_tcp_server_ports",
+        "AllowedTcpServerPorts",
+        Kind::List,
+    ),
+    (
+        "allowed_udp_server_ports",
+        "AllowedUdpServerPorts",
+        Kind::List,
+    ),
+    ("cert_store", "CertStore", Kind::Flag),
+    ("device_authentication", "DeviceAuthentication", Kind::Plain),
+    ("dhcp_service", "DhcpService", Kind::Flag),
+    (
+        "hardware_address_config",
+        "HardwareAddressConfig",
+        Kind::Flag,
+    ),
+    ("mutable_storage_kb", "MutableStorage", Kind::StorageSize),
+    ("network_config", "NetworkConfig", Kind::Flag),
+    ("network_proxy_config", "NetworkProxyConfig", Kind::Flag),
+    ("power_controls", "PowerControls", Kind::List),
+    (
+        "read_network_proxy_config",
+        "ReadNetworkProxyConfig",
+        Kind::Flag,
+    ),
+    ("sntp_service", "SntpService", Kind::Flag),
+    (
+        "software_update_deferral",
+        "SoftwareUpdateDeferral",
+        Kind::Flag,
+    ),
+    (
+        "system_event_notifications",
+        "SystemEventNotifications",
+        Kind::Flag,
+    ),
+    ("system_time", "SystemTime", Kind::Flag),
+    ("time_sync_config", "TimeSyncConfig", Kind::Flag),
+    ("wifi_config", "WifiConfig", Kind::Flag),
+];
+
+/// Top-level app manifest fields other than the required ones
+const OPTIONAL_FIELDS: &[&str] = &[
+    "CmdArgs",
+    "MallocVersion",
+    "TargetApplicationRuntimeVersion",
+    "TargetBetaApis",
+];
+
+/// A stable ComponentId derived from the package's Cargo.toml and the app's name, so the device
+/// sees the same app on every build, and apps of the same name from other crates as other apps.
+/// Formatted like a name-based (version 5) UUID.
+pub fn default_component_id(manifest_path: &Path, name: &str) -> Guid {
+    let hash =
+        Sha256::digest(format!("cargo-azsphere:{}:{name}", manifest_path.display()).as_bytes());
+    let mut bytes: [u8; 16] = hash[..16].try_into().unwrap();
+    // Guid stores the first three groups little-endian, so the version nibble is in byte 7
+    bytes[7] = (bytes[7] & 0x0f) | 0x50;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    Guid(bytes)
+}
+
+/// A new random ComponentId, formatted like a version 4 UUID
+pub fn random_component_id() -> Guid {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos();
+    // RandomState is seeded from the OS's random number generator
+    let seed = RandomState::new().hash_one(nanos);
+    let hash = Sha256::new()
+        .chain_update(nanos.to_le_bytes())
+        .chain_update(std::process::id().to_le_bytes())
+        .chain_update(seed.to_le_bytes())
+        .finalize();
+    let mut bytes: [u8; 16] = hash[..16].try_into().unwrap();
+    bytes[7] = (bytes[7] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    Guid(bytes)
+}
+
+fn capability_error(key: &str, type_name: &'static str) -> ConfigError {
+    ConfigError::WrongType(
+        format!("package.metadata.azsphere.capabilities.{key}"),
+        type_name,
+    )
+}
+
+fn resolve_peripherals(
+    key: &str,
+    value: &toml::Value,
+    hardware_definition: Option<&HardwareDefinition>,
+) -> Result<Value, Error> {
+    let values = value
+        .as_array()
+        .ok_or_else(|| capability_error(key, "array"))?;
+    values
+        .iter()
+        .map(|value| match value {
+            toml::Value::Integer(v) => Ok(Value::from(*v)),
+            toml::Value::String(name) => {
+                let name = name.strip_prefix('$').unwrap_or(name);
+                let definition = hardware_definition
+                    .ok_or_else(|| ConfigError::NoHardwareDefinition(name.to_string()))?;
+                Ok(definition.app_manifest_value(name)?.clone())
+            }
+            _ => Err(capability_error(key, "array of strings or integers").into()),
+        })
+        .collect()
+}
+
+fn capabilities(
+    table: &Table,
+    hardware_definition: Option<&HardwareDefinition>,
+) -> Result<Map<String, Value>, Error> {
+    for key in table.keys() {
+        if !CAPABILITIES.iter().any(|(k, _, _)| k == key) {
+            return Err(ConfigError::UnknownCapability(key.clone()).into());
+        }
+    }
+    // Follow the order of CAPABILITIES, so the manifest doesn't churn as Cargo.toml is edited
+    let mut capabilities = Map::new();
+    for (key, name, kind) in CAPABILITIES {
+        let Some(value) = table.get(*key) else {
+            continue;
+        };
+        let value = match kind {
+            Kind::Peripherals => resolve_peripherals(key, value, hardware_definition)?,
+            Kind::List | Kind::Flag | Kind::Plain => serde_json::to_value(value)
+                .map_err(|_| capability_error(key, "a JSON-compatible value"))?,
+            Kind::StorageSize => {
+                let size = value
+                    .as_integer()
+                    .ok_or_else(|| capability_error(key, "integer"))?;
+                json!({ "SizeKB": size })
+            }
+        };
+        capabilities.insert(name.to_string(), value);
+    }
+    Ok(capabilities)
+}
+
+fn generated_component_id(package_config: &PackageConfig) -> Result<Guid, ConfigError> {
+    match &package_config.component_id {
+        Some(id) => Guid::parse(id).ok_or_else(|| {
+            ConfigError::WrongType(
+                "package.metadata.azsphere.component_id".to_string(),
+                "a GUID",
+            )
+        }),
+        None => Ok(default_component_id(
+            &package_config.manifest_path,
+            &package_config.name,
+        )),
+    }
+}
+
+/// Build the app manifest for `package_config`, which must have `capabilities` set
+pub fn generate(
+    package_config: &PackageConfig,
+    hardware_definition: Option<&HardwareDefinition>,
+) -> Result<Value, Error> {
+    let table = package_config.capabilities.as_ref().ok_or_else(|| {
+        ConfigError::Missing("package.metadata.azsphere.capabilities".to_string())
+    })?;
+    let component_id = generated_component_id(package_config)?;
+    Ok(json!({
+        "SchemaVersion": 1,
+        "Name": package_config.name,
+        "ComponentId": component_id.to_string(),
+        "EntryPoint": format!("/bin/{}", package_config.name),
+        "CmdArgs": [],
+        "Capabilities": capabilities(table, hardware_definition)?,
+        "ApplicationType": "Default",
+        "MallocVersion": 2,
+    }))
+}
+
+/// ComponentId of the package's app manifest, whether generated or hand-written
+pub fn component_id(package_config: &PackageConfig) -> Result<String, Error> {
+    if package_config.capabilities.is_some() {
+        return Ok(generated_component_id(package_config)?.to_string());
+    }
+    let path = package_config.app_manifest.clone();
+    let data = fs::read_to_string(&path).map_err(|e| Error::FileIo(path.clone(), e))?;
+    let manifest: Value = serde_json::from_str(&data)
+        .map_err(|e| Error::BadAppManifest(path.clone(), e.to_string()))?;
+    let component_id = manifest["ComponentId"]
+        .as_str()
+        .ok_or_else(|| Error::BadAppManifest(path, "missing ComponentId".to_string()))?;
+    Ok(component_id.to_string())
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Error,
+}
+
+/// A problem found in an app manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    /// JSON path of the offending value, such as `$.Capabilities.Gpio[1]`
+    pub path: String,
+    pub message: String,
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        let severity = match self.severity {
+            Severity::Warning => "warning",
+            Severity::Error => "error",
+        };
+        write!(f, "{severity}: {}: {}", self.path, self.message)
+    }
+}
+
+#[derive(Default)]
+struct Diagnostics(Vec<Diagnostic>);
+
+impl Diagnostics {
+    fn error(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Error, path.into(), message.into());
+    }
+
+    fn warning(&mut self, path: impl Into<String>, message: impl Into<String>) {
+        self.push(Severity::Warning, path.into(), message.into());
+    }
+
+    fn push(&mut self, severity: Severity, path: String, message: String) {
+        self.0.push(Diagnostic {
+            severity,
+            path,
+            message,
+        });
+    }
+}
+
+/// Check an app manifest before it is packaged.  `executable_name` is the program staged under
+/// `bin/`, and `hardware_definition` resolves `$NAME` peripheral references.
+pub fn validate(
+    manifest: &Value,
+    executable_name: &str,
+    hardware_definition: Option<&HardwareDefinition>,
+) -> Vec<Diagnostic> {
+    let mut diagnostics = Diagnostics::default();
+    let Some(fields) = manifest.as_object() else {
+        diagnostics.error("$", "expected a JSON object");
+        return diagnostics.0;
+    };
+
+    if manifest["SchemaVersion"] != 1 {
+        diagnostics.error("$.SchemaVersion", "must be 1");
+    }
+    match manifest["Name"].as_str() {
+        Some(name) if !name.is_empty() => {}
+        _ => diagnostics.error("$.Name", "must be a non-empty string"),
+    }
+    if manifest["ComponentId"]
+        .as_str()
+        .and_then(Guid::parse)
+        .is_none()
+    {
+        diagnostics.error("$.ComponentId", "must be a GUID");
+    }
+    let entry_point = format!("/bin/{executable_name}");
+    if manifest["EntryPoint"].as_str() != Some(&entry_point) {
+        diagnostics.error(
+            "$.EntryPoint",
+            format!("must be `{entry_point}', where the executable is packaged"),
+        );
+    }
+    match manifest["ApplicationType"].as_str() {
+        Some("Default" | "Debugger") => {}
+        _ => diagnostics.error("$.ApplicationType", "must be \"Default\" or \"Debugger\""),
+    }
+    if let Some(args) = fields.get("CmdArgs") {
+        if !args
+            .as_array()
+            .is_some_and(|a| a.iter().all(Value::is_string))
+        {
+            diagnostics.error("$.CmdArgs", "must be an array of strings");
+        }
+    }
+    for name in fields.keys() {
+        let known = [
+            "SchemaVersion",
+            "Name",
+            "ComponentId",
+            "EntryPoint",
+            "ApplicationType",
+        ]
+        .contains(&name.as_str())
+            || OPTIONAL_FIELDS.contains(&name.as_str())
+            || name == "Capabilities";
+        if !known {
+            diagnostics.warning(format!("$.{name}"), "unknown field");
+        }
+    }
+
+    match fields.get("Capabilities").map(Value::as_object) {
+        Some(Some(capabilities)) => {
+            validate_capabilities(capabilities, hardware_definition, &mut diagnostics)
+        }
+        Some(None) => diagnostics.error("$.Capabilities", "must be an object"),
+        None => diagnostics.error("$.Capabilities", "is missing"),
+    }
+    diagnostics.0
+}
+
+fn validate_capabilities(
+    capabilities: &Map<String, Value>,
+    hardware_definition: Option<&HardwareDefinition>,
+    diagnostics: &mut Diagnostics,
+) {
+    for (name, value) in capabilities {
+        let path = format!("$.Capabilities.{name}");
+        let Some((_, _, kind)) = CAPABILITIES.iter().find(|(_, n, _)| n == name) else {
+            diagnostics.warning(path, "unknown capability");
+            continue;
+        };
+        match kind {
+            Kind::Peripherals => {
+                let Some(items) = value.as_array() else {
+                    diagnostics.error(path, "must be an array");
+                    continue;
+                };
+                let mut seen = HashSet::new();
+                for (i, item) in items.iter().enumerate() {
+                    let path = format!("{path}[{i}]");
+                    let resolved = match item {
+                        Value::String(s) if s.starts_with('$') => match hardware_definition {
+                            None => {
+                                diagnostics.error(
+                                    path,
+                                    format!("`{s}' needs target_hardware and target_definition to be set"),
+                                );
+                                continue;
+                            }
+                            Some(definition) => match definition.app_manifest_value(&s[1..]) {
+                                Ok(value) => value.clone(),
+                                Err(e) => {
+                                    diagnostics.error(path, e.to_string());
+                                    continue;
+                                }
+                            },
+                        },
+                        Value::String(_) | Value::Number(_) => item.clone(),
+                        _ => {
+                            diagnostics.error(path, "must be a string or an integer");
+                            continue;
+                        }
+                    };
+                    if name == "Gpio" && !seen.insert(resolved.to_string()) {
+                        diagnostics.error(path, format!("duplicate GPIO {resolved}"));
+                    }
+                }
+            }
+            Kind::List => {
+                let valid = value
+                    .as_array()
+                    .is_some_and(|a| a.iter().all(|v| v.is_string() || v.is_u64()));
+                if !valid {
+                    diagnostics.error(path, "must be an array of strings or integers");
+                }
+            }
+            Kind::Flag => {
+                if !value.is_boolean() {
+                    diagnostics.error(path, "must be true or false");
+                }
+            }
+            Kind::Plain => {}
+            Kind::StorageSize => match value["SizeKB"].as_i64() {
+                Some(size) if (1..=MAX_MUTABLE_STORAGE_KB).contains(&size) => {}
+                Some(size) => diagnostics.error(
+                    format!("{path}.SizeKB"),
+                    format!("{size} is outside 1..={MAX_MUTABLE_STORAGE_KB}"),
+                ),
+                None => diagnostics.error(format!("{path}.SizeKB"), "must be an integer"),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn package_config(capabilities: toml::Value) -> PackageConfig {
+        PackageConfig {
+            capabilities: capabilities.as_table().cloned(),
+            ..PackageConfig::for_test("test_app")
+        }
+    }
+
+    #[test]
+    fn test_generate() {
+        let dirs =
+            vec![PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/HardwareDefinitions")];
+        let definition =
+            HardwareDefinition::load(&dirs, "test_board/sample_appliance.json").unwrap();
+        let config = package_config(toml::toml! {
+            mutable_storage_kb = 64
+            gpio = ["SAMPLE_LED", "$SAMPLE_BUTTON", 4]
+            allowed_connections = ["example.com"]
+            uart = ["SAMPLE_UART"]
+            network_config = true
+        });
+        let manifest = generate(&config, Some(&definition)).unwrap();
+        assert_eq!(
+            config.app_manifest_source(),
+            "app manifest generated from package.metadata.azsphere.capabilities"
+        );
+        assert_eq!(
+            manifest,
+            json!({
+                "SchemaVersion": 1,
+                "Name": "test_app",
+                "ComponentId": default_component_id(&config.manifest_path, "test_app").to_string(),
+                "EntryPoint": "/bin/test_app",
+                "CmdArgs": [],
+                "Capabilities": {
+                    "Gpio": [8, 12, 4],
+                    "Uart": ["ISU0"],
+                    "AllowedConnections": ["example.com"],
+                    "MutableStorage": { "SizeKB": 64 },
+                    "NetworkConfig": true,
+                },
+                "ApplicationType": "Default",
+                "MallocVersion": 2,
+            })
+        );
+        let order = manifest["Capabilities"]
+            .as_object()
+            .unwrap()
+            .keys()
+            .collect::<Vec<_>>();
+        assert_eq!(
+            order,
+            [
+                "Gpio",
+                "Uart",
+                "AllowedConnections",
+                "MutableStorage",
+                "NetworkConfig"
+            ]
+        );
+
+        let id = default_component_id(&config.manifest_path, "test_app");
+        assert_eq!(id, default_component_id(&config.manifest_path, "test_app"));
+        assert_ne!(id, default_component_id(&config.manifest_path, "other_app"));
+        // A crate of the same name elsewhere is another app
+        let elsewhere = Path::new("/elsewhere/test_app/Cargo.toml");
+        assert_ne!(id, default_component_id(elsewhere, "test_app"));
+        assert_eq!(id.to_string().as_bytes()[14], b'5');
+        assert_eq!(Guid::parse(&id.to_string()), Some(id));
+
+        let config = package_config(toml::toml! { gpio = ["NOT_THERE"] });
+        assert!(matches!(
+            generate(&config, Some(&definition)),
+            Err(Error::Image(crate::error::ImageError::UnknownPeripheral(name))) if name == "NOT_THERE"
+        ));
+        assert!(matches!(
+            generate(&config, None),
+            Err(Error::Config(ConfigError::NoHardwareDefinition(name))) if name == "NOT_THERE"
+        ));
+        let config = package_config(toml::toml! { gpoi = [8] });
+        assert!(matches!(
+            generate(&config, None),
+            Err(Error::Config(ConfigError::UnknownCapability(key))) if key == "gpoi"
+        ));
+        let mut config = package_config(toml::toml! { gpio = [8] });
+        config.component_id = Some("911fa4f1-8fd7-4bd0-a4a7-6b5b2a2b4a12".to_string());
+        assert_eq!(
+            generate(&config, None).unwrap()["ComponentId"],
+            "911fa4f1-8fd7-4bd0-a4a7-6b5b2a2b4a12"
+        );
+    }
+
+    #[test]
+    fn test_component_id() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut config = package_config(toml::Value::Boolean(false));
+        config.app_manifest = dir.path().join("app_manifest.json");
+        fs::write(&config.app_manifest, r#"{"ComponentId": "c64ecd9e"}"#).unwrap();
+        assert_eq!(component_id(&config).unwrap(), "c64ecd9e");
+
+        for bad in [r#"{"Name": "test_app"}"#, "{"] {
+            fs::write(&config.app_manifest, bad).unwrap();
+            assert!(matches!(
+                component_id(&config),
+                Err(Error::BadAppManifest(path, _)) if path == config.app_manifest
+            ));
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let dirs =
+            vec![PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                .join("tests/fixtures/HardwareDefinitions")];
+        let definition =
+            HardwareDefinition::load(&dirs, "test_board/sample_appliance.json").unwrap();
+        let manifest: Value = serde_json::from_str(
+            &fs::read_to_string(dirs[0].join("../app_manifest.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(validate(&manifest, "test_app", Some(&definition)), vec![]);
+
+        let config = package_config(toml::toml! { gpio = ["SAMPLE_LED"] });
+        let generated = generate(&config, Some(&definition)).unwrap();
+        assert_eq!(validate(&generated, "test_app", None), vec![]);
+
+        let manifest = json!({
+            "SchemaVersion": 2,
+            "Name": "TestApp",
+            "ComponentId": "not-a-guid",
+            "EntryPoint": "/bin/other_app",
+            "ApplicationType": "Default",
+            "Extra": true,
+            "Capabilities": {
+                "Gpio": ["$SAMPLE_LED", 8, "$NOT_THERE"],
+                "MutableStorage": { "SizeKB": 65 },
+                "NetworkConfig": "yes",
+                "Bluetooth": true
+            }
+        });
+        let diagnostics = validate(&manifest, "test_app", Some(&definition))
+            .into_iter()
+            .map(|d| (d.severity, d.path))
+            .collect::<Vec<_>>();
+        let expected = [
+            (Severity::Error, "$.SchemaVersion"),
+            (Severity::Error, "$.ComponentId"),
+            (Severity::Error, "$.EntryPoint"),
+            (Severity::Warning, "$.Extra"),
+            (Severity::Error, "$.Capabilities.Gpio[1]"),
+            (Severity::Error, "$.Capabilities.Gpio[2]"),
+            (Severity::Error, "$.Capabilities.MutableStorage.SizeKB"),
+            (Severity::Error, "$.Capabilities.NetworkConfig"),
+            (Severity::Warning, "$.Capabilities.Bluetooth"),
+        ]
+        .map(|(severity, path)| (severity, path.to_string()));
+        assert_eq!(diagnostics, expected);
+
+        let diagnostics = validate(
+            &json!({ "Capabilities": { "Gpio": ["$SAMPLE_LED"] } }),
+            "x",
+            None,
+        );
+        assert!(diagnostics
+            .iter()
+            .any(|d| d.path == "$.Capabilities.Gpio[0]" && d.severity == Severity::Error));
+    }
+}
@@ -0,0 +1,555 @@
+//! `cargo azsphere new`: scaffold a crate from a template.
+//!
+//! The generated crate has the boilerplate every sample repeats: the STEP constants with the
+//! `set_step!`/`get_step!` macros, `hook_sigterm`, the `.cargo/config` env block, an app manifest
+//! with a fresh ComponentId, `[package.metadata.azsphere]` and VS Code launch settings.
+//!
+//! Each template's source starts with a paragraph of `use` lines, then a paragraph of STEP
+//! constants; both are spliced into `templates/main.rs` with the rest of the template.
+
+use crate::dry_run::Changes;
+use crate::error::{Error, ImageError};
+use crate::manifest;
+use crate::util;
+use crate::vscode::Launch;
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+const MAIN_RS: &str = include_str!("templates/main.rs");
+const CARGO_TOML: &str = include_str!("templates/Cargo.toml");
+const CARGO_CONFIG: &str = include_str!("templates/config.toml");
+const GITIGNORE: &str = include_str!("templates/gitignore");
+const SETTINGS_JSON: &str = include_str!("templates/settings.json");
+
+/// Profiles of the samples' workspace, for crates that aren't in a workspace
+const PROFILES: &str = r#"
+[profile.dev]
+panic = "abort"
+
+[profile.release]
+panic = "abort"
+lto = true
+codegen-units = 1"#;
+
+/// Imports every template uses
+const COMMON_IMPORTS: &[&str] = &[
+    "use azs::applibs::eventloop::{EventLoop, IoCallback, IoEvents};",
+    "use azs::applibs::eventloop_timer_utilities;",
+    "use azure_sphere as azs;",
+    "use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};",
+    "use std::sync::Arc;",
+];
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Template {
+    /// blink SAMPLE_LED
+    Blink,
+    /// blink SAMPLE_LED from a timer, with SAMPLE_BUTTON_1 changing the rate
+    EventLoop,
+    /// show whether networking is ready on SAMPLE_LED
+    Network,
+    /// read an LSM6DS3 accelerometer over I2C and write the readings to a UART
+    Sensor,
+}
+
+impl Template {
+    fn name(self) -> &'static str {
+        match self {
+            Template::Blink => "blink",
+            Template::EventLoop => "event-loop",
+            Template::Network => "network",
+            Template::Sensor => "sensor",
+        }
+    }
+
+    fn source(self) -> &'static str {
+        match self {
+            Template::Blink => include_str!("templates/blink.rs"),
+            Template::EventLoop => include_str!("templates/event_loop.rs"),
+            Template::Network => include_str!("templates/network.rs"),
+            Template::Sensor => include_str!("templates/sensor.rs"),
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            Template::Blink => "It blinks SAMPLE_LED from an event loop timer.",
+            Template::EventLoop => {
+                "It blinks SAMPLE_LED from an event loop timer, and a second timer polls\n\
+                 // SAMPLE_BUTTON_1, which changes the blink rate."
+            }
+            Template::Network => {
+                "It polls whether networking is ready, blinking SAMPLE_LED until it is and\n\
+                 // keeping it lit while it stays ready."
+            }
+            Template::Sensor => {
+                "It reads the LSM6DS3 accelerometer on SAMPLE_LSM6DS3_I2C each second, and\n\
+                 // writes the readings to SAMPLE_UART_LOOPBACK."
+            }
+        }
+    }
+
+    /// Peripherals used, by app manifest capability
+    fn peripherals(self) -> &'static [(&'static str, &'static [&'static str])] {
+        match self {
+            Template::Blink | Template::Network => &[("Gpio", &["SAMPLE_LED"])],
+            Template::EventLoop => &[("Gpio", &["SAMPLE_BUTTON_1", "SAMPLE_LED"])],
+            Template::Sensor => &[
+                ("I2cMaster", &["SAMPLE_LSM6DS3_I2C"]),
+                ("Uart", &["SAMPLE_UART_LOOPBACK"]),
+            ],
+        }
+    }
+}
+
+#[derive(clap::Parser, Debug)]
+pub(crate) struct CliArgs {
+    /// directory to create the crate in
+    path: PathBuf,
+    /// package name [DEFAULT = the directory name]
+    #[arg(long)]
+    name: Option<String>,
+    /// what the app does
+    #[arg(long, value_enum, default_value = "blink")]
+    template: Template,
+    /// target hardware, a directory of hardware/HardwareDefinitions
+    #[arg(long, default_value = "mt3620_rdb")]
+    target_hardware: String,
+    /// target hardware definition, a file in the target hardware's directory
+    #[arg(long, default_value = "sample_appliance")]
+    target_definition: String,
+    /// Application Runtime Version
+    #[arg(long, default_value = "16")]
+    arv: String,
+    /// directory of the hardware crate [DEFAULT = hardware/ in the nearest ancestor that has one]
+    #[arg(long)]
+    hardware: Option<PathBuf>,
+    /// directory of the azure-sphere crate [DEFAULT = azure-sphere/ next to the hardware crate]
+    #[arg(long)]
+    azure_sphere: Option<PathBuf>,
+    /// display verbose output
+    #[arg(short, long)]
+    verbose: bool,
+}
+
+#[derive(Debug)]
+pub struct CliSetting {
+    path: PathBuf,
+    name: Option<String>,
+    template: Template,
+    target_hardware: String,
+    target_definition: String,
+    arv: String,
+    hardware: Option<PathBuf>,
+    azure_sphere: Option<PathBuf>,
+    verbose: bool,
+}
+
+impl CliSetting {
+    pub(crate) fn new(args: CliArgs) -> Self {
+        Self {
+            path: args.path,
+            name: args.name,
+            template: args.template,
+            target_hardware: args.target_hardware,
+            target_definition: args.target_definition,
+            arv: args.arv,
+            hardware: args.hardware,
+            azure_sphere: args.azure_sphere,
+            verbose: args.verbose,
+        }
+    }
+
+    /// Create the crate
+    pub fn do_new(&self) -> Result<(), Error> {
+        if self.path.exists() {
+            return Err(Error::AlreadyExists(self.path.clone()));
+        }
+        let name = match &self.name {
+            Some(name) => name.clone(),
+            None => self
+                .path
+                .file_name()
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default(),
+        };
+        if !is_valid_package_name(&name) {
+            return Err(Error::InvalidPackageName(name));
+        }
+
+        let parent = self
+            .path
+            .parent()
+            .filter(|parent| !parent.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        fs::create_dir_all(parent).map_err(|e| Error::FileIo(parent.to_path_buf(), e))?;
+        let parent =
+            fs::canonicalize(parent).map_err(|e| Error::FileIo(parent.to_path_buf(), e))?;
+        let crate_dir = parent.join(self.path.file_name().unwrap_or_default());
+
+        let hardware = match &self.hardware {
+            Some(hardware) => {
+                fs::canonicalize(hardware).map_err(|e| Error::FileIo(hardware.clone(), e))?
+            }
+            None => find_hardware_crate(&crate_dir).ok_or(Error::HardwareCrateNotFound)?,
+        };
+        let azure_sphere = match &self.azure_sphere {
+            Some(azure_sphere) => fs::canonicalize(azure_sphere)
+                .map_err(|e| Error::FileIo(azure_sphere.clone(), e))?,
+            None => hardware.with_file_name("azure-sphere"),
+        };
+        check_peripherals(
+            &hardware,
+            &self.target_hardware,
+            &self.target_definition,
+            self.template,
+        )?;
+
+        let workspace = find_workspace(&parent);
+        let vars = BTreeMap::from([
+            ("name", name.clone()),
+            ("template", self.template.name().to_string()),
+            ("description", self.template.description().to_string()),
+            ("arv", self.arv.clone()),
+            ("target_hardware", self.target_hardware.clone()),
+            ("target_definition", self.target_definition.clone()),
+            (
+                "hardware_path",
+                util::relative_path(&crate_dir, &hardware)
+                    .display()
+                    .to_string(),
+            ),
+            (
+                "azure_sphere_path",
+                util::relative_path(&crate_dir, &azure_sphere)
+                    .display()
+                    .to_string(),
+            ),
+            (
+                "profiles",
+                match workspace {
+                    Some(_) => String::new(),
+                    None => PROFILES.to_string(),
+                },
+            ),
+        ]);
+
+        let files = [
+            ("Cargo.toml", render(CARGO_TOML, &vars)),
+            (".cargo/config", render(CARGO_CONFIG, &vars)),
+            (".gitignore", GITIGNORE.to_string()),
+            ("app_manifest.json", app_manifest(&name, self.template)),
+            ("src/main.rs", main_rs(self.template, &vars)),
+            (".vscode/settings.json", SETTINGS_JSON.to_string()),
+        ];
+        for (file, contents) in files {
+            let path = crate_dir.join(file);
+            if self.verbose {
+                println!("Writing {}", path.display());
+            }
+            if let Some(dir) = path.parent() {
+                fs::create_dir_all(dir).map_err(|e| Error::FileIo(dir.to_path_buf(), e))?;
+            }
+            fs::write(&path, contents).map_err(|e| Error::FileIo(path.clone(), e))?;
+        }
+        let launch = Launch {
+            name: name.clone(),
+            variant: None,
+            arv: self.arv.clone(),
+            sdk_path: util::sdk_path(),
+            crate_dir: crate_dir.clone(),
+            target_path: workspace
+                .as_deref()
+                .unwrap_or(&crate_dir)
+                .join("target/armv7-unknown-linux-musleabihf/debug"),
+            release: false,
+            device: None,
+        };
+        launch.write(Changes(None), self.verbose)?;
+
+        println!(
+            "Created {} from the {} template, for {}/{}",
+            name,
+            self.template.name(),
+            self.target_hardware,
+            self.target_definition
+        );
+        if let Some(workspace) = workspace {
+            println!(
+                "Add \"{}\" to the members of {}",
+                util::relative_path(&workspace, &crate_dir).display(),
+                workspace.join("Cargo.toml").display()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Cargo accepts letters, digits, `-` and `_`, not starting with a digit
+fn is_valid_package_name(name: &str) -> bool {
+    name.chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+        && name.chars().next().is_some_and(|c| !c.is_ascii_digit())
+}
+
+/// Replace each `{{key}}` in `template` with its value
+fn render(template: &str, vars: &BTreeMap<&str, String>) -> String {
+    let mut text = template.to_string();
+    for (key, value) in vars {
+        text = text.replace(&format!("{{{{{key}}}}}"), value);
+    }
+    text.trim_end().to_string() + "\n"
+}
+
+fn main_rs(template: Template, vars: &BTreeMap<&str, String>) -> String {
+    let mut sections = template.source().splitn(3, "\n\n");
+    let mut imports = sections
+        .next()
+        .unwrap_or_default()
+        .lines()
+        .chain(COMMON_IMPORTS.iter().copied())
+        .collect::<Vec<_>>();
+    // rustfmt's order: module names before type names
+    imports.sort_by_key(|line| {
+        line.chars()
+            .map(|c| match c {
+                'a'..='z' => c.to_ascii_uppercase(),
+                'A'..='Z' => c.to_ascii_lowercase(),
+                _ => c,
+            })
+            .collect::<String>()
+    });
+
+    let mut vars = vars.clone();
+    vars.insert("imports", imports.join("\n"));
+    vars.insert("steps", sections.next().unwrap_or_default().to_string());
+    vars.insert(
+        "body",
+        format!("\n{}", render(sections.next().unwrap_or_default(), &vars)),
+    );
+    render(MAIN_RS, &vars)
+}
+
+fn app_manifest(name: &str, template: Template) -> String {
+    let capabilities = template
+        .peripherals()
+        .iter()
+        .map(|(capability, names)| {
+            let names = names.iter().map(|name| json!(format!("${name}"))).collect();
+            (capability.to_string(), Value::Array(names))
+        })
+        .collect::<Map<_, _>>();
+    let manifest = json!({
+        "SchemaVersion": 1,
+        "Name": name,
+        "ComponentId": manifest::random_component_id().to_string(),
+        "EntryPoint": format!("/bin/{name}"),
+        "CmdArgs": [],
+        "Capabilities": capabilities,
+        "ApplicationType": "Default",
+        "MallocVersion": 2
+    });
+    serde_json::to_string_pretty(&manifest).unwrap_or_default() + "\n"
+}
+
+/// The nearest `hardware` crate in `dir` or its ancestors, or in their `rust` subdirectories
+fn find_hardware_crate(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .flat_map(|dir| [dir.join("hardware"), dir.join("rust").join("hardware")])
+        .find(|hardware| hardware.join("HardwareDefinitions").is_dir())
+}
+
+/// Directory of the Cargo.toml with a `[workspace]` table in `dir` or its ancestors
+fn find_workspace(dir: &Path) -> Option<PathBuf> {
+    dir.ancestors()
+        .find(|dir| {
+            fs::read_to_string(dir.join("Cargo.toml"))
+                .ok()
+                .and_then(|text| text.parse::<toml::Value>().ok())
+                .is_some_and(|toml| toml.get("workspace").is_some())
+        })
+        .map(|dir| dir.to_path_buf())
+}
+
+/// Check the target hardware definition names every peripheral the template uses
+fn check_peripherals(
+    hardware: &Path,
+    target_hardware: &str,
+    target_definition: &str,
+    template: Template,
+) -> Result<(), Error> {
+    let path = hardware
+        .join("HardwareDefinitions")
+        .join(target_hardware)
+        .join(format!("{target_definition}.json"));
+    if !path.is_file() {
+        return Err(ImageError::HardwareDefinitionNotFound(path.display().to_string()).into());
+    }
+    let text = fs::read_to_string(&path).map_err(|e| Error::FileIo(path.clone(), e))?;
+    let definition: Value =
+        serde_json::from_str(&text).map_err(|e| Error::Json(path.clone(), e))?;
+    let defined = definition["Peripherals"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|peripheral| peripheral["Name"].as_str())
+        .collect::<Vec<_>>();
+    for (_, names) in template.peripherals() {
+        if let Some(name) = names.iter().find(|name| !defined.contains(name)) {
+            return Err(ImageError::UnknownPeripheral(name.to_string()).into());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hwdef::HardwareDefinition;
+    use clap::ValueEnum;
+
+    #[test]
+    fn test_new() {
+        let root = tempfile::tempdir().unwrap();
+        let hardware = root.path().join("rust/hardware");
+        let definitions = hardware.join("HardwareDefinitions");
+        fs::create_dir_all(&definitions).unwrap();
+        fs::create_dir_all(root.path().join("rust/azure-sphere")).unwrap();
+        let peripherals = [
+            ("SAMPLE_LED", "Gpio", json!(8)),
+            ("SAMPLE_BUTTON_1", "Gpio", json!(12)),
+            ("SAMPLE_LSM6DS3_I2C", "I2cMaster", json!("ISU2")),
+            ("SAMPLE_UART_LOOPBACK", "Uart", json!("ISU0")),
+        ]
+        .map(|(name, peripheral_type, value)| {
+            json!({ "Name": name, "Type": peripheral_type, "AppManifestValue": value })
+        });
+        fs::create_dir(definitions.join("mt3620_rdb")).unwrap();
+        fs::write(
+            definitions.join("mt3620_rdb/sample_appliance.json"),
+            json!({ "Peripherals": peripherals }).to_string(),
+        )
+        .unwrap();
+        fs::write(
+            definitions.join("mt3620_rdb/leds_only.json"),
+            json!({ "Peripherals": [peripherals[0]] }).to_string(),
+        )
+        .unwrap();
+        fs::write(
+            root.path().join("rust/Cargo.toml"),
+            "[workspace]\nmembers = []\n",
+        )
+        .unwrap();
+
+        for template in Template::value_variants() {
+            let path = root.path().join("rust/samples").join(template.name());
+            let setting = CliSetting {
+                path: path.clone(),
+                name: None,
+                template: *template,
+                target_hardware: "mt3620_rdb".to_string(),
+                target_definition: "sample_appliance".to_string(),
+                arv: "16".to_string(),
+                hardware: None,
+                azure_sphere: None,
+                verbose: false,
+            };
+            setting.do_new().unwrap();
+
+            let cargo_toml: toml::Value = fs::read_to_string(path.join("Cargo.toml"))
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert_eq!(
+                cargo_toml["dependencies"]["hardware"]["path"].as_str(),
+                Some("../../hardware")
+            );
+            assert_eq!(
+                cargo_toml["dependencies"]["azure-sphere"]["path"].as_str(),
+                Some("../../azure-sphere")
+            );
+            let metadata = &cargo_toml["package"]["metadata"]["azsphere"];
+            assert_eq!(
+                metadata["target_definition"].as_str(),
+                Some("sample_appliance")
+            );
+            assert!(cargo_toml.get("profile").is_none());
+
+            let config: toml::Value = fs::read_to_string(path.join(".cargo/config"))
+                .unwrap()
+                .parse()
+                .unwrap();
+            assert_eq!(
+                config["env"]["AZURE_SPHERE_TARGET_HARDWARE"].as_str(),
+                Some("mt3620_rdb")
+            );
+
+            let app_manifest: Value =
+                serde_json::from_str(&fs::read_to_string(path.join("app_manifest.json")).unwrap())
+                    .unwrap();
+            let definition = HardwareDefinition::load(
+                &[definitions.join("mt3620_rdb")],
+                "sample_appliance.json",
+            )
+            .unwrap();
+            let diagnostics = manifest::validate(&app_manifest, template.name(), Some(&definition));
+            assert!(diagnostics.is_empty(), "{diagnostics:?}");
+
+            let main_rs = fs::read_to_string(path.join("src/main.rs")).unwrap();
+            assert!(!main_rs.contains("{{"), "{main_rs}");
+            assert!(main_rs.contains("hardware::sample_appliance::SAMPLE_"));
+            assert!(main_rs.contains("macro_rules! set_step"));
+            let launch = serde_json::from_str::<Value>(
+                &fs::read_to_string(path.join(".vscode/launch.json")).unwrap(),
+            )
+            .unwrap();
+            assert_eq!(
+                launch["configurations"][0]["program"],
+                format!(
+                    "${{workspaceFolder}}/../../target/armv7-unknown-linux-musleabihf/debug/{}.unstripped",
+                    template.name()
+                )
+            );
+            assert!(path.join(".vscode/tasks.json").is_file());
+        }
+
+        let other: Value = serde_json::from_str(
+            &fs::read_to_string(root.path().join("rust/samples/blink/app_manifest.json")).unwrap(),
+        )
+        .unwrap();
+        let mine: Value = serde_json::from_str(
+            &fs::read_to_string(root.path().join("rust/samples/sensor/app_manifest.json")).unwrap(),
+        )
+        .unwrap();
+        assert_ne!(other["ComponentId"], mine["ComponentId"]);
+
+        let setting = CliSetting {
+            path: root.path().join("rust/samples/blink"),
+            name: None,
+            template: Template::Blink,
+            target_hardware: "mt3620_rdb".to_string(),
+            target_definition: "sample_appliance".to_string(),
+            arv: "16".to_string(),
+            hardware: None,
+            azure_sphere: None,
+            verbose: false,
+        };
+        assert!(matches!(setting.do_new(), Err(Error::AlreadyExists(_))));
+
+        let setting = CliSetting {
+            path: root.path().join("rust/samples/leds_only"),
+            template: Template::Sensor,
+            target_definition: "leds_only".to_string(),
+            ..setting
+        };
+        assert!(matches!(
+            setting.do_new(),
+            Err(Error::Image(ImageError::UnknownPeripheral(name))) if name == "SAMPLE_LSM6DS3_I2C"
+        ));
+        assert!(!root.path().join("rust/samples/leds_only").exists());
+        assert!(!is_valid_package_name("1st"));
+        assert!(is_valid_package_name("my-app_2"));
+    }
+}
@@ -0,0 +1,65 @@
+use azs::applibs::gpio::{self, OutputPin, Value};
+use std::time::Duration;
+
+const STEP_INIT_LED: i32 = 10;
+const STEP_LED_TIMERHANDLER_CONSUME: i32 = 11;
+const STEP_LED_TIMERHANDLER_LED_SET_STATE: i32 = 12;
+
+const BLINK_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Toggles an LED each time its timer fires
+struct BlinkTimer {
+    led: OutputPin,
+    state: Value,
+    elt: eventloop_timer_utilities::EventLoopTimer,
+}
+
+impl BlinkTimer {
+    fn new(id: u32, period: Duration) -> Result<Self, std::io::Error> {
+        let led = OutputPin::new(id, gpio::OutputMode::PushPull, Value::High)?;
+        let elt = eventloop_timer_utilities::EventLoopTimer::new()?;
+        elt.set_period(period)?;
+        Ok(Self {
+            led,
+            state: Value::High,
+            elt,
+        })
+    }
+}
+
+impl IoCallback for BlinkTimer {
+    fn event(&mut self, _events: IoEvents) {
+        set_step!(STEP_LED_TIMERHANDLER_CONSUME);
+        if let Err(e) = self.elt.consume_event() {
+            azs::debug!("EventLoopTimer::consume_event() failed with {:?}\n", e);
+            std::process::exit(get_step!());
+        }
+
+        set_step!(STEP_LED_TIMERHANDLER_LED_SET_STATE);
+        self.state = if self.state == Value::High {
+            Value::Low
+        } else {
+            Value::High
+        };
+        let _ = self.led.set_value(self.state);
+    }
+
+    unsafe fn fd(&self) -> i32 {
+        self.elt.fd()
+    }
+}
+
+// A main(), except that it returns a Result<T,E>, making it easy to invoke functions using the '?' operator.
+fn actual_main() -> Result<(), std::io::Error> {
+    azs::debug!("{{name}} starting.\n");
+    let term = hook_sigterm()?;
+    let mut event_loop = EventLoop::new()?;
+
+    // Open SAMPLE_LED GPIO, set as output with value GPIO_Value_High (off), and blink it
+    azs::debug!("Opening SAMPLE_LED as output.\n");
+    set_step!(STEP_INIT_LED);
+    let mut blink_timer = BlinkTimer::new(hardware::{{target_definition}}::SAMPLE_LED, BLINK_INTERVAL)?;
+    event_loop.register_io(IoEvents::Input, &mut blink_timer)?;
+
+    run_event_loop(&event_loop, &term)
+}
@@ -0,0 +1,164 @@
+use azs::applibs::gpio::{self, InputPin, OutputPin, Value};
+use std::sync::atomic::AtomicU8;
+use std::time::Duration;
+
+const STEP_INIT_BUTTON: i32 = 10;
+const STEP_INIT_LED: i32 = 11;
+const STEP_LED_TIMERHANDLER_CONSUME: i32 = 12;
+const STEP_LED_TIMERHANDLER_LED_SET_STATE: i32 = 13;
+const STEP_BUTTON_TIMERHANDLER_CONSUME: i32 = 14;
+const STEP_CHECK_BUTTON1: i32 = 15;
+
+static BLINK_INTERVALS: [Duration; 3] = [
+    Duration::from_millis(125),
+    Duration::from_millis(250),
+    Duration::from_millis(500),
+];
+
+const BUTTON_PRESS_CHECK_PERIOD: Duration = Duration::from_millis(1);
+
+struct Button {
+    button: InputPin,
+    old_value: AtomicU8,
+}
+
+impl Button {
+    pub fn new(id: u32) -> Result<Self, std::io::Error> {
+        let button = InputPin::new(id)?;
+        Ok(Self {
+            button,
+            old_value: AtomicU8::new(0u8),
+        })
+    }
+
+    pub fn is_pressed(&self) -> Result<bool, std::io::Error> {
+        let new_state = self.button.value()?;
+        let is_pressed = (new_state as u8) != self.old_value.load(Ordering::Relaxed)
+            && new_state == gpio::Value::Low;
+        self.old_value.store(new_state as u8, Ordering::Relaxed);
+        Ok(is_pressed)
+    }
+}
+
+/// Toggles an LED each time its timer fires
+struct BlinkTimer {
+    led: OutputPin,
+    state: Value,
+    elt: Arc<eventloop_timer_utilities::EventLoopTimer>,
+}
+
+impl BlinkTimer {
+    fn new(
+        id: u32,
+        elt: Arc<eventloop_timer_utilities::EventLoopTimer>,
+        period: Duration,
+    ) -> Result<Self, std::io::Error> {
+        let led = OutputPin::new(id, gpio::OutputMode::PushPull, Value::High)?;
+        elt.set_period(period)?;
+        Ok(Self {
+            led,
+            state: Value::High,
+            elt,
+        })
+    }
+}
+
+impl IoCallback for BlinkTimer {
+    fn event(&mut self, _events: IoEvents) {
+        set_step!(STEP_LED_TIMERHANDLER_CONSUME);
+        if let Err(e) = self.elt.consume_event() {
+            azs::debug!("EventLoopTimer::consume_event() failed with {:?}\n", e);
+            std::process::exit(get_step!());
+        }
+
+        set_step!(STEP_LED_TIMERHANDLER_LED_SET_STATE);
+        self.state = if self.state == Value::High {
+            Value::Low
+        } else {
+            Value::High
+        };
+        let _ = self.led.set_value(self.state);
+    }
+
+    unsafe fn fd(&self) -> i32 {
+        self.elt.fd()
+    }
+}
+
+/// Polls a button, and cycles the blink rate of the LED timer when it's pressed
+struct ButtonChecker {
+    button: Button,
+    elt: eventloop_timer_utilities::EventLoopTimer,
+    blink_interval_index: usize,
+    led_elt: Arc<eventloop_timer_utilities::EventLoopTimer>,
+}
+
+impl ButtonChecker {
+    fn new(
+        button: Button,
+        period: Duration,
+        led_elt: Arc<eventloop_timer_utilities::EventLoopTimer>,
+    ) -> Result<Self, std::io::Error> {
+        let elt = eventloop_timer_utilities::EventLoopTimer::new()?;
+        elt.set_period(period)?;
+        Ok(Self {
+            button,
+            elt,
+            blink_interval_index: 0,
+            led_elt,
+        })
+    }
+}
+
+impl IoCallback for ButtonChecker {
+    fn event(&mut self, _events: IoEvents) {
+        let mut event_handler = || -> Result<(), std::io::Error> {
+            set_step!(STEP_BUTTON_TIMERHANDLER_CONSUME);
+            self.elt.consume_event()?;
+
+            set_step!(STEP_CHECK_BUTTON1);
+            if self.button.is_pressed()? {
+                self.blink_interval_index = (self.blink_interval_index + 1) % BLINK_INTERVALS.len();
+                self.led_elt
+                    .set_period(BLINK_INTERVALS[self.blink_interval_index])?;
+            }
+            Ok(())
+        };
+        if let Err(e) = event_handler() {
+            azs::debug!("Button timer callback failed with {:?}\n", e);
+            std::process::exit(get_step!());
+        }
+    }
+
+    unsafe fn fd(&self) -> i32 {
+        self.elt.fd()
+    }
+}
+
+// A main(), except that it returns a Result<T,E>, making it easy to invoke functions using the '?' operator.
+fn actual_main() -> Result<(), std::io::Error> {
+    azs::debug!("{{name}} starting.\n");
+    let term = hook_sigterm()?;
+    let mut event_loop = EventLoop::new()?;
+
+    // Open SAMPLE_LED GPIO, set as output with value GPIO_Value_High (off), and set up a timer to
+    // blink it
+    azs::debug!("Opening SAMPLE_LED as output.\n");
+    set_step!(STEP_INIT_LED);
+    let led_elt = Arc::new(eventloop_timer_utilities::EventLoopTimer::new()?);
+    let mut blink_timer = BlinkTimer::new(
+        hardware::{{target_definition}}::SAMPLE_LED,
+        led_elt.clone(),
+        BLINK_INTERVALS[0],
+    )?;
+    event_loop.register_io(IoEvents::Input, &mut blink_timer)?;
+
+    // Open SAMPLE_BUTTON_1 GPIO as input, and set up a timer to poll it
+    azs::debug!("Opening SAMPLE_BUTTON_1 as input.\n");
+    set_step!(STEP_INIT_BUTTON);
+    let button = Button::new(hardware::{{target_definition}}::SAMPLE_BUTTON_1)?;
+    let mut button_checker = ButtonChecker::new(button, BUTTON_PRESS_CHECK_PERIOD, led_elt)?;
+    event_loop.register_io(IoEvents::Input, &mut button_checker)?;
+
+    run_event_loop(&event_loop, &term)
+}
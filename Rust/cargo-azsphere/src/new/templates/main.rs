@@ -0,0 +1,67 @@
+// {{name}}: an Azure Sphere high-level app, created by `cargo azsphere new` from the
+// {{template}} template.
+//
+// {{description}}
+//
+// Rust doesn't have mutable global variables, so the C samples' exitCode is replaced by STEP,
+// the step about to be attempted, set and read with the set_step and get_step macros.
+{{imports}}
+
+const STEP_SUCCESS: i32 = 0;
+const STEP_TERMHANDLER_SIGTERM: i32 = 1;
+const STEP_SIGNAL_REGISTRATION: i32 = 2;
+const STEP_EVENTLOOP: i32 = 5;
+{{steps}}
+
+/// Currently executing program step
+static STEP: AtomicI32 = AtomicI32::new(STEP_SUCCESS);
+
+/// Macro to assign a new value to STEP.
+macro_rules! set_step {
+    ($i:ident) => {
+        STEP.store($i, Ordering::Relaxed);
+    };
+}
+/// Macro to read the current STEP value
+macro_rules! get_step {
+    () => {
+        STEP.load(Ordering::Relaxed)
+    };
+}
+
+/// Hook SIGTERM so that it modifies the returned AtomicBool if signalled
+fn hook_sigterm() -> Result<Arc<AtomicBool>, std::io::Error> {
+    set_step!(STEP_SIGNAL_REGISTRATION);
+    let term = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register_conditional_shutdown(
+        signal_hook::consts::SIGTERM,
+        STEP_TERMHANDLER_SIGTERM,
+        Arc::clone(&term),
+    )?;
+
+    Ok(term)
+}
+
+/// Dispatch events until SIGTERM is received
+fn run_event_loop(event_loop: &EventLoop, term: &AtomicBool) -> Result<(), std::io::Error> {
+    while !term.load(Ordering::Relaxed) {
+        if let Err(e) = event_loop.run(-1, true) {
+            if e.kind() != std::io::ErrorKind::Interrupted {
+                set_step!(STEP_EVENTLOOP);
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+{{body}}
+pub fn main() -> ! {
+    let result = actual_main();
+    if result.is_err() {
+        azs::debug!("Failed at step {:?} with {:?}\n", get_step!(), result.err());
+        std::process::exit(get_step!());
+    }
+
+    azs::debug!("Application exiting\n");
+    std::process::exit(get_step!());
+}
@@ -0,0 +1,98 @@
+use azs::applibs::gpio::{self, OutputPin, Value};
+use azs::applibs::networking;
+use std::time::Duration;
+
+const STEP_INIT_LED: i32 = 10;
+const STEP_NETWORK_TIMERHANDLER_CONSUME: i32 = 11;
+const STEP_NETWORK_TIMERHANDLER_CHECK: i32 = 12;
+
+const NETWORK_CHECK_PERIOD: Duration = Duration::from_millis(500);
+
+/// Polls the network status, blinking an LED until networking is ready and then keeping it lit
+struct NetworkStatus {
+    led: OutputPin,
+    state: Value,
+    is_ready: bool,
+    elt: eventloop_timer_utilities::EventLoopTimer,
+}
+
+impl NetworkStatus {
+    fn new(id: u32, period: Duration) -> Result<Self, std::io::Error> {
+        let led = OutputPin::new(id, gpio::OutputMode::PushPull, Value::High)?;
+        let elt = eventloop_timer_utilities::EventLoopTimer::new()?;
+        elt.set_period(period)?;
+        Ok(Self {
+            led,
+            state: Value::High,
+            is_ready: false,
+            elt,
+        })
+    }
+
+    fn set_led(&mut self, value: Value) -> Result<(), std::io::Error> {
+        self.led.set_value(value)?;
+        self.state = value;
+        Ok(())
+    }
+
+    /// Called when networking becomes ready; start talking to your services here
+    fn on_network_ready(&mut self) {
+        azs::debug!("Networking is ready.\n");
+    }
+
+    /// Called when networking is lost
+    fn on_network_lost(&mut self) {
+        azs::debug!("Networking is not ready.\n");
+    }
+}
+
+impl IoCallback for NetworkStatus {
+    fn event(&mut self, _events: IoEvents) {
+        let mut event_handler = || -> Result<(), std::io::Error> {
+            set_step!(STEP_NETWORK_TIMERHANDLER_CONSUME);
+            self.elt.consume_event()?;
+
+            set_step!(STEP_NETWORK_TIMERHANDLER_CHECK);
+            let is_ready = networking::is_networking_ready()?;
+            if is_ready != self.is_ready {
+                self.is_ready = is_ready;
+                if is_ready {
+                    self.on_network_ready();
+                } else {
+                    self.on_network_lost();
+                }
+            }
+            // The LED is lit when its GPIO is low
+            let value = if is_ready || self.state == Value::High {
+                Value::Low
+            } else {
+                Value::High
+            };
+            self.set_led(value)
+        };
+        if let Err(e) = event_handler() {
+            azs::debug!("Network timer callback failed with {:?}\n", e);
+            std::process::exit(get_step!());
+        }
+    }
+
+    unsafe fn fd(&self) -> i32 {
+        self.elt.fd()
+    }
+}
+
+// A main(), except that it returns a Result<T,E>, making it easy to invoke functions using the '?' operator.
+fn actual_main() -> Result<(), std::io::Error> {
+    azs::debug!("{{name}} starting.\n");
+    let term = hook_sigterm()?;
+    let mut event_loop = EventLoop::new()?;
+
+    // SAMPLE_LED blinks while waiting for the network, and stays lit once it is ready
+    azs::debug!("Opening SAMPLE_LED as output.\n");
+    set_step!(STEP_INIT_LED);
+    let mut network_status =
+        NetworkStatus::new(hardware::{{target_definition}}::SAMPLE_LED, NETWORK_CHECK_PERIOD)?;
+    event_loop.register_io(IoEvents::Input, &mut network_status)?;
+
+    run_event_loop(&event_loop, &term)
+}
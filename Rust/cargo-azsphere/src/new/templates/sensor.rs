@@ -0,0 +1,121 @@
+use azs::applibs::i2c;
+use azs::applibs::uart;
+use std::fs::File;
+use std::io::Write;
+use std::time::Duration;
+
+const STEP_INIT_I2C: i32 = 10;
+const STEP_INIT_SENSOR: i32 = 11;
+const STEP_INIT_UART: i32 = 12;
+const STEP_SENSOR_TIMERHANDLER_CONSUME: i32 = 13;
+const STEP_SENSOR_TIMERHANDLER_READ: i32 = 14;
+
+const SENSOR_READ_PERIOD: Duration = Duration::from_secs(1);
+
+/// I2C address of the LSM6DS3 accelerometer
+const LSM6DS3_ADDRESS: i2c::DeviceAddress = 0x6a;
+const LSM6DS3_WHO_AM_I: u8 = 0x0f;
+const LSM6DS3_WHO_AM_I_VALUE: u8 = 0x69;
+const LSM6DS3_CTRL1_XL: u8 = 0x10;
+/// 104 Hz, +/-2 g
+const LSM6DS3_CTRL1_XL_104HZ_2G: u8 = 0x40;
+const LSM6DS3_OUTX_L_XL: u8 = 0x28;
+/// Milli-g per least significant bit at +/-2 g
+const LSM6DS3_MG_PER_LSB: f32 = 0.061;
+
+/// Reads the accelerometer each time its timer fires, and writes the reading to a UART
+struct SensorReader {
+    i2c: i2c::I2CMaster,
+    uart: File,
+    elt: eventloop_timer_utilities::EventLoopTimer,
+}
+
+impl SensorReader {
+    fn new(i2c: i2c::I2CMaster, uart: File, period: Duration) -> Result<Self, std::io::Error> {
+        let elt = eventloop_timer_utilities::EventLoopTimer::new()?;
+        elt.set_period(period)?;
+        Ok(Self { i2c, uart, elt })
+    }
+
+    /// Acceleration in milli-g along the x, y and z axes
+    fn read_acceleration(&self) -> Result<[f32; 3], std::io::Error> {
+        let mut raw = [0u8; 6];
+        self.i2c
+            .write_then_read(LSM6DS3_ADDRESS, &[LSM6DS3_OUTX_L_XL], &mut raw)?;
+        let axis = |i: usize| i16::from_le_bytes([raw[i * 2], raw[i * 2 + 1]]) as f32;
+        Ok([0, 1, 2].map(|i| axis(i) * LSM6DS3_MG_PER_LSB))
+    }
+}
+
+impl IoCallback for SensorReader {
+    fn event(&mut self, _events: IoEvents) {
+        set_step!(STEP_SENSOR_TIMERHANDLER_CONSUME);
+        if let Err(e) = self.elt.consume_event() {
+            azs::debug!("EventLoopTimer::consume_event() failed with {:?}\n", e);
+            std::process::exit(get_step!());
+        }
+
+        set_step!(STEP_SENSOR_TIMERHANDLER_READ);
+        match self.read_acceleration() {
+            Ok([x, y, z]) => {
+                let line = format!("Acceleration [mg]: X={x:.2} Y={y:.2} Z={z:.2}\r\n");
+                azs::debug!("{}", line);
+                if let Err(e) = self.uart.write_all(line.as_bytes()) {
+                    azs::debug!("ERROR: could not write to UART: {:?}\n", e);
+                }
+            }
+            Err(e) => azs::debug!("ERROR: could not read accelerometer: {:?}\n", e),
+        }
+    }
+
+    unsafe fn fd(&self) -> i32 {
+        self.elt.fd()
+    }
+}
+
+/// Check the accelerometer is present, then turn it on
+fn init_sensor(i2c: &i2c::I2CMaster) -> Result<(), std::io::Error> {
+    let mut who_am_i = [0u8];
+    i2c.write_then_read(LSM6DS3_ADDRESS, &[LSM6DS3_WHO_AM_I], &mut who_am_i)?;
+    if who_am_i[0] != LSM6DS3_WHO_AM_I_VALUE {
+        azs::debug!("ERROR: unexpected WHO_AM_I value {:#x}\n", who_am_i[0]);
+        return Err(std::io::Error::from(std::io::ErrorKind::NotFound));
+    }
+    i2c.write(
+        LSM6DS3_ADDRESS,
+        &[LSM6DS3_CTRL1_XL, LSM6DS3_CTRL1_XL_104HZ_2G],
+    )?;
+    Ok(())
+}
+
+// A main(), except that it returns a Result<T,E>, making it easy to invoke functions using the '?' operator.
+fn actual_main() -> Result<(), std::io::Error> {
+    azs::debug!("{{name}} starting.\n");
+    let term = hook_sigterm()?;
+    let mut event_loop = EventLoop::new()?;
+
+    azs::debug!("Opening SAMPLE_LSM6DS3_I2C.\n");
+    set_step!(STEP_INIT_I2C);
+    let i2c_master = i2c::I2CMaster::new(hardware::{{target_definition}}::SAMPLE_LSM6DS3_I2C as _)?;
+    i2c_master.set_bus_speed(400_000)?;
+    i2c_master.set_timeout(100)?;
+
+    set_step!(STEP_INIT_SENSOR);
+    init_sensor(&i2c_master)?;
+
+    azs::debug!("Opening SAMPLE_UART_LOOPBACK.\n");
+    set_step!(STEP_INIT_UART);
+    let uart_config = uart::UARTConfig {
+        baud_rate: 115200,
+        ..Default::default()
+    };
+    let uart = uart::open(
+        hardware::{{target_definition}}::SAMPLE_UART_LOOPBACK as _,
+        uart_config,
+    )?;
+
+    let mut sensor_reader = SensorReader::new(i2c_master, uart, SENSOR_READ_PERIOD)?;
+    event_loop.register_io(IoEvents::Input, &mut sensor_reader)?;
+
+    run_event_loop(&event_loop, &term)
+}
@@ -1,14 +1,24 @@
-use crate::config::{Config, ExtraMetadataSource};
-use crate::error::{self, ConfigError};
+use crate::applibs;
+use crate::config::{Config, ExtraMetadataSource, PackageConfig};
+use crate::dry_run::{self, Changes, DryRun, Step};
+use crate::elf;
+use crate::error::{self, ConfigError, ImageError};
+use crate::extra_files::{self, ExtraFile};
+use crate::hwdef::HardwareDefinition;
+use crate::image;
+use crate::manifest;
+use crate::partners;
+use crate::size::SizeReport;
+use crate::tool::{self, SystemRunner};
+use crate::util;
+use crate::workspace::{Member, Workspace};
 use anyhow::Context;
 use serde_json::Value;
-use std::env;
+use sha2::{Digest, Sha256};
 use std::fs;
-use std::io::{self, Write};
-use std::path::PathBuf;
-use std::process::Command;
+use std::path::{Path, PathBuf};
 
-#[derive(clap::Parser, Debug)]
+#[derive(clap::Parser, Debug, Clone)]
 #[group(skip)]
 pub(crate) struct CliArgs {
     /// set a package name of the workspace
@@ -23,7 +33,8 @@ pub(crate) struct CliArgs {
     /// overwrite metadata with TOML file. If suffixed with "#dotted.key", load "dotted.key" table instead of the root table.
     #[arg(long)]
     metadata_overwrite: Vec<String>,
-    /// shortcut to --metadata-overwrite=path/to/Cargo.toml#package.metadata.generate-rpm.variants.VARIANT
+    /// build variant: a table of [package.metadata.azsphere.variants], which overrides the
+    /// package's metadata, and is packaged as <name>-<VARIANT>.imagepackage
     #[arg(long)]
     variant: Option<String>,
     /// enable verbose logging
@@ -31,6 +42,35 @@ pub(crate) struct CliArgs {
     pub(crate) verbose: bool,
 }
 
+/// Arguments of the subcommands that package: `package` and `build`
+#[derive(clap::Parser, Debug, Clone)]
+#[group(skip)]
+pub(crate) struct PackageArgs {
+    #[clap(flatten)]
+    pub(crate) common: CliArgs,
+    /// package every variant of [package.metadata.azsphere.variants]
+    #[arg(long, conflicts_with = "variant")]
+    all_variants: bool,
+    /// write the app package in-process, without the SDK's azsphere CLI.  Experimental: the
+    /// packages are not yet checked against the SDK's
+    #[arg(long)]
+    native_pack: bool,
+    #[clap(flatten)]
+    pub(crate) dry_run: dry_run::CliArgs,
+}
+
+impl PackageArgs {
+    /// Package the crates `common` selects, as they are
+    pub(crate) fn new(common: CliArgs) -> Self {
+        Self {
+            common,
+            all_variants: false,
+            native_pack: false,
+            dry_run: dry_run::CliArgs::default(),
+        }
+    }
+}
+
 impl CliArgs {
     pub(crate) fn extra_metadata(&self) -> Vec<ExtraMetadataSource> {
         if self.verbose {
@@ -54,282 +94,630 @@ impl CliArgs {
                     .enumerate()
                     .map(|(i, v)| (i, ExtraMetadataSource::Text(v.clone()))),
             )
-            .chain(self.variant.iter().enumerate().map(|(i, v)| {
-                let file = match &self.package {
-                    None => PathBuf::from("Cargo.toml"),
-                    Some(package) => PathBuf::from(package).join("Cargo.toml"),
-                };
-                let branch = format!("package.metadata.generate-rpm.variants.{v}");
-                (i, ExtraMetadataSource::File(file, Some(branch)))
-            }))
             .collect::<Vec<_>>();
         extra_metadata.sort_by_key(|(i, _)| *i);
-        extra_metadata.into_iter().map(|(_, v)| v).collect()
+        // The variant overrides the package's metadata, and the command line overrides both
+        self.variant
+            .iter()
+            .map(|v| ExtraMetadataSource::Variant(v.clone()))
+            .chain(extra_metadata.into_iter().map(|(_, v)| v))
+            .collect()
     }
 }
 
+/// Directories to search for the package's hardware definition: the target hardware's directory
+/// in the `hardware` crate that `crate_name` depends on, then the SDK's HardwareDefinitions.
+/// `None` when no target hardware or definition is configured.
+pub(crate) fn hardware_definition_dirs(
+    cargo_metadata: &Value,
+    crate_name: &str,
+    package_config: &PackageConfig,
+    sdk_path: Option<&Path>,
+) -> Option<Vec<PathBuf>> {
+    let target_hardware = package_config.target_hardware.as_ref()?;
+    package_config.target_definition.as_ref()?;
+    let hardware = cargo_metadata["packages"]
+        .as_array()?
+        .iter()
+        .find(|&x| x["name"] == crate_name)?["dependencies"]
+        .as_array()?
+        .iter()
+        .find(|&x| x["name"] == "hardware")?;
+    let mut dirs = vec![PathBuf::from(hardware["path"].as_str()?)
+        .join("HardwareDefinitions")
+        .join(target_hardware)];
+    if let Some(sdk_path) = sdk_path {
+        dirs.push(sdk_path.join("HardwareDefinitions"));
+    }
+    Some(dirs)
+}
+
+/// The executable built into `target_path`, with the device's interpreter but not stripped
+pub(crate) fn unstripped_executable(target_path: &Path, name: &str) -> PathBuf {
+    target_path.join(format!("{name}.unstripped"))
+}
+
+/// The app package of `package_config`, built into `target_path`
+pub(crate) fn app_package(target_path: &Path, package_config: &PackageConfig) -> PathBuf {
+    target_path.join(package_config.package_name() + ".imagepackage")
+}
+
+/// The crate being packaged, as found by `cargo metadata` and its Cargo.toml
+#[derive(Debug)]
+pub(crate) struct PackageContext {
+    pub cargo_metadata: Value,
+    /// cargo package name of the crate
+    pub crate_name: String,
+    pub manifest_file_dir: PathBuf,
+    pub package_config: PackageConfig,
+    /// the executable to package
+    pub executable: PathBuf,
+    /// build output directory, such as target/armv7-unknown-linux-musleabihf/debug
+    pub target_path: PathBuf,
+}
+
+impl PackageContext {
+    pub fn unstripped_executable(&self) -> PathBuf {
+        unstripped_executable(&self.target_path, &self.package_config.package_name())
+    }
+
+    pub fn app_package(&self) -> PathBuf {
+        app_package(&self.target_path, &self.package_config)
+    }
+
+    /// The files `extra_files` adds to the app package
+    pub fn extra_files(&self) -> Result<Vec<ExtraFile>, ConfigError> {
+        let entries = self
+            .package_config
+            .extra_files
+            .as_deref()
+            .unwrap_or_default();
+        let out_dir = || extra_files::build_script_out_dir(&self.target_path, &self.crate_name);
+        extra_files::resolve(entries, &self.manifest_file_dir, &out_dir)
+    }
+
+    /// Files the app package is built from
+    fn inputs(&self) -> Result<Vec<PathBuf>, ConfigError> {
+        let mut inputs = vec![
+            self.executable.clone(),
+            self.manifest_file_dir.join("Cargo.toml"),
+        ];
+        if self.package_config.capabilities.is_none() {
+            inputs.push(self.package_config.app_manifest.clone());
+        }
+        let extra_files = self.extra_files()?;
+        inputs.extend(extra_files.into_iter().map(|extra_file| extra_file.source));
+        Ok(inputs)
+    }
+
+    /// Directories to search for the hardware definition, if one is configured
+    pub fn hardware_definition_dirs(&self) -> Option<Vec<PathBuf>> {
+        hardware_definition_dirs(
+            &self.cargo_metadata,
+            &self.crate_name,
+            &self.package_config,
+            util::sdk_path().as_deref(),
+        )
+    }
+
+    /// The hardware definition of `target_definition`, if one is configured
+    pub fn hardware_definition(&self) -> Result<Option<HardwareDefinition>, error::Error> {
+        match (
+            self.hardware_definition_dirs(),
+            self.package_config.target_definition_file(),
+        ) {
+            (Some(dirs), Some(file)) => Ok(Some(HardwareDefinition::load(&dirs, &file)?)),
+            _ => Ok(None),
+        }
+    }
+
+    /// The file recording `config_hash` of the app package last built
+    pub fn config_stamp(&self) -> PathBuf {
+        self.target_path
+            .join(self.package_config.package_name() + ".config-hash")
+    }
+
+    /// Hash of the resolved config, overrides included, and of the hardware definition files,
+    /// which the app package depends on as well as on `inputs`
+    pub fn config_hash(&self) -> Result<String, error::Error> {
+        let mut hasher = Sha256::new();
+        hasher.update(self.package_config.to_json().to_string());
+        for file in self.hardware_definition()?.iter().flat_map(|d| d.files()) {
+            hasher.update(fs::read(file).map_err(|e| error::Error::FileIo(file.clone(), e))?);
+        }
+        Ok(hasher
+            .finalize()
+            .iter()
+            .map(|b| format!("{b:02x}"))
+            .collect())
+    }
+
+    /// Whether the app package is missing, older than any of the files it is built from, or
+    /// built with another config.  Always, when `extra_files` or the hardware definition can't
+    /// be resolved, so that packaging reports why.
+    pub fn is_package_stale(&self) -> bool {
+        let Ok(hash) = self.config_hash() else {
+            return true;
+        };
+        fs::read_to_string(self.config_stamp()).map_or(true, |stamp| stamp != hash)
+            || self
+                .inputs()
+                .map_or(true, |inputs| is_stale(&self.app_package(), &inputs))
+    }
+}
+
+/// Arguments of `azsphere image-package pack-application` to pack `dest_dir` into `app_package`
+fn pack_application_args(
+    dest_dir: &Path,
+    app_package: &Path,
+    options: &image::PackOptions,
+    verbose: bool,
+) -> Vec<String> {
+    let path = |path: &Path| path.display().to_string();
+    let mut args = vec![
+        "image-package".to_string(),
+        "pack-application".to_string(),
+        "--package-directory".to_string(),
+        path(dest_dir),
+        "--destination".to_string(),
+        path(app_package),
+        "--target-api-set".to_string(),
+        options.target_api_set.clone(),
+    ];
+    if let Some(target_definition) = &options.target_definition {
+        args.push("--target-definition-filename".to_string());
+        args.push(target_definition.clone());
+        args.push("--hardware-definitions".to_string());
+        args.extend(options.hardware_definition_dirs.iter().map(|dir| path(dir)));
+    }
+    if verbose {
+        args.push("--verbose".to_string());
+    }
+    args
+}
+
+fn is_stale(output: &Path, inputs: &[PathBuf]) -> bool {
+    let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+    let Some(output_modified) = modified(output) else {
+        return true;
+    };
+    inputs
+        .iter()
+        .any(|input| modified(input).is_none_or(|m| m > output_modified))
+}
+
 #[derive(Debug)]
 pub struct CliSetting {
+    package: Option<String>,
     release: bool,
     verbose: bool,
+    all_variants: bool,
+    native_pack: bool,
     extra_metadata: Vec<ExtraMetadataSource>,
+    pub(crate) dry_run: Option<DryRun>,
 }
 
 impl CliSetting {
-    pub(crate) fn new(args: CliArgs) -> Self {
-        let extra_metadata = args.extra_metadata();
+    pub(crate) fn new(args: PackageArgs) -> Self {
+        let extra_metadata = args.common.extra_metadata();
 
         Self {
-            release: args.release,
-            verbose: args.verbose,
+            package: args.common.package,
+            release: args.common.release,
+            verbose: args.common.verbose,
+            all_variants: args.all_variants,
+            native_pack: args.native_pack,
             extra_metadata,
+            dry_run: args.dry_run.dry_run(),
         }
     }
 
-    pub fn build_app_package(self) -> anyhow::Result<()> {
-        if self.verbose {
-            println!("Invoking 'cargo metadata'");
+    /// Package with `[package.metadata.azsphere.test]` too, which overrides the variant
+    pub(crate) fn with_test_metadata(mut self) -> Self {
+        let variants = self
+            .extra_metadata
+            .iter()
+            .take_while(|source| matches!(source, ExtraMetadataSource::Variant(_)))
+            .count();
+        self.extra_metadata
+            .insert(variants, ExtraMetadataSource::Test);
+        self
+    }
+
+    /// The metadata sources of each variant of `member` to package: all of them with
+    /// `--all-variants`, or else only the one selected
+    fn variants_metadata(
+        &self,
+        member: &Member,
+    ) -> Result<Vec<Vec<ExtraMetadataSource>>, error::Error> {
+        if !self.all_variants {
+            return Ok(vec![self.extra_metadata.clone()]);
         }
-        let output = Command::new("cargo")
-            .arg("metadata")
-            .arg("--format-version=1")
-            .output()
-            .expect("Failed to run 'cargo metadata'");
-        if self.verbose {
-            println!("Parsing metadata");
+        let variants = Config::new(&member.manifest_path, &[])?.variants()?;
+        if variants.is_empty() {
+            println!("{} has no variants; packaging it as it is", member.name);
+            return Ok(vec![self.extra_metadata.clone()]);
         }
-        let cargo_metadata: Value = serde_json::from_slice(output.stdout.as_slice()).unwrap();
+        Ok(variants
+            .into_iter()
+            .map(|variant| {
+                let mut extra_metadata = vec![ExtraMetadataSource::Variant(variant)];
+                extra_metadata.extend(self.extra_metadata.iter().cloned());
+                extra_metadata
+            })
+            .collect())
+    }
 
-        if self.verbose {
-            println!("Finding package config");
+    /// Find the crates to package, and their variants, and where their build output goes
+    pub(crate) fn contexts(&self) -> anyhow::Result<Vec<PackageContext>> {
+        let workspace = Workspace::load(self.verbose)?;
+        let target_path = workspace.target_path(self.release);
+        let mut contexts = vec![];
+        for member in workspace.members(self.package.as_deref())? {
+            for extra_metadata in self.variants_metadata(&member)? {
+                let package_config =
+                    workspace.package_config(&member, &extra_metadata, self.verbose)?;
+                contexts.push(PackageContext {
+                    cargo_metadata: workspace.metadata.clone(),
+                    crate_name: member.name.clone(),
+                    manifest_file_dir: member.dir().to_path_buf(),
+                    executable: target_path.join(&package_config.name),
+                    package_config,
+                    target_path: target_path.clone(),
+                });
+            }
         }
-        let manifest_file_dir = env::current_dir().context("failed to get manifest file")?;
-        let manifest_file_path = manifest_file_dir.join("Cargo.toml");
-        let config = Config::new(&manifest_file_path, self.extra_metadata.as_slice())?;
-        let package_config = config.package_config(
-            cargo_metadata["workspace_root"]
-                .to_string()
-                .replace("\"", ""),
-            self.verbose,
-        )?;
+        Ok(contexts)
+    }
 
-        // cargo_metadata["target_directory"] + "armv7-unknown-linux-musleabihf" + "debug" + crate_name is the executable file
-        if self.verbose {
-            println!("Determining target_path");
+    /// Package each selected crate
+    pub fn build_app_package(&self) -> anyhow::Result<()> {
+        for context in self.contexts()? {
+            self.package(&context)?;
         }
-        let build_flavor = if self.release { "release" } else { "debug" };
-        let target_directory = cargo_metadata["target_directory"]
-            .to_string()
-            .replace("\"", "");
-        let mut target_path = PathBuf::from(target_directory);
-        target_path.push("armv7-unknown-linux-musleabihf");
-        target_path.push(build_flavor);
+        Ok(())
+    }
 
-        let source_program = target_path.join(&package_config.name);
+    pub(crate) fn package(&self, context: &PackageContext) -> anyhow::Result<()> {
+        let PackageContext {
+            cargo_metadata,
+            package_config,
+            target_path,
+            ..
+        } = context;
+        let source_program = context.executable.clone();
+        let dry_run = self.dry_run.as_ref();
+        let changes = Changes(dry_run);
+        if let Some(dry_run) = dry_run {
+            dry_run.record(Step::Config(package_config.to_json()));
+        }
 
         let out_dir = target_path.join("out");
-        let dest_dir = out_dir.join(&package_config.name);
+        let dest_dir = out_dir.join(package_config.package_name());
         if self.verbose {
             println!("Staging files to {}", dest_dir.display());
         }
-        let _ = fs::remove_dir_all(&dest_dir); // Remove the previous contents.  Ignore failures, which can happen if the dir doesn't exist.
-        fs::create_dir_all(&dest_dir)?; // Create a clean directory
+        changes.remove_dir_all(&dest_dir); // Remove the previous contents
+        changes.create_dir_all(&dest_dir)?; // Create a clean directory
 
         let dest_bin_dir = dest_dir.join("bin");
-        fs::create_dir_all(&dest_bin_dir)?;
+        changes.create_dir_all(&dest_bin_dir)?;
+
+        let hardware_definition_dirs = context.hardware_definition_dirs();
+        let hardware_definition = context.hardware_definition()?;
 
-        // cp app_manifest.json out/
         let dest_app_manifest = dest_dir.join("app_manifest.json");
+        let app_manifest_text = if package_config.capabilities.is_some() {
+            if self.verbose {
+                println!("Generating app manifest from package.metadata.azsphere.capabilities");
+            }
+            let app_manifest = manifest::generate(package_config, hardware_definition.as_ref())
+                .context("failed to generate app manifest")?;
+            let text = serde_json::to_string_pretty(&app_manifest)?;
+            changes.write(&dest_app_manifest, &text)?;
+            text
+        } else {
+            // cp app_manifest.json out/
+            if self.verbose {
+                println!(
+                    "Copy app manifest {}",
+                    package_config.app_manifest.display()
+                );
+            }
+            changes
+                .copy(&package_config.app_manifest, &dest_app_manifest)
+                .context("failed to copy app manifest")?;
+            fs::read_to_string(&package_config.app_manifest)
+                .map_err(|e| error::Error::FileIo(package_config.app_manifest.clone(), e))?
+        };
+
         if self.verbose {
-            println!("Copy app manifest {}", &package_config.app_manifest);
+            println!("Validating app manifest");
         }
-        fs::copy(&package_config.app_manifest, &dest_app_manifest)
-            .context("failed to copy app manifest")?;
+        let app_manifest: Value = serde_json::from_str(&app_manifest_text).map_err(|e| {
+            error::Error::BadAppManifest(package_config.app_manifest.clone(), e.to_string())
+        })?;
+        let diagnostics = manifest::validate(
+            &app_manifest,
+            &package_config.name,
+            hardware_definition.as_ref(),
+        );
+        for diagnostic in &diagnostics {
+            eprintln!("{}: {diagnostic}", package_config.app_manifest_source());
+        }
+        let errors = diagnostics
+            .iter()
+            .filter(|d| d.severity == manifest::Severity::Error)
+            .count();
+        if errors > 0 {
+            return Err(ImageError::InvalidManifest(format!("{errors} error(s) found")).into());
+        }
+        self.check_partners(&context.package_config, &app_manifest)?;
 
         // cp ../target/armv7-unknown-linux-musleabihf/debug/${APPNAME} out/bin
         let dest_program = dest_bin_dir.join(&package_config.name);
-        if self.verbose {
+        let unstripped = context.unstripped_executable();
+        let executable = match dry_run {
+            // The executable may not be built yet, so it is neither read nor checked
+            Some(dry_run) => {
+                dry_run.record(Step::Strip {
+                    from: source_program,
+                    unstripped,
+                    to: dest_program,
+                });
+                None
+            }
+            None => Some(self.strip_executable(
+                &source_program,
+                &unstripped,
+                &dest_program,
+                &app_manifest,
+                package_config,
+            )?),
+        };
+
+        // Copy extra files
+        let extra_files = context.extra_files()?;
+        let mut total_size = 0;
+        for extra_file in &extra_files {
+            let destination_file_path = dest_dir.join(&extra_file.destination);
+            if self.verbose {
+                println!(
+                    "Copying extra_file {} => {} ",
+                    extra_file.source.display(),
+                    extra_file.destination.display()
+                );
+            }
+            let destination_file_directory = destination_file_path.parent().unwrap();
+            changes.create_dir_all(destination_file_directory)?;
+            let size = changes.copy(&extra_file.source, &destination_file_path)?;
+            total_size += size;
+            if dry_run.is_none() {
+                println!("    {size:>10}  {}", extra_file.destination.display());
+            }
+        }
+        if !extra_files.is_empty() && dry_run.is_none() {
             println!(
-                "Copying app executable from {} to {}",
-                source_program.display(),
-                dest_program.display()
+                "    {total_size:>10}  total of {} extra file(s)",
+                extra_files.len()
             );
         }
+
+        let app_package_name = context.app_package();
+        let Some((unstripped_elf, binary_size)) = executable else {
+            // A dry run, which leaves the executable alone
+            if let Some(dry_run) = dry_run {
+                dry_run.record(Step::Pack(dest_dir, app_package_name));
+            }
+            return Ok(());
+        };
+
+        let mut pack_options = image::PackOptions {
+            target_api_set: package_config.arv.clone(),
+            ..Default::default()
+        };
+        if let Some(hardware_definition_dirs) = hardware_definition_dirs {
+            pack_options.target_definition = package_config.target_definition_file();
+            pack_options.hardware_definition_dirs = hardware_definition_dirs;
+        }
+        if self.verbose {
+            println!("Pack options: {:?}\n", pack_options);
+        }
+        println!("Generating {}", app_package_name.display());
+        self.pack(&dest_dir, &app_package_name, &pack_options)
+            .context("failed to create app package")?;
+
+        let package_size = fs::metadata(&app_package_name)?.len();
+        let report = SizeReport::new(
+            &unstripped_elf,
+            cargo_metadata,
+            binary_size,
+            total_size,
+            package_size,
+        )?;
+        report.print(package_config);
+        if let Err(e) = report.check(package_config) {
+            // Don't leave a package that a later build would take to be up to date
+            let _ = fs::remove_file(&app_package_name);
+            return Err(e.into());
+        }
+        let stamp = context.config_stamp();
+        fs::write(&stamp, context.config_hash()?).map_err(|e| error::Error::FileIo(stamp, e))?;
+
+        Ok(())
+    }
+
+    /// Pack the staged `dest_dir` into `app_package`: with the SDK's CLI, or in-process with
+    /// `--native-pack`
+    fn pack(
+        &self,
+        dest_dir: &Path,
+        app_package: &Path,
+        options: &image::PackOptions,
+    ) -> Result<(), error::Error> {
+        if self.native_pack {
+            return image::pack_application(dest_dir, app_package, options);
+        }
+        let azsphere = util::required_sdk_path()?.join("Tools_v2/azsphere");
+        let args = pack_application_args(dest_dir, app_package, options, self.verbose);
+        tool::run(&SystemRunner, &azsphere, &args)?;
+        Ok(())
+    }
+
+    /// Check that each partner of the app is a real-time app, and that they allow connections
+    /// with each other
+    fn check_partners(
+        &self,
+        package_config: &PackageConfig,
+        app_manifest: &Value,
+    ) -> anyhow::Result<()> {
+        if package_config.partners.is_empty() {
+            return Ok(());
+        }
+        if self.verbose {
+            println!("Checking the partners");
+        }
+        let mut problems = vec![];
+        for partner in partners::resolve(package_config) {
+            let partner_manifest = partner.app_manifest().with_context(|| {
+                format!(
+                    "failed to read the app manifest of partner {}",
+                    partner.name
+                )
+            })?;
+            problems.extend(partners::check_real_time(&partner.name, &partner_manifest));
+            problems.extend(partners::check_connection(
+                (&package_config.name, app_manifest),
+                (&partner.name, &partner_manifest),
+            ));
+        }
+        for problem in &problems {
+            eprintln!("{}: error: {problem}", package_config.app_manifest_source());
+        }
+        if !problems.is_empty() {
+            return Err(error::Error::Partners(problems.len()).into());
+        }
+        Ok(())
+    }
+
+    /// Check the executable's imports against the app manifest, then write it with the
+    /// device's interpreter to `unstripped` and stripped to `dest_program`.  Returns the
+    /// unstripped executable and the size of the stripped one.
+    fn strip_executable(
+        &self,
+        source_program: &Path,
+        unstripped: &Path,
+        dest_program: &Path,
+        app_manifest: &Value,
+        package_config: &PackageConfig,
+    ) -> anyhow::Result<(Vec<u8>, u64)> {
         if !source_program.exists() {
             anyhow::bail!(
-                "executable `{}` does not exist. Are you sure you compiled it with `cargo build`?",
+                "executable `{}` does not exist. Build it with `cargo azsphere build`, or `cargo build`",
                 source_program.display()
             );
         }
-        fs::copy(&source_program, &dest_program).with_context(|| {
-            format!(
-                "failed to copy executable from {} to {}",
-                source_program.display(),
-                dest_program.display()
-            )
-        })?;
+        let elf = fs::read(source_program)
+            .with_context(|| format!("failed to read {}", source_program.display()))?;
 
-        // patchelf --set-interpreter /lib/ld-musl-armhf.so.1 out/bin/${APPNAME}
         if self.verbose {
-            println!("Running patchelf");
+            println!("Checking applibs imports against the app manifest capabilities");
         }
-        let output = Command::new("patchelf")
-            .arg("--set-interpreter")
-            .arg("/lib/ld-musl-armhf.so.1")
-            .arg(dest_program.as_os_str())
-            .output()
-            .expect("Failed to execute patchelf");
-        if !output.status.success() {
-            println!("patchelf command failed:");
-            io::stdout().write_all(&output.stdout).unwrap();
-            io::stderr().write_all(&output.stderr).unwrap();
-            std::process::exit(output.status.code().unwrap());
+        for diagnostic in applibs::check_capabilities(app_manifest, &elf)? {
+            eprintln!("{}: {diagnostic}", package_config.app_manifest_source());
         }
 
-        // ${AzureSphereDefaultSDKDir}/Sysroots/${ARV}/tools/sysroots/x86_64-pokysdk-linux/usr/bin/arm-poky-linux-musleabi/arm-poky-linux-musleabi-strip --strip-debug --strip-unneeded out/bin/${APPNAME}
-        let sdk_path = PathBuf::from(std::env::var("AzureSphereDefaultSDKDir").unwrap());
-        let strip_command = sdk_path.join("Sysroots").join(&package_config.arv).join("tools/sysroots/x86_64-pokysdk-linux/usr/bin/arm-poky-linux-musleabi/arm-poky-linux-musleabi-strip");
+        // The equivalent of patchelf --set-interpreter /lib/ld-musl-armhf.so.1, keeping a copy
+        // with symbols for debugging
         if self.verbose {
-            println!("Running arm-poky-linux-musleabi-strip");
-        }
-        let output = Command::new(strip_command)
-            .arg("--strip-debug")
-            .arg("--strip-unneeded")
-            .arg(dest_program.as_os_str())
-            .output()
-            .expect("Failed to execute strip");
-        if !output.status.success() {
-            println!("patchelf command failed:");
-            io::stdout().write_all(&output.stdout).unwrap();
-            io::stderr().write_all(&output.stderr).unwrap();
-            std::process::exit(output.status.code().unwrap());
+            println!(
+                "Setting the interpreter from {} to {}",
+                elf::interpreter(&elf)?.as_deref().unwrap_or("nothing"),
+                elf::MUSL_INTERPRETER
+            );
         }
+        let unstripped_elf = elf::set_interpreter(&elf, elf::MUSL_INTERPRETER)?;
+        fs::write(unstripped, &unstripped_elf)
+            .map_err(|e| error::Error::FileIo(unstripped.to_path_buf(), e))?;
 
-        // Copy extra files
-        if let Some(extra_files) = package_config.extra_files {
-            for extra_file in extra_files {
-                let extra_file = extra_file.as_array().ok_or(ConfigError::WrongType(
-                    "extra_files entry".to_string(),
-                    "array",
-                ))?;
-                if extra_file.len() < 1 || extra_file.len() > 2 {
-                    return Err(error::Error::Config(ConfigError::MalFormedArray).into());
-                }
-                let source_file = extra_file[0].as_str().ok_or(ConfigError::WrongType(
-                    "extra_files entry source filename".to_string(),
-                    "string",
-                ))?;
-                let destination_file = if extra_file.len() == 1 {
-                    source_file
-                } else {
-                    extra_file[1].as_str().ok_or(ConfigError::WrongType(
-                        "extra_files entry destination filename".to_string(),
-                        "string",
-                    ))?
-                };
-
-                let source_file_path = manifest_file_dir.join(source_file);
-                let destination_file_path = dest_dir.join(destination_file);
-                if self.verbose {
-                    println!(
-                        "Copying extra_file {} => {} ",
-                        source_file, destination_file
-                    );
-                }
-                let destination_file_directory = destination_file_path.parent().unwrap();
-                fs::create_dir_all(destination_file_directory)?;
-                fs::copy(source_file_path, destination_file_path)?;
-            }
+        // The equivalent of strip --strip-debug --strip-unneeded
+        if self.verbose {
+            println!(
+                "Stripping the executable and writing it to {}",
+                dest_program.display()
+            );
         }
+        let elf = elf::strip(&unstripped_elf)?;
+        let binary_size = elf.len() as u64;
+        fs::write(dest_program, elf)
+            .map_err(|e| error::Error::FileIo(dest_program.to_path_buf(), e))?;
+        let permissions = fs::metadata(source_program)?.permissions();
+        fs::set_permissions(dest_program, permissions)?;
+        Ok((unstripped_elf, binary_size))
+    }
+}
 
-        let target_definition_file =
-            if let Some(target_definition) = &package_config.target_definition {
-                let t = target_definition.to_owned() + ".json";
-                Some(t)
-            } else {
-                None
-            };
-
-        // ${AzureSphereDefaultSDKDir}/Tools_v2/azsphere image-package pack-application --package-directory out/ --destination ${APPNAME}.imagepackage --target-api-set ${ARV}
-        let azsphere = sdk_path.join("Tools_v2/azsphere");
-        let app_package_name = target_path.join(package_config.name.clone() + ".imagepackage");
-        let mut args = vec![
-            "image-package",
-            "pack-application",
-            "--package-directory",
-            dest_dir.as_os_str().to_str().unwrap(),
-            "--destination",
-            app_package_name.as_os_str().to_str().unwrap(),
-            "--target-api-set",
-            &package_config.arv,
-        ];
-
-        let app_package = cargo_metadata["packages"]
-            .as_array()
-            .unwrap()
-            .into_iter()
-            .find(|&x| x["name"] == package_config.name);
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::time::{Duration, SystemTime};
 
-        let hw_defs_path = if let Some(target_hardware) = &package_config.target_hardware {
-            if self.verbose {
-                println!("target_hardware {:?}\n", target_hardware);
-                println!("app_package: {:?}\n", app_package);
-            }
-            if let Some(app_package) = app_package {
-                let hardware = app_package["dependencies"]
-                    .as_array()
-                    .unwrap()
-                    .into_iter()
-                    .find(|&x| x["name"] == "hardware");
-                if let Some(hardware) = hardware {
-                    let path = &hardware["path"].to_string().replace("\"", "");
-                    Some(
-                        PathBuf::from(path)
-                            .join("HardwareDefinitions")
-                            .join(target_hardware),
-                    )
-                } else {
-                    None
-                }
-            } else {
-                None
-            }
-        } else {
-            None
+    #[test]
+    fn test_is_stale() {
+        let dir = tempfile::tempdir().unwrap();
+        let touch = |name: &str, age: u64| {
+            let path = dir.path().join(name);
+            let file = fs::File::create(&path).unwrap();
+            file.set_modified(SystemTime::now() - Duration::from_secs(age))
+                .unwrap();
+            path
         };
+        let executable = touch("app", 20);
+        let cargo_toml = touch("Cargo.toml", 30);
+        let package = dir.path().join("app.imagepackage");
+        let inputs = [executable.clone(), cargo_toml];
 
-        let sdk_hw_definitions = sdk_path.join("HardwareDefinitions");
-        if let Some(hw_defs_path) = &hw_defs_path {
-            if let Some(target_definition_file) = &target_definition_file {
-                args.push("--target-definition-filename");
-                args.push(target_definition_file.as_str());
-            }
+        assert!(is_stale(&package, &inputs));
+        touch("app.imagepackage", 10);
+        assert!(!is_stale(&package, &inputs));
+        touch("app", 0);
+        assert!(is_stale(&package, &inputs));
+        fs::remove_file(&executable).unwrap();
+        assert!(is_stale(&package, &inputs));
+    }
 
-            if package_config.target_definition.is_some() {
-                args.push("--hardware-definitions");
-                args.push(hw_defs_path.as_os_str().to_str().unwrap());
-                args.push(sdk_hw_definitions.as_os_str().to_str().unwrap());
-            }
-        }
-        if self.verbose {
-            args.push("--verbose");
-            println!("Args: {:?}\n", args);
-        }
-        println!(
-            "Generating {}",
-            app_package_name.as_os_str().to_str().unwrap()
+    #[test]
+    fn test_pack_application_args() {
+        let mut options = image::PackOptions {
+            target_api_set: "16".to_string(),
+            ..Default::default()
+        };
+        let (dir, package) = (Path::new("/t/out/app"), Path::new("/t/app.imagepackage"));
+        assert_eq!(
+            pack_application_args(dir, package, &options, false),
+            [
+                "image-package",
+                "pack-application",
+                "--package-directory",
+                "/t/out/app",
+                "--destination",
+                "/t/app.imagepackage",
+                "--target-api-set",
+                "16"
+            ]
+        );
+        options.target_definition = Some("sample_appliance.json".to_string());
+        options.hardware_definition_dirs = vec![PathBuf::from("/hw/rdb"), PathBuf::from("/sdk/hw")];
+        assert_eq!(
+            pack_application_args(dir, package, &options, true)[8..],
+            [
+                "--target-definition-filename",
+                "sample_appliance.json",
+                "--hardware-definitions",
+                "/hw/rdb",
+                "/sdk/hw",
+                "--verbose"
+            ]
         );
-        let output = Command::new(azsphere)
-            .args(args)
-            .output()
-            .expect("Failed to create app package");
-        if !output.status.success() {
-            println!("azsphere command failed:");
-            io::stdout().write_all(&output.stdout).unwrap();
-            io::stderr().write_all(&output.stderr).unwrap();
-            std::process::exit(output.status.code().unwrap());
-        }
-
-        Ok(())
     }
 }
@@ -0,0 +1,125 @@
+//! Partner components of an app, declared by `partners` in `[package.metadata.azsphere]`:
+//! prebuilt packages of real-time apps that the app talks to through `application::connect`.
+//! They are sideloaded and started along with the app, and before it, so that the app finds them
+//! running.  Only one high-level app runs at a time, so a partner must be real-time capable.
+
+use crate::config::{PackageConfig, Partner};
+use crate::error::Error;
+use crate::image::ImagePackage;
+use serde_json::Value;
+use std::path::PathBuf;
+
+/// A partner, found on disk
+#[derive(Debug)]
+pub(crate) struct PartnerApp {
+    /// its app package's path
+    pub name: String,
+    pub app_package: PathBuf,
+}
+
+impl PartnerApp {
+    pub fn component_id(&self) -> Result<String, Error> {
+        let app_manifest = self.app_manifest()?;
+        let component_id = app_manifest["ComponentId"].as_str().ok_or_else(|| {
+            Error::BadAppManifest(self.app_package.clone(), "missing ComponentId".into())
+        })?;
+        Ok(component_id.to_string())
+    }
+
+    /// The app manifest in its app package
+    pub fn app_manifest(&self) -> Result<Value, Error> {
+        Ok(ImagePackage::open(&self.app_package)?.app_manifest()?)
+    }
+}
+
+/// The partners of `package_config`
+pub(crate) fn resolve(package_config: &PackageConfig) -> Vec<PartnerApp> {
+    package_config
+        .partners
+        .iter()
+        .map(|partner| match partner {
+            Partner::ImagePackage(path) => PartnerApp {
+                name: path.display().to_string(),
+                app_package: path.clone(),
+            },
+        })
+        .collect()
+}
+
+/// Whether the partner of `app_manifest` is a real-time app, as a high-level one would replace
+/// the app on the device
+pub(crate) fn check_real_time(name: &str, app_manifest: &Value) -> Option<String> {
+    let application_type = app_manifest["ApplicationType"]
+        .as_str()
+        .unwrap_or("Default");
+    (application_type != "RealTimeCapable").then(|| {
+        format!(
+            "{name} has ApplicationType `{application_type}', not `RealTimeCapable'; only one high-level app runs at a time, so it would replace the app"
+        )
+    })
+}
+
+/// The ComponentIds `app_manifest` allows connections with, in lowercase
+fn allowed_connections(app_manifest: &Value) -> Vec<String> {
+    app_manifest["Capabilities"]["AllowedApplicationConnections"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|id| id.as_str())
+        .map(str::to_lowercase)
+        .collect()
+}
+
+/// Problems with the connection between two apps, each given by its name and app manifest: each
+/// must list the other's ComponentId in `AllowedApplicationConnections`
+pub(crate) fn check_connection(app: (&str, &Value), partner: (&str, &Value)) -> Vec<String> {
+    let mut problems = vec![];
+    for ((name, app_manifest), (other, other_manifest)) in [(app, partner), (partner, app)] {
+        let component_id = other_manifest["ComponentId"].as_str().unwrap_or_default();
+        if !allowed_connections(app_manifest).contains(&component_id.to_lowercase()) {
+            problems.push(format!(
+                "{name} does not list {other}'s ComponentId `{component_id}' in AllowedApplicationConnections"
+            ));
+        }
+    }
+    problems
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_check_connection() {
+        let app = json!({
+            "ComponentId": "0b4b4a2e-3d5c-4a06-8a25-1f0b5c3e0a11",
+            "Capabilities": {
+                "AllowedApplicationConnections": ["6A1E84C7-D05B-4F8B-A1B5-52A4B1B2F9C0"]
+            }
+        });
+        let rtapp = json!({
+            "ComponentId": "6a1e84c7-d05b-4f8b-a1b5-52a4b1b2f9c0",
+            "ApplicationType": "RealTimeCapable",
+            "Capabilities": {
+                "AllowedApplicationConnections": ["0b4b4a2e-3d5c-4a06-8a25-1f0b5c3e0a11"]
+            }
+        });
+        // ComponentIds compare regardless of case
+        assert!(check_connection(("app", &app), ("rtapp", &rtapp)).is_empty());
+        assert_eq!(check_real_time("rtapp", &rtapp), None);
+        assert_eq!(
+            check_real_time("app", &app).unwrap(),
+            "app has ApplicationType `Default', not `RealTimeCapable'; only one high-level app runs at a time, so it would replace the app"
+        );
+
+        let unconnected = json!({ "ComponentId": "1c2d0c2f-9d4e-4d2a-b1a0-3f8a2c1d4e5f" });
+        assert_eq!(
+            check_connection(("app", &app), ("other", &unconnected)),
+            [
+                "app does not list other's ComponentId `1c2d0c2f-9d4e-4d2a-b1a0-3f8a2c1d4e5f' in AllowedApplicationConnections",
+                "other does not list app's ComponentId `0b4b4a2e-3d5c-4a06-8a25-1f0b5c3e0a11' in AllowedApplicationConnections",
+            ]
+        );
+    }
+}
@@ -1,152 +1,203 @@
-use crate::config::{Config, ExtraMetadataSource};
+use crate::config::ExtraMetadataSource;
+use crate::device::{self, DeviceBackend};
+use crate::devices::{self, TargetArgs};
+use crate::dry_run::{self, DryRun, Step};
 use crate::error::Error;
-use crate::util;
-use serde_json::Value;
-use std::env;
+use crate::logs::{self, LogStream};
+use crate::package;
+use crate::partners;
+use crate::symbolize::Symbolizer;
+use crate::workspace::Workspace;
 use std::io::{self, Write};
 use std::path::PathBuf;
-use std::process::Command;
+use std::sync::Arc;
 
 #[derive(clap::Parser, Debug)]
 pub(crate) struct CliArgs {
     #[clap(flatten)]
-    common: super::package::CliArgs,
-    /// the device to run the command on when multiple devices are attached.
-    /// Values from: azsphere device list-attached. Specify the ID, IP address, or Local Connection ID of the device.
-    #[arg(short, long)]
-    device: Option<String>,
+    pub(crate) common: super::package::CliArgs,
+    #[clap(flatten)]
+    pub(crate) targets: TargetArgs,
     /// force the deployment of an image using a Beta API that may no longer be supported.
     #[arg(long)]
-    force: bool,
+    pub(crate) force: bool,
     /// do not automatically start the application after sideload.
     #[arg(short, long)]
-    manual_start: bool,
+    pub(crate) manual_start: bool,
+    /// stream the app's output after sideloading it, as `cargo azsphere logs` does; needs a
+    /// single package
+    #[arg(long, conflicts_with = "all_attached")]
+    pub(crate) follow: bool,
+    #[clap(flatten)]
+    pub(crate) dry_run: dry_run::CliArgs,
 }
 
 #[derive(Debug)]
 pub struct CliSetting {
+    package: Option<String>,
     verbose: bool,
     release_opt: bool,
-    device_opt: Option<String>,
+    targets: TargetArgs,
     force_opt: bool,
     manual_start_opt: bool,
+    follow: bool,
     extra_metadata: Vec<ExtraMetadataSource>,
+    dry_run: Option<DryRun>,
 }
 
 impl CliSetting {
     pub(crate) fn new(args: CliArgs) -> CliSetting {
         CliSetting {
+            package: args.common.package.clone(),
             verbose: args.common.verbose,
             release_opt: args.common.release,
-            device_opt: args.device,
+            targets: args.targets,
             force_opt: args.force,
             manual_start_opt: args.manual_start,
+            follow: args.follow,
             extra_metadata: args.common.extra_metadata(),
+            dry_run: args.dry_run.dry_run(),
         }
     }
 
-    // Determine if the host environment is WSL or not
-    pub fn do_sideload(self) -> Result<(), Error> {
-        if self.verbose {
-            println!("Invoking 'cargo metadata'");
-        }
-        let output = Command::new("cargo")
-            .arg("metadata")
-            .arg("--format-version=1")
-            .output()
-            .expect("Failed to run 'cargo metadata'");
-        if self.verbose {
-            println!("Parsing metadata");
-        }
-        let cargo_metadata: Value = serde_json::from_slice(output.stdout.as_slice()).unwrap();
-
-        if self.verbose {
-            println!("Finding package config");
-        }
-        let manifest_file_dir = env::current_dir()?;
-        let manifest_file_path = manifest_file_dir.join("Cargo.toml");
-        let config = Config::new(&manifest_file_path, self.extra_metadata.as_slice())?;
-        let package_config = config.package_config(
-            cargo_metadata["workspace_root"]
-                .to_string()
-                .replace("\"", ""),
-            self.verbose,
-        )?;
-
-        // cargo_metadata["target_directory"] + "armv7-unknown-linux-musleabihf" + "debug" + crate_name is the executable file
-        if self.verbose {
-            println!("Determining target_path");
+    /// Sideload the app package of each selected crate, after those of its partners
+    pub fn do_sideload(&self) -> Result<(), Error> {
+        let workspace = Workspace::load(self.verbose)?;
+        let target_path = workspace.target_path(self.release_opt);
+        let devices = self.targets.devices(self.verbose)?;
+        if self.follow && devices.len() > 1 {
+            return Err(Error::SeveralDevices("--follow"));
         }
-        let build_flavor = if self.release_opt { "release" } else { "debug" };
-        let target_directory = cargo_metadata["target_directory"]
-            .to_string()
-            .replace("\"", "");
-        let mut target_path = PathBuf::from(target_directory);
-        target_path.push("armv7-unknown-linux-musleabihf");
-        target_path.push(build_flavor);
-
-        // ${AzureSphereDefaultSDKDir}/Tools_v2/azsphere image-package pack-application --package-directory out/ --destination ${APPNAME}.imagepackage --target-api-set ${ARV}
-        let app_package_name = target_path
-            .clone()
-            .join(package_config.name + ".imagepackage");
-        let app_package_name = app_package_name.as_os_str().to_str().unwrap();
-        let (azsphere, azsphere_args) = util::azsphere_tool_path();
-        let mut args: Vec<String> = vec![];
-        args.extend(azsphere_args);
-        let translated_app_package_name = if util::is_wsl() {
-            if self.verbose {
-                println!("calling wslpath");
-            }
-            let output = Command::new("wslpath")
-                .args(["-w", app_package_name])
-                .output()
-                .expect("wslpath failed");
-            if !output.status.success() {
-                println!("wslpath command failed:");
-                io::stdout().write_all(&output.stdout).unwrap();
-                io::stderr().write_all(&output.stderr).unwrap();
-                std::process::exit(output.status.code().unwrap());
-            }
-            let translated = String::from_utf8(output.stdout.clone()).unwrap();
-            translated.replace("\n", "")
+        // The followed app's executable annotates the addresses in its output
+        let members = if self.follow {
+            vec![workspace.member(self.package.as_deref())?]
         } else {
-            app_package_name.to_string()
+            workspace.members(self.package.as_deref())?
         };
-
-        args.push("device".to_string());
-        args.push("sideload".to_string());
-        args.push("deploy".to_string());
-        args.push("-p".to_string());
-        args.push(translated_app_package_name);
-        if let Some(device) = self.device_opt {
-            args.push("-d".to_string());
-            args.push(device);
-        }
-        if self.force_opt {
-            args.push("--force".to_string());
-        }
-        if self.manual_start_opt {
-            args.push("-m".to_string())
+        let mut symbols = None;
+        let mut app_packages = vec![];
+        for member in members {
+            let package_config =
+                workspace.package_config(&member, &self.extra_metadata, self.verbose)?;
+            if let Some(dry_run) = &self.dry_run {
+                dry_run.record(Step::Config(package_config.to_json()));
+            }
+            symbols = Some(package::unstripped_executable(
+                &target_path,
+                &package_config.package_name(),
+            ));
+            // Partners first, so that the app finds them running when it starts
+            let partners = partners::resolve(&package_config);
+            for app_package in partners.into_iter().map(|partner| partner.app_package) {
+                if !app_packages.contains(&app_package) {
+                    app_packages.push(app_package);
+                }
+            }
+            app_packages.push(package::app_package(&target_path, &package_config));
         }
-        if self.verbose {
-            args.push("--verbose".to_string());
+        devices::for_each(&devices, self.dry_run.as_ref(), |device, out| {
+            let device = device::backend(self.dry_run.as_ref(), device, self.verbose)?;
+            self.sideload(device.as_ref(), &app_packages, out)
+        })?;
+        if self.follow {
+            let device = devices[0].as_deref().unwrap_or(logs::DEFAULT_DEVICE);
+            let mut logs = LogStream::new(device);
+            if let Some(dry_run) = &self.dry_run {
+                dry_run.record(Step::Connect(logs.address));
+                return Ok(());
+            }
+            logs.symbolizer = symbols
+                .and_then(|symbols| Symbolizer::load(&symbols).ok())
+                .map(Arc::new);
+            logs.follow(&mut io::stdout(), None)?;
         }
+        Ok(())
+    }
 
-        println!("Sideloading {}", app_package_name);
-        if self.verbose {
-            println!("Program: {:?} Args {:?}", azsphere, args);
-        }
-        let output = Command::new(azsphere)
-            .args(args)
-            .output()
-            .expect("Failed to sideload");
-        if !output.status.success() {
-            println!("azsphere command failed:");
-            io::stdout().write_all(&output.stdout).unwrap();
-            io::stderr().write_all(&output.stderr).unwrap();
-            std::process::exit(output.status.code().unwrap());
+    /// Sideload `app_packages` to `device`, in turn
+    fn sideload(
+        &self,
+        device: &dyn DeviceBackend,
+        app_packages: &[PathBuf],
+        out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        for app_package_name in app_packages {
+            if self.dry_run.is_none() {
+                writeln!(out, "Sideloading {}", app_package_name.display())?;
+            }
+            device.deploy(app_package_name, self.force_opt, self.manual_start_opt)?;
         }
-
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::fake::{azsphere, azsphere_under_wsl, calls};
+    use crate::tool::fake::{exited, FakeRunner};
+    use std::path::Path;
+
+    #[test]
+    fn test_sideload() {
+        let mut setting = CliSetting {
+            package: None,
+            verbose: false,
+            release_opt: false,
+            targets: TargetArgs::default(),
+            force_opt: false,
+            manual_start_opt: false,
+            follow: false,
+            extra_metadata: vec![],
+            dry_run: None,
+        };
+        let packages = [Path::new("/t/app.imagepackage").to_path_buf()];
+        let device = azsphere(None, false);
+        let mut out = vec![];
+        setting.sideload(&device, &packages, &mut out).unwrap();
+        setting.force_opt = true;
+        setting.manual_start_opt = true;
+        setting.sideload(&device, &packages, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            "Sideloading /t/app.imagepackage\nSideloading /t/app.imagepackage\n"
+        );
+        assert_eq!(
+            calls(&device),
+            [
+                vec!["device", "sideload", "deploy", "-p", "/t/app.imagepackage"],
+                vec![
+                    "device",
+                    "sideload",
+                    "deploy",
+                    "-p",
+                    "/t/app.imagepackage",
+                    "--force",
+                    "-m"
+                ],
+            ]
+        );
+
+        let device = azsphere_under_wsl(FakeRunner::default().reply(Ok(exited(
+            0,
+            "C:\\t\\app.imagepackage\n",
+            "",
+        ))));
+        setting.sideload(&device, &packages, &mut vec![]).unwrap();
+        assert_eq!(
+            calls(&device),
+            [
+                vec!["-w", "/t/app.imagepackage"],
+                vec![
+                    "device",
+                    "sideload",
+                    "deploy",
+                    "-p",
+                    "C:\\t\\app.imagepackage",
+                    "--force",
+                    "-m"
+                ],
+            ]
+        );
+    }
+}
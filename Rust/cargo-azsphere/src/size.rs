@@ -0,0 +1,258 @@
+//! How much flash an app package takes, and what in the executable takes it: the summary printed
+//! after packaging, and the `max_binary_kb` and `max_package_kb` budgets that fail packaging when
+//! they are exceeded.
+
+use crate::config::PackageConfig;
+use crate::error::ImageError;
+use object::{Object, ObjectSymbol, SymbolKind};
+use serde_json::Value;
+use std::collections::HashMap;
+
+/// How many of the largest crates and symbols to list
+const TOP: usize = 10;
+
+/// What symbols without a crate path, such as those of C code or `#[no_mangle]` functions, are
+/// counted as
+const UNMANGLED: &str = "(unmangled symbols)";
+
+#[derive(Debug, Default)]
+pub(crate) struct SizeReport {
+    /// the stripped executable
+    pub binary: u64,
+    pub extra_files: u64,
+    pub package: u64,
+    /// the executable's symbols, demangled, largest first
+    pub symbols: Vec<(String, u64)>,
+    /// what the symbols of each crate add up to, largest first
+    pub crates: Vec<(String, u64)>,
+}
+
+impl SizeReport {
+    /// Sizes of the packaged files, and of what is in `unstripped`, the executable with its
+    /// symbol table.  Crates are named as in `cargo_metadata` when it has them.
+    pub fn new(
+        unstripped: &[u8],
+        cargo_metadata: &Value,
+        binary: u64,
+        extra_files: u64,
+        package: u64,
+    ) -> Result<Self, ImageError> {
+        let symbols = symbol_sizes(unstripped)?;
+        let crates = crate_sizes(&symbols, &package_names(cargo_metadata));
+        Ok(Self {
+            binary,
+            extra_files,
+            package,
+            symbols,
+            crates,
+        })
+    }
+
+    pub fn print(&self, package_config: &PackageConfig) {
+        let budget = |budget: Option<u64>| match budget {
+            Some(budget) => format!(", of a {budget} KB budget"),
+            None => String::new(),
+        };
+        println!("Size:");
+        println!(
+            "    binary       {:>10} bytes{}",
+            self.binary,
+            budget(package_config.max_binary_kb)
+        );
+        println!("    extra files  {:>10} bytes", self.extra_files);
+        println!(
+            "    app package  {:>10} bytes{}",
+            self.package,
+            budget(package_config.max_package_kb)
+        );
+        for (title, sizes) in [
+            ("Largest crates:", &self.crates),
+            ("Largest symbols:", &self.symbols),
+        ] {
+            if sizes.is_empty() {
+                continue;
+            }
+            println!("{title}");
+            for (name, size) in sizes.iter().take(TOP) {
+                println!("    {size:>10}  {name}");
+            }
+        }
+    }
+
+    /// Fail when the binary or the app package is over its budget
+    pub fn check(&self, package_config: &PackageConfig) -> Result<(), ImageError> {
+        let budgets = [
+            (
+                "the binary",
+                self.binary,
+                package_config.max_binary_kb,
+                "max_binary_kb",
+            ),
+            (
+                "the app package",
+                self.package,
+                package_config.max_package_kb,
+                "max_package_kb",
+            ),
+        ];
+        for (what, size, budget, key) in budgets {
+            if let Some(budget) = budget {
+                if size > budget * 1024 {
+                    return Err(ImageError::OverBudget(what, size, budget, key));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The defined functions and data of the ELF file `elf`, demangled, largest first
+fn symbol_sizes(elf: &[u8]) -> Result<Vec<(String, u64)>, ImageError> {
+    let file = object::File::parse(elf).map_err(|e| ImageError::Malformed(e.to_string()))?;
+    let mut symbols = file
+        .symbols()
+        .filter(|symbol| symbol.is_definition() && symbol.size() > 0)
+        .filter(|symbol| matches!(symbol.kind(), SymbolKind::Text | SymbolKind::Data))
+        .filter_map(|symbol| {
+            let name = symbol.name().ok()?;
+            let name = addr2line::demangle(name, gimli::DW_LANG_Rust).unwrap_or(name.to_string());
+            Some((name, symbol.size()))
+        })
+        .collect::<Vec<_>>();
+    symbols.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    Ok(symbols)
+}
+
+/// The crate of the demangled symbol `name`: the first segment of its path, which for a trait
+/// method, such as `<alloc::string::String as core::fmt::Display>::fmt`, is that of the type
+fn symbol_crate(name: &str) -> Option<&str> {
+    let name = name.trim_start_matches(['<', '&', '*', '[', '(', ' ']);
+    let name = ["mut ", "const ", "dyn "]
+        .iter()
+        .fold(name, |name, prefix| {
+            name.strip_prefix(prefix).unwrap_or(name)
+        });
+    let (krate, _) = name.split_once("::")?;
+    let is_identifier =
+        !krate.is_empty() && krate.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+    is_identifier.then_some(krate)
+}
+
+/// Package names from `cargo metadata`, by the crate name they are used as in code
+fn package_names(cargo_metadata: &Value) -> HashMap<String, String> {
+    let packages = cargo_metadata["packages"].as_array().into_iter().flatten();
+    let mut names = HashMap::new();
+    for package in packages {
+        let dependencies = package["dependencies"].as_array().into_iter().flatten();
+        for name in std::iter::once(&package["name"])
+            .chain(dependencies.map(|dependency| &dependency["name"]))
+            .filter_map(Value::as_str)
+        {
+            names.insert(name.replace('-', "_"), name.to_string());
+        }
+    }
+    names
+}
+
+/// Symbol sizes summed by crate, largest first
+fn crate_sizes(
+    symbols: &[(String, u64)],
+    package_names: &HashMap<String, String>,
+) -> Vec<(String, u64)> {
+    let mut crates = HashMap::<String, u64>::new();
+    for (name, size) in symbols {
+        let krate = match symbol_crate(name) {
+            Some(krate) => package_names
+                .get(krate)
+                .cloned()
+                .unwrap_or_else(|| krate.to_string()),
+            None => UNMANGLED.to_string(),
+        };
+        *crates.entry(krate).or_default() += size;
+    }
+    let mut crates = crates.into_iter().collect::<Vec<_>>();
+    crates.sort_by(|a, b| b.1.cmp(&a.1).then_with(|| a.0.cmp(&b.0)));
+    crates
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+    use std::fs;
+    use std::path::Path;
+
+    #[test]
+    fn test_size_report() {
+        let elf =
+            fs::read(Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/elf/sym_app"))
+                .unwrap();
+        let symbols = symbol_sizes(&elf).unwrap();
+        assert_eq!(
+            symbols,
+            [
+                ("read_sensor".to_string(), 28),
+                ("_start".to_string(), 20),
+                ("fail".to_string(), 16)
+            ]
+        );
+
+        assert_eq!(symbol_crate("core::fmt::write"), Some("core"));
+        assert_eq!(
+            symbol_crate("<alloc::string::String as core::fmt::Display>::fmt"),
+            Some("alloc")
+        );
+        assert_eq!(
+            symbol_crate("<&mut azure_sphere::gpio::Pin>::set"),
+            Some("azure_sphere")
+        );
+        assert_eq!(symbol_crate("GPIO_OpenAsOutput"), None);
+        let metadata = json!({
+            "packages": [{
+                "name": "blink",
+                "dependencies": [{ "name": "azure-sphere" }, { "name": "hardware" }]
+            }]
+        });
+        let symbols = [
+            ("azure_sphere::gpio::open".to_string(), 300),
+            ("core::fmt::write".to_string(), 200),
+            ("blink::main".to_string(), 150),
+            (
+                "<azure_sphere::Error as core::fmt::Debug>::fmt".to_string(),
+                100,
+            ),
+            ("memcpy".to_string(), 50),
+        ];
+        assert_eq!(
+            crate_sizes(&symbols, &package_names(&metadata)),
+            [
+                ("azure-sphere".to_string(), 400),
+                ("core".to_string(), 200),
+                ("blink".to_string(), 150),
+                (UNMANGLED.to_string(), 50)
+            ]
+        );
+
+        let mut package_config = PackageConfig {
+            max_binary_kb: Some(64),
+            ..PackageConfig::for_test("blink")
+        };
+        let report = SizeReport::new(&elf, &metadata, 65 * 1024, 0, 70 * 1024).unwrap();
+        assert_eq!(
+            report.check(&package_config).unwrap_err().to_string(),
+            "the binary is 66560 bytes, over its budget of 64 KB (max_binary_kb)"
+        );
+        package_config.max_binary_kb = Some(65);
+        assert!(report.check(&package_config).is_ok());
+        package_config.max_package_kb = Some(64);
+        assert!(matches!(
+            report.check(&package_config),
+            Err(ImageError::OverBudget(
+                "the app package",
+                _,
+                64,
+                "max_package_kb"
+            ))
+        ));
+    }
+}
@@ -1,110 +1,123 @@
-use crate::config::{Config, ExtraMetadataSource};
+use crate::config::ExtraMetadataSource;
+use crate::device::{self, DeviceBackend};
+use crate::devices::{self, TargetArgs};
+use crate::dry_run::{self, DryRun, Step};
 use crate::error::Error;
-use crate::util;
-use serde_json::Value;
-use std::env;
-use std::fs;
-use std::io::{self, Write};
-use std::process::Command;
+use crate::manifest;
+use crate::workspace::Workspace;
+use std::io::Write;
 
 #[derive(clap::Parser, Debug)]
 pub(crate) struct CliArgs {
     #[clap(flatten)]
     common: super::package::CliArgs,
-    /// the device to run the command on when multiple devices are attached.
-    /// Values from: azsphere device list-attached. Specify the ID, IP address, or Local Connection ID of the device.
-    #[arg(short, long)]
-    device: Option<String>,
+    #[clap(flatten)]
+    targets: TargetArgs,
     /// force the deployment of an image using a Beta API that may no longer be supported.
     #[arg(long)]
     debug_mode: bool,
+    #[clap(flatten)]
+    dry_run: dry_run::CliArgs,
 }
 
 #[derive(Debug)]
 pub struct CliSetting {
+    package: Option<String>,
     verbose: bool,
     debug_mode: bool,
-    device_opt: Option<String>,
+    targets: TargetArgs,
     extra_metadata: Vec<ExtraMetadataSource>,
+    dry_run: Option<DryRun>,
 }
 
 impl CliSetting {
     pub(crate) fn new(args: CliArgs) -> Self {
         Self {
+            package: args.common.package.clone(),
             verbose: args.common.verbose,
             debug_mode: args.debug_mode,
-            device_opt: args.device,
+            targets: args.targets,
             extra_metadata: args.common.extra_metadata(),
+            dry_run: args.dry_run.dry_run(),
         }
     }
 
-    pub fn do_start(self) -> Result<(), Error> {
-        if self.verbose {
-            println!("Invoking 'cargo metadata'");
+    /// Start the app of each selected crate
+    pub fn do_start(&self) -> Result<(), Error> {
+        let workspace = Workspace::load(self.verbose)?;
+        let devices = self.targets.devices(self.verbose)?;
+        let mut component_ids = vec![];
+        for member in workspace.members(self.package.as_deref())? {
+            let package_config =
+                workspace.package_config(&member, &self.extra_metadata, self.verbose)?;
+            if let Some(dry_run) = &self.dry_run {
+                dry_run.record(Step::Config(package_config.to_json()));
+            }
+            component_ids.push(manifest::component_id(&package_config)?);
         }
-        let output = Command::new("cargo")
-            .arg("metadata")
-            .arg("--format-version=1")
-            .output()
-            .expect("Failed to run 'cargo metadata'");
-        if self.verbose {
-            println!("Parsing metadata");
-        }
-        let cargo_metadata: Value = serde_json::from_slice(output.stdout.as_slice()).unwrap();
+        devices::for_each(&devices, self.dry_run.as_ref(), |device, out| {
+            let device = device::backend(self.dry_run.as_ref(), device, self.verbose)?;
+            self.start(device.as_ref(), &component_ids, out)
+        })
+    }
 
-        if self.verbose {
-            println!("Finding package config");
+    /// Start the apps `component_ids` on `device`, in turn
+    fn start(
+        &self,
+        device: &dyn DeviceBackend,
+        component_ids: &[String],
+        out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        for component_id in component_ids {
+            if self.dry_run.is_none() {
+                writeln!(out, "Starting app {component_id}")?;
+            }
+            device.start(component_id, self.debug_mode)?;
         }
-        let manifest_file_dir = env::current_dir()?;
-        let manifest_file_path = manifest_file_dir.join("Cargo.toml");
-        let config = Config::new(&manifest_file_path, self.extra_metadata.as_slice())?;
-        let package_config = config.package_config(
-            cargo_metadata["workspace_root"]
-                .to_string()
-                .replace("\"", ""),
-            self.verbose,
-        )?;
-
-        let (azsphere, azsphere_args) = util::azsphere_tool_path();
-        let mut args: Vec<String> = vec![];
-        args.extend(azsphere_args);
-
-        let data = fs::read_to_string(package_config.app_manifest)
-            .expect("Unable to read app manifest file");
-        let app_manifest: serde_json::Value =
-            serde_json::from_str(&data).expect("Unable to parse app manifest file");
-        let component_id = app_manifest["ComponentId"].to_string();
+        Ok(())
+    }
+}
 
-        args.push("device".to_string());
-        args.push("app".to_string());
-        args.push("start".to_string());
-        args.push("-i".to_string());
-        args.push(component_id.replace("\"", ""));
-        if let Some(device) = self.device_opt {
-            args.push("-d".to_string());
-            args.push(device);
-        }
-        if self.debug_mode {
-            args.push("--debug-mode".to_string());
-        }
-        if self.verbose {
-            args.push("-verbose".to_string());
-        }
-        println!("Starting app");
-        if self.verbose {
-            println!("Program: {:?} Args {:?}", azsphere, args);
-        }
-        let output = Command::new(azsphere)
-            .args(args)
-            .output()
-            .expect("Failed to start app");
-        if !output.status.success() {
-            println!("azsphere command failed:");
-            io::stdout().write_all(&output.stdout).unwrap();
-            io::stderr().write_all(&output.stderr).unwrap();
-            std::process::exit(output.status.code().unwrap());
-        }
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config::PackageConfig;
+    use crate::device::fake::{azsphere, calls};
 
-        Ok(())
+    #[test]
+    fn test_start() {
+        let setting = CliSetting {
+            package: None,
+            verbose: true,
+            debug_mode: true,
+            targets: TargetArgs::default(),
+            extra_metadata: vec![],
+            dry_run: None,
+        };
+        let package_config = PackageConfig::for_test("test_app");
+        let component_id = manifest::component_id(&package_config).unwrap();
+        let device = azsphere(Some("192.168.35.2"), setting.verbose);
+        let mut out = vec![];
+        setting
+            .start(&device, std::slice::from_ref(&component_id), &mut out)
+            .unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            format!("Starting app {component_id}\n")
+        );
+        assert_eq!(
+            calls(&device),
+            [vec![
+                "device",
+                "app",
+                "start",
+                "-i",
+                &component_id,
+                "--debug-mode",
+                "-d",
+                "192.168.35.2",
+                "--verbose"
+            ]]
+        );
     }
 }
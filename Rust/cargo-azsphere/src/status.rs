@@ -0,0 +1,164 @@
+//! `cargo azsphere status` and `cargo azsphere memory`: the state and memory use of the app of
+//! each selected crate, on the device, as text or JSON.
+
+use crate::config::{ExtraMetadataSource, PackageConfig};
+use crate::device::{AzsphereCli, DeviceBackend};
+use crate::error::Error;
+use crate::manifest;
+use crate::workspace::Workspace;
+use serde_json::{json, Value};
+use std::io::{self, Write};
+
+#[derive(clap::Parser, Debug)]
+pub(crate) struct CliArgs {
+    #[clap(flatten)]
+    common: super::package::CliArgs,
+    /// the device to run the command on when multiple devices are attached.
+    /// Values from: azsphere device list-attached. Specify the ID, IP address, or Local Connection ID of the device.
+    #[arg(short, long)]
+    device: Option<String>,
+    /// print one JSON object per app
+    #[arg(long)]
+    json: bool,
+}
+
+/// What to ask of each app
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Query {
+    Status,
+    Memory,
+}
+
+#[derive(Debug)]
+pub struct CliSetting {
+    package: Option<String>,
+    verbose: bool,
+    device_opt: Option<String>,
+    json: bool,
+    extra_metadata: Vec<ExtraMetadataSource>,
+}
+
+impl CliSetting {
+    pub(crate) fn new(args: CliArgs) -> Self {
+        Self {
+            package: args.common.package.clone(),
+            verbose: args.common.verbose,
+            device_opt: args.device,
+            json: args.json,
+            extra_metadata: args.common.extra_metadata(),
+        }
+    }
+
+    /// Print the answer to `query` of the app of each selected crate
+    pub fn do_query(&self, query: Query) -> Result<(), Error> {
+        let workspace = Workspace::load(self.verbose)?;
+        let device = AzsphereCli::from_sdk(self.device_opt.clone(), self.verbose)?;
+        for member in workspace.members(self.package.as_deref())? {
+            let package_config =
+                workspace.package_config(&member, &self.extra_metadata, self.verbose)?;
+            self.query(&device, &package_config, query, &mut io::stdout())?;
+        }
+        Ok(())
+    }
+
+    fn query(
+        &self,
+        device: &dyn DeviceBackend,
+        package_config: &PackageConfig,
+        query: Query,
+        out: &mut dyn Write,
+    ) -> Result<(), Error> {
+        let component_id = manifest::component_id(package_config)?;
+        let (text, value) = match query {
+            Query::Status => {
+                let status = device.show_status(&component_id)?;
+                (status.to_string(), status.to_json())
+            }
+            Query::Memory => {
+                let stats = device.show_memory_stats(&component_id)?;
+                (stats.to_string(), stats.to_json())
+            }
+        };
+        let name = package_config.package_name();
+        if self.json {
+            let mut object = json!({ "package": name, "componentId": component_id });
+            if let (Value::Object(object), Value::Object(value)) = (&mut object, value) {
+                object.extend(value);
+            }
+            writeln!(out, "{object}")?;
+        } else {
+            writeln!(out, "{name} ({component_id}): {text}")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::device::fake::{azsphere, calls};
+    use crate::tool::fake::{exited, FakeRunner};
+
+    #[test]
+    fn test_query() {
+        let mut setting = CliSetting {
+            package: None,
+            verbose: false,
+            device_opt: None,
+            json: false,
+            extra_metadata: vec![],
+        };
+        let package_config = PackageConfig::for_test("test_app");
+        let component_id = manifest::component_id(&package_config).unwrap();
+        let status = format!(r#"{{ "componentId": "{component_id}", "state": "running" }}"#);
+        let memory = r#"{
+            "currentMemoryUsageInKB": 356,
+            "userModeMemoryUsageInKB": 50,
+            "peakUserModeMemoryUsageInKB": 58
+        }"#;
+        let mut device = azsphere(None, false);
+        device.runner = FakeRunner::default()
+            .reply(Ok(exited(0, &status, "")))
+            .reply(Ok(exited(0, memory, "")))
+            .reply(Ok(exited(0, memory, "")));
+        let mut out = vec![];
+        for query in [Query::Status, Query::Memory] {
+            setting
+                .query(&device, &package_config, query, &mut out)
+                .unwrap();
+        }
+        setting.json = true;
+        setting
+            .query(&device, &package_config, Query::Memory, &mut out)
+            .unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines = out.lines().collect::<Vec<_>>();
+        assert_eq!(lines[0], format!("test_app ({component_id}): running"));
+        assert_eq!(
+            lines[1],
+            format!("test_app ({component_id}): 356 KB, of which user mode 50 KB (peak 58 KB)")
+        );
+        assert_eq!(
+            serde_json::from_str::<Value>(lines[2]).unwrap(),
+            json!({
+                "package": "test_app",
+                "componentId": component_id,
+                "currentMemoryUsageInKB": 356,
+                "userModeMemoryUsageInKB": 50,
+                "peakUserModeMemoryUsageInKB": 58
+            })
+        );
+        assert_eq!(
+            calls(&device)[1],
+            [
+                "device",
+                "app",
+                "show-memory-stats",
+                "-i",
+                &component_id,
+                "--output",
+                "json"
+            ]
+        );
+    }
+}
@@ -0,0 +1,66 @@
+use crate::config::{ExtraMetadataSource, PackageConfig};
+use crate::device::{self, DeviceBackend};
+use crate::dry_run::{self, DryRun, Step};
+use crate::error::Error;
+use crate::manifest;
+use crate::workspace::Workspace;
+
+#[derive(clap::Parser, Debug)]
+pub(crate) struct CliArgs {
+    #[clap(flatten)]
+    common: super::package::CliArgs,
+    /// the device to run the command on when multiple devices are attached.
+    /// Values from: azsphere device list-attached. Specify the ID, IP address, or Local Connection ID of the device.
+    #[arg(short, long)]
+    device: Option<String>,
+    #[clap(flatten)]
+    dry_run: dry_run::CliArgs,
+}
+
+#[derive(Debug)]
+pub struct CliSetting {
+    package: Option<String>,
+    verbose: bool,
+    device_opt: Option<String>,
+    extra_metadata: Vec<ExtraMetadataSource>,
+    dry_run: Option<DryRun>,
+}
+
+impl CliSetting {
+    pub(crate) fn new(args: CliArgs) -> Self {
+        Self {
+            package: args.common.package.clone(),
+            verbose: args.common.verbose,
+            device_opt: args.device,
+            extra_metadata: args.common.extra_metadata(),
+            dry_run: args.dry_run.dry_run(),
+        }
+    }
+
+    /// Stop the app of each selected crate
+    pub fn do_stop(&self) -> Result<(), Error> {
+        let workspace = Workspace::load(self.verbose)?;
+        let device = device::backend(self.dry_run.as_ref(), self.device_opt.clone(), self.verbose)?;
+        for member in workspace.members(self.package.as_deref())? {
+            let package_config =
+                workspace.package_config(&member, &self.extra_metadata, self.verbose)?;
+            if let Some(dry_run) = &self.dry_run {
+                dry_run.record(Step::Config(package_config.to_json()));
+            }
+            self.stop(device.as_ref(), &package_config)?;
+        }
+        Ok(())
+    }
+
+    fn stop(
+        &self,
+        device: &dyn DeviceBackend,
+        package_config: &PackageConfig,
+    ) -> Result<(), Error> {
+        let component_id = manifest::component_id(package_config)?;
+        if self.dry_run.is_none() {
+            println!("Stopping {}", package_config.package_name());
+        }
+        device.stop(&component_id)
+    }
+}
@@ -0,0 +1,232 @@
+//! `cargo azsphere symbolize`: annotate the code addresses in device output, such as those of a
+//! crash or backtrace, with function, file and line, from the DWARF of the unstripped executable
+//! that packaging keeps.
+
+use crate::config::ExtraMetadataSource;
+use crate::error::Error;
+use crate::package;
+use crate::workspace::Workspace;
+use gimli::{EndianArcSlice, RunTimeEndian};
+use object::{Object, ObjectSection};
+use regex::{Captures, Regex};
+use std::fmt::{Debug, Formatter};
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, OnceLock};
+
+#[derive(clap::Parser, Debug)]
+pub(crate) struct CliArgs {
+    #[clap(flatten)]
+    common: package::CliArgs,
+    /// device output to annotate [DEFAULT = standard input]
+    file: Option<PathBuf>,
+    /// unstripped executable to read symbols from [DEFAULT = the package's]
+    #[arg(long)]
+    symbols: Option<PathBuf>,
+}
+
+#[derive(Debug)]
+pub struct CliSetting {
+    package: Option<String>,
+    verbose: bool,
+    release: bool,
+    file: Option<PathBuf>,
+    symbols: Option<PathBuf>,
+    extra_metadata: Vec<ExtraMetadataSource>,
+}
+
+impl CliSetting {
+    pub(crate) fn new(args: CliArgs) -> Self {
+        Self {
+            package: args.common.package.clone(),
+            verbose: args.common.verbose,
+            release: args.common.release,
+            file: args.file,
+            symbols: args.symbols,
+            extra_metadata: args.common.extra_metadata(),
+        }
+    }
+
+    pub fn do_symbolize(&self) -> Result<(), Error> {
+        let symbols = match &self.symbols {
+            Some(symbols) => symbols.clone(),
+            None => package_symbols(
+                self.package.as_deref(),
+                self.release,
+                &self.extra_metadata,
+                self.verbose,
+            )?,
+        };
+        let symbolizer = Symbolizer::load(&symbols)?;
+        let input: Box<dyn BufRead> = match &self.file {
+            Some(file) => Box::new(BufReader::new(
+                fs::File::open(file).map_err(|e| Error::FileIo(file.clone(), e))?,
+            )),
+            None => Box::new(io::stdin().lock()),
+        };
+        let mut out = io::stdout().lock();
+        for line in input.lines() {
+            writeln!(out, "{}", symbolizer.annotate(&line?))?;
+        }
+        Ok(())
+    }
+}
+
+/// The unstripped executable of the selected package
+pub(crate) fn package_symbols(
+    package: Option<&str>,
+    release: bool,
+    extra_metadata: &[ExtraMetadataSource],
+    verbose: bool,
+) -> Result<PathBuf, Error> {
+    let workspace = Workspace::load(verbose)?;
+    let member = workspace.member(package)?;
+    let package_config = workspace.package_config(&member, extra_metadata, verbose)?;
+    let target_path = workspace.target_path(release);
+    Ok(package::unstripped_executable(
+        &target_path,
+        &package_config.package_name(),
+    ))
+}
+
+type Reader = EndianArcSlice<RunTimeEndian>;
+
+/// Looks up code addresses in the DWARF of an executable
+pub(crate) struct Symbolizer {
+    path: PathBuf,
+    /// Behind a mutex, as it caches what it parses, so that output can be annotated on another
+    /// thread
+    context: Mutex<addr2line::Context<Reader>>,
+}
+
+impl Debug for Symbolizer {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Symbolizer")
+            .field("path", &self.path)
+            .finish()
+    }
+}
+
+/// Addresses as devices print them: `0x0001f2a4`, or `pc=0001f2a4`
+fn address_regex() -> &'static Regex {
+    static REGEX: OnceLock<Regex> = OnceLock::new();
+    REGEX.get_or_init(|| Regex::new(r"\b(?:pc\s*[=:]\s*(?:0x)?|0x)([0-9a-fA-F]{4,16})\b").unwrap())
+}
+
+impl Symbolizer {
+    pub fn load(path: &Path) -> Result<Self, Error> {
+        let data = fs::read(path).map_err(|e| Error::FileIo(path.to_path_buf(), e))?;
+        let symbols_error =
+            |e: &dyn std::fmt::Display| Error::Symbols(path.to_path_buf(), e.to_string());
+        let file = object::File::parse(data.as_slice()).map_err(|e| symbols_error(&e))?;
+        if file.section_by_name(".debug_info").is_none() {
+            return Err(symbols_error(&"no debug information; was it stripped?"));
+        }
+        let endian = if file.is_little_endian() {
+            RunTimeEndian::Little
+        } else {
+            RunTimeEndian::Big
+        };
+        let dwarf = gimli::Dwarf::load(|id| -> Result<Reader, gimli::Error> {
+            let section = file
+                .section_by_name(id.name())
+                .and_then(|section| section.uncompressed_data().ok())
+                .unwrap_or_default();
+            Ok(EndianArcSlice::new(Arc::from(&*section), endian))
+        })
+        .map_err(|e| symbols_error(&e))?;
+        let context = addr2line::Context::from_dwarf(dwarf).map_err(|e| symbols_error(&e))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            context: Mutex::new(context),
+        })
+    }
+
+    /// `function at file:line` for `address`, innermost inlined function first
+    pub fn describe(&self, address: u64) -> Option<String> {
+        // Thumb code addresses have bit 0 set
+        let address = address & !1;
+        let context = self.context.lock().unwrap_or_else(|e| e.into_inner());
+        let mut frames = context.find_frames(address).skip_all_loads().ok()?;
+        let mut described = vec![];
+        while let Ok(Some(frame)) = frames.next() {
+            let function = frame
+                .function
+                .as_ref()
+                .and_then(|function| function.demangle().ok())
+                .map(|function| function.to_string())
+                .unwrap_or_else(|| "??".to_string());
+            let location = match &frame.location {
+                Some(addr2line::Location {
+                    file: Some(file),
+                    line: Some(line),
+                    ..
+                }) => format!(" at {file}:{line}"),
+                Some(addr2line::Location {
+                    file: Some(file), ..
+                }) => format!(" at {file}"),
+                _ => String::new(),
+            };
+            described.push(format!("{function}{location}"));
+        }
+        if described.is_empty() {
+            return None;
+        }
+        Some(described.join(", inlined into "))
+    }
+
+    /// `line` with each code address in it followed by where it is
+    pub fn annotate(&self, line: &str) -> String {
+        address_regex()
+            .replace_all(line, |captures: &Captures| {
+                let text = &captures[0];
+                match u64::from_str_radix(&captures[1], 16)
+                    .ok()
+                    .and_then(|address| self.describe(address))
+                {
+                    Some(described) => format!("{text} ({described})"),
+                    None => text.to_string(),
+                }
+            })
+            .to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// An ARM executable with DWARF, whose `_start` calls `read_sensor`, which calls `fail`
+    fn sym_app() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/elf/sym_app")
+    }
+
+    #[test]
+    fn test_symbolize() {
+        let symbolizer = Symbolizer::load(&sym_app()).unwrap();
+        let read_sensor = symbolizer.describe(0x20120).unwrap();
+        assert!(read_sensor.starts_with("read_sensor at "), "{read_sensor}");
+        assert!(read_sensor.ends_with("app.rs:16"), "{read_sensor}");
+        assert!(symbolizer.describe(0x10).is_none());
+
+        let annotated = symbolizer.annotate("Abort: pc=0002010C lr=0x00020121, data 0x00000010");
+        let fail = symbolizer.describe(0x2010c).unwrap();
+        assert!(fail.starts_with("fail at "), "{fail}");
+        assert_eq!(
+            annotated,
+            format!("Abort: pc=0002010C ({fail}) lr=0x00020121 ({read_sensor}), data 0x00000010")
+        );
+        assert_eq!(symbolizer.annotate("no addresses"), "no addresses");
+
+        // A stripped executable has nothing to look addresses up in
+        let stripped = crate::elf::strip(&fs::read(sym_app()).unwrap()).unwrap();
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("sym_app");
+        fs::write(&path, stripped).unwrap();
+        assert!(matches!(
+            Symbolizer::load(&path),
+            Err(Error::Symbols(stripped, _)) if stripped == path
+        ));
+    }
+}
@@ -0,0 +1,549 @@
+//! `cargo azsphere test`: run the crate's `#[test]`s on the device.  Each test executable is
+//! packaged as an app of its own, with the capabilities of `[package.metadata.azsphere.test]`
+//! when there is one, then sideloaded and started.  libtest's output is read from the device's
+//! output stream, and the test app is removed once it ends.
+
+use crate::build;
+use crate::device::{AzsphereCli, DeviceBackend};
+use crate::error::Error;
+use crate::logs::{self, LogStream};
+use crate::manifest;
+use crate::package::{self, PackageContext};
+use crate::symbolize::Symbolizer;
+use anyhow::Context;
+use serde_json::Value;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// How often the test app's output is tried, while it starts
+const CONNECT_RETRY_DELAY: Duration = Duration::from_millis(50);
+
+#[derive(clap::Parser, Debug)]
+pub(crate) struct CliArgs {
+    #[clap(flatten)]
+    common: package::CliArgs,
+    /// the device to run the tests on when multiple devices are attached.
+    /// Values from: azsphere device list-attached. Specify the ID, IP address, or Local Connection ID of the device.
+    #[arg(short, long)]
+    device: Option<String>,
+    /// also write the results as JUnit XML to this file
+    #[arg(long, value_name = "PATH")]
+    junit: Option<PathBuf>,
+    /// how long the tests may print nothing before they are taken to hang, in seconds
+    #[arg(long, default_value_t = 300)]
+    timeout: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Outcome {
+    Passed,
+    Failed,
+    Ignored,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct TestCase {
+    name: String,
+    outcome: Outcome,
+    /// what a failed test printed
+    output: String,
+}
+
+/// The results of one test executable, from libtest's output
+#[derive(Debug, Default)]
+struct TestReport {
+    /// the test app's name
+    name: String,
+    cases: Vec<TestCase>,
+    /// whether libtest's `running N tests` line was seen
+    started: bool,
+    /// whether libtest's `test result:` line was seen
+    finished: bool,
+    /// why the test executable could not be built or run to the end
+    error: Option<String>,
+    /// the case whose output is being read, in the failures section
+    capturing: Option<usize>,
+}
+
+impl TestReport {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn parse_line(&mut self, line: &str) {
+        if let Some(name) = line
+            .strip_prefix("---- ")
+            .and_then(|rest| rest.strip_suffix(" stdout ----"))
+        {
+            self.capturing = self.cases.iter().position(|case| case.name == name);
+            return;
+        }
+        if line.starts_with("running ") && (line.ends_with(" test") || line.ends_with(" tests")) {
+            self.started = true;
+            return;
+        }
+        if line.starts_with("test result: ") {
+            self.finished = true;
+            self.capturing = None;
+            return;
+        }
+        if line == "failures:" {
+            self.capturing = None;
+            return;
+        }
+        if let Some(index) = self.capturing {
+            let output = &mut self.cases[index].output;
+            output.push_str(line);
+            output.push('\n');
+            return;
+        }
+        let Some((name, result)) = line
+            .strip_prefix("test ")
+            .and_then(|rest| rest.split_once(" ... "))
+        else {
+            return;
+        };
+        let outcome = if result == "ok" {
+            Outcome::Passed
+        } else if result.starts_with("FAILED") {
+            Outcome::Failed
+        } else if result.starts_with("ignored") {
+            Outcome::Ignored
+        } else {
+            return;
+        };
+        self.cases.push(TestCase {
+            name: name.to_string(),
+            outcome,
+            output: String::new(),
+        });
+    }
+
+    /// Whether all of libtest's output was read, from its first line to its result, without error
+    fn is_complete(&self) -> bool {
+        self.started && self.finished && self.error.is_none()
+    }
+
+    fn count(&self, outcome: Outcome) -> usize {
+        self.cases
+            .iter()
+            .filter(|case| case.outcome == outcome)
+            .count()
+    }
+}
+
+/// Echoes the test app's output, and parses each line of it into `report`
+struct ReportWriter<'a> {
+    report: &'a mut TestReport,
+    line: Vec<u8>,
+    echo: &'a mut dyn Write,
+}
+
+impl Write for ReportWriter<'_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.echo.write_all(buf)?;
+        for &byte in buf {
+            if byte == b'\n' {
+                let line = String::from_utf8_lossy(&self.line).to_string();
+                self.report.parse_line(line.trim_end_matches('\r'));
+                self.line.clear();
+            } else {
+                self.line.push(byte);
+            }
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.echo.flush()
+    }
+}
+
+fn escape_xml(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+/// `reports` as JUnit XML, with a test suite for each test app
+fn junit(reports: &[TestReport]) -> String {
+    let mut xml = String::from("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n<testsuites>\n");
+    for report in reports {
+        xml.push_str(&format!(
+            "  <testsuite name=\"{}\" tests=\"{}\" failures=\"{}\" skipped=\"{}\">\n",
+            escape_xml(&report.name),
+            report.cases.len(),
+            report.count(Outcome::Failed),
+            report.count(Outcome::Ignored)
+        ));
+        for case in &report.cases {
+            // `module::tests::name` is reported as the test `name` of the class `module::tests`
+            let (classname, name) = case
+                .name
+                .rsplit_once("::")
+                .unwrap_or((&report.name, &case.name));
+            let testcase = format!(
+                "    <testcase classname=\"{}\" name=\"{}\"",
+                escape_xml(classname),
+                escape_xml(name)
+            );
+            match case.outcome {
+                Outcome::Passed => xml.push_str(&format!("{testcase}/>\n")),
+                Outcome::Ignored => {
+                    xml.push_str(&format!("{testcase}>\n      <skipped/>\n"));
+                    xml.push_str("    </testcase>\n");
+                }
+                Outcome::Failed => {
+                    xml.push_str(&format!("{testcase}>\n      <failure message=\"failed\">"));
+                    xml.push_str(&escape_xml(&case.output));
+                    xml.push_str("</failure>\n    </testcase>\n");
+                }
+            }
+        }
+        if let Some(error) = &report.error {
+            xml.push_str(&format!(
+                "    <system-err>{}</system-err>\n",
+                escape_xml(error)
+            ));
+        } else if !report.is_complete() {
+            xml.push_str("    <system-err>the output lacks the start or the result of the run");
+            xml.push_str("</system-err>\n");
+        }
+        xml.push_str("  </testsuite>\n");
+    }
+    xml.push_str("</testsuites>\n");
+    xml
+}
+
+/// The test executables in `cargo test --message-format=json` messages, each with the name of
+/// its test app: the target's, and its kind unless that is `test`
+fn test_executables(messages: &str) -> Vec<(String, PathBuf)> {
+    messages
+        .lines()
+        .filter_map(|line| serde_json::from_str::<Value>(line).ok())
+        .filter(|message| {
+            message["reason"] == "compiler-artifact" && message["profile"]["test"] == true
+        })
+        .filter_map(|message| {
+            let executable = message["executable"].as_str()?;
+            let name = message["target"]["name"].as_str()?;
+            let name = match message["target"]["kind"][0].as_str()? {
+                "test" => format!("{name}-test"),
+                kind => format!("{name}-{kind}-test"),
+            };
+            Some((name, PathBuf::from(executable)))
+        })
+        .collect()
+}
+
+/// `context` with the test executable `executable` as the app `name` instead.  Without
+/// `capabilities` to generate it from, the app manifest is the app's, renamed, written next to
+/// the app package.
+fn test_context(
+    context: &PackageContext,
+    name: &str,
+    executable: PathBuf,
+) -> Result<PackageContext, Error> {
+    let mut package_config = context.package_config.clone();
+    package_config.name = name.to_string();
+    package_config.component_id = None;
+    // The test app is not the app its partners allow connections with
+    package_config.partners = vec![];
+    if package_config.capabilities.is_none() {
+        let path = &package_config.app_manifest;
+        let text = fs::read_to_string(path).map_err(|e| Error::FileIo(path.clone(), e))?;
+        let mut app_manifest: Value = serde_json::from_str(&text)
+            .map_err(|e| Error::BadAppManifest(path.clone(), e.to_string()))?;
+        let Value::Object(fields) = &mut app_manifest else {
+            return Err(Error::BadAppManifest(
+                path.clone(),
+                "expected an object".to_string(),
+            ));
+        };
+        let component_id =
+            manifest::default_component_id(&package_config.manifest_path, name).to_string();
+        fields.insert("Name".to_string(), name.into());
+        fields.insert("ComponentId".to_string(), component_id.into());
+        fields.insert("EntryPoint".to_string(), format!("/bin/{name}").into());
+        let path = context
+            .target_path
+            .join(format!("{name}.app_manifest.json"));
+        let text = serde_json::to_string_pretty(&app_manifest)
+            .map_err(|e| Error::Json(path.clone(), e))?;
+        fs::write(&path, text).map_err(|e| Error::FileIo(path.clone(), e))?;
+        package_config.app_manifest = path;
+    }
+    Ok(PackageContext {
+        cargo_metadata: context.cargo_metadata.clone(),
+        crate_name: context.crate_name.clone(),
+        manifest_file_dir: context.manifest_file_dir.clone(),
+        package_config,
+        executable,
+        target_path: context.target_path.clone(),
+    })
+}
+
+#[derive(Debug)]
+pub struct CliSetting {
+    common: package::CliArgs,
+    device: Option<String>,
+    junit: Option<PathBuf>,
+    timeout: Duration,
+}
+
+impl CliSetting {
+    pub(crate) fn new(args: CliArgs) -> Self {
+        Self {
+            common: args.common,
+            device: args.device,
+            junit: args.junit,
+            timeout: Duration::from_secs(args.timeout),
+        }
+    }
+
+    /// Build the test executables of the crate of `context`
+    fn build_tests(&self, context: &PackageContext) -> anyhow::Result<Vec<(String, PathBuf)>> {
+        let mut command = build::cargo_command("test", self.common.release);
+        command
+            .arg("--no-run")
+            .arg("--message-format=json-render-diagnostics");
+        build::select_crate(&mut command, context);
+        if self.common.verbose {
+            println!("Running {:?}", command);
+        }
+        // Compiler output goes to stderr, as it is produced; the messages to stdout
+        let output = command
+            .stderr(Stdio::inherit())
+            .output()
+            .context("failed to run 'cargo test'")?;
+        if !output.status.success() {
+            anyhow::bail!("'cargo test' failed with {}", output.status);
+        }
+        Ok(test_executables(&String::from_utf8_lossy(&output.stdout)))
+    }
+
+    /// Start the test app `component_id`, and read its output into `report` until it ends
+    fn run(
+        &self,
+        device: &dyn DeviceBackend,
+        component_id: &str,
+        context: &PackageContext,
+        report: &mut TestReport,
+    ) -> Result<(), Error> {
+        let mut logs = LogStream::new(self.device.as_deref().unwrap_or(logs::DEFAULT_DEVICE));
+        logs.timestamps = false;
+        logs.retry_delay = CONNECT_RETRY_DELAY;
+        logs.symbolizer = Symbolizer::load(&context.unstripped_executable())
+            .ok()
+            .map(Arc::new);
+        // Connecting from before the app starts, so that it is connected to as soon as it serves
+        // its output, and as little of it as possible is missed
+        let connecting = {
+            let (logs, timeout) = (logs.clone(), self.timeout);
+            thread::spawn(move || logs.connect_within(timeout))
+        };
+        device.start(component_id, false)?;
+        let stream = connecting.join().expect("connecting panicked")?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        let mut writer = ReportWriter {
+            report,
+            line: vec![],
+            echo: &mut io::stdout(),
+        };
+        logs.copy(stream, &mut writer)
+    }
+
+    /// Package `executable` as a test app, and run it on the device, into `report`
+    fn test_executable(
+        &self,
+        package: &package::CliSetting,
+        device: &dyn DeviceBackend,
+        context: &PackageContext,
+        executable: PathBuf,
+        report: &mut TestReport,
+    ) -> anyhow::Result<()> {
+        let context = test_context(context, &report.name, executable)?;
+        package.package(&context)?;
+        let component_id = manifest::component_id(&context.package_config)?;
+        println!("Running {} on the device", report.name);
+        device.deploy(&context.app_package(), false, true)?;
+        let result = self.run(device, &component_id, &context, report);
+        // Leave the device as it was, whatever happened
+        let deleted = device.delete(&component_id);
+        result?;
+        deleted?;
+        Ok(())
+    }
+
+    pub fn do_test(&self) -> anyhow::Result<()> {
+        let package = package::CliSetting::new(package::PackageArgs::new(self.common.clone()))
+            .with_test_metadata();
+        let device = AzsphereCli::from_sdk(self.device.clone(), self.common.verbose)?;
+        let mut reports = vec![];
+        // A crate or test executable that fails is reported, and the others still run
+        for context in package.contexts()? {
+            let executables = match self.build_tests(&context) {
+                Ok(executables) => executables,
+                Err(e) => {
+                    eprintln!("Failed to build the tests of {}: {e:#}", context.crate_name);
+                    let mut report = TestReport::new(&context.crate_name);
+                    report.error = Some(format!("{e:#}"));
+                    reports.push(report);
+                    continue;
+                }
+            };
+            if executables.is_empty() {
+                println!("{} has no tests", context.crate_name);
+            }
+            for (name, executable) in executables {
+                let mut report = TestReport::new(&name);
+                if let Err(e) =
+                    self.test_executable(&package, &device, &context, executable, &mut report)
+                {
+                    eprintln!("Failed to run {name}: {e:#}");
+                    report.error = Some(format!("{e:#}"));
+                }
+                reports.push(report);
+            }
+        }
+
+        if let Some(path) = &self.junit {
+            fs::write(path, junit(&reports))
+                .with_context(|| format!("failed to write {}", path.display()))?;
+        }
+        let mut failed = 0;
+        for report in &reports {
+            println!(
+                "{}: {} passed, {} failed, {} ignored",
+                report.name,
+                report.count(Outcome::Passed),
+                report.count(Outcome::Failed),
+                report.count(Outcome::Ignored)
+            );
+            failed += report.count(Outcome::Failed);
+        }
+        if failed > 0 {
+            return Err(Error::TestsFailed(failed).into());
+        }
+        if let Some(report) = reports.iter().find(|report| report.error.is_some()) {
+            return Err(Error::TestsNotRun(report.name.clone()).into());
+        }
+        if let Some(report) = reports.iter().find(|report| !report.is_complete()) {
+            return Err(Error::TestsIncomplete(report.name.clone()).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+#[allow(clippy::module_inception)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_test_report() {
+        let output = "\
+running 3 tests
+test pwm::tests::open_works ... ok
+test pwm::tests::apply_works ... FAILED
+test gpio::tests::blink ... ignored, needs a LED
+
+failures:
+
+---- pwm::tests::apply_works stdout ----
+thread 'pwm::tests::apply_works' panicked at src/pwm.rs:40:9:
+assertion `left == right` failed
+  left: 1 < 2
+
+failures:
+    pwm::tests::apply_works
+
+test result: FAILED. 1 passed; 1 failed; 1 ignored; 0 measured; 0 filtered out; finished in 0.02s
+";
+        let mut report = TestReport::new("pwm-lib-test");
+        let mut echo = vec![];
+        let mut writer = ReportWriter {
+            report: &mut report,
+            line: vec![],
+            echo: &mut echo,
+        };
+        // Split mid-line, as reads of the stream are
+        let (first, second) = output.split_at(50);
+        writer.write_all(first.as_bytes()).unwrap();
+        writer.write_all(second.as_bytes()).unwrap();
+        assert_eq!(String::from_utf8(echo).unwrap(), output);
+        assert!(report.is_complete());
+        let outcomes = report
+            .cases
+            .iter()
+            .map(|case| (case.name.as_str(), case.outcome))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outcomes,
+            [
+                ("pwm::tests::open_works", Outcome::Passed),
+                ("pwm::tests::apply_works", Outcome::Failed),
+                ("gpio::tests::blink", Outcome::Ignored),
+            ]
+        );
+        assert_eq!(
+            report.cases[1].output,
+            "thread 'pwm::tests::apply_works' panicked at src/pwm.rs:40:9:\n\
+             assertion `left == right` failed\n  left: 1 < 2\n\n"
+        );
+
+        let mut unfinished = TestReport::new("gpio-test");
+        unfinished.parse_line("running 1 test");
+        assert!(unfinished.started && !unfinished.is_complete());
+        // The output was connected to after libtest's first line
+        let mut late = TestReport::new("uart-test");
+        late.parse_line("test uart::tests::echo ... ok");
+        late.parse_line("test result: ok. 1 passed; 0 failed; 0 ignored; 0 measured");
+        assert!(late.finished && !late.is_complete());
+        let mut not_run = TestReport::new("i2c-test");
+        not_run.error = Some("failed to connect to 192.168.35.2:2342 <timed out>".to_string());
+        assert!(!not_run.is_complete());
+        let xml = junit(&[report, unfinished, not_run]);
+        assert!(xml.contains(
+            "<testsuite name=\"pwm-lib-test\" tests=\"3\" failures=\"1\" skipped=\"1\">"
+        ));
+        assert!(xml.contains("<testcase classname=\"pwm::tests\" name=\"open_works\"/>"));
+        assert!(xml.contains(
+            "<failure message=\"failed\">thread &apos;pwm::tests::apply_works&apos; panicked"
+        ));
+        assert!(xml.contains("  left: 1 &lt; 2\n"));
+        assert!(xml.contains(
+            "<testsuite name=\"gpio-test\" tests=\"0\" failures=\"0\" skipped=\"0\">\n    \
+             <system-err>"
+        ));
+        assert!(xml.contains(
+            "<testsuite name=\"i2c-test\" tests=\"0\" failures=\"0\" skipped=\"0\">\n    \
+             <system-err>failed to connect to 192.168.35.2:2342 &lt;timed out&gt;</system-err>"
+        ));
+
+        let messages = r#"{"reason":"compiler-artifact","target":{"kind":["lib"],"name":"pwm"},"profile":{"test":false},"executable":null}
+{"reason":"compiler-artifact","target":{"kind":["lib"],"name":"pwm"},"profile":{"test":true},"executable":"/t/deps/pwm-1a2b"}
+{"reason":"compiler-artifact","target":{"kind":["test"],"name":"gpio"},"profile":{"test":true},"executable":"/t/deps/gpio-3c4d"}
+{"reason":"build-finished","success":true}"#;
+        assert_eq!(
+            test_executables(messages),
+            [
+                (
+                    "pwm-lib-test".to_string(),
+                    PathBuf::from("/t/deps/pwm-1a2b")
+                ),
+                ("gpio-test".to_string(), PathBuf::from("/t/deps/gpio-3c4d")),
+            ]
+        );
+    }
+}
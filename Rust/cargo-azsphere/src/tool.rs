@@ -0,0 +1,135 @@
+//! Running external tools, such as the azsphere CLI and wslpath, and turning their failures into
+//! errors rather than exiting.
+
+use crate::error::Error;
+use std::io;
+use std::path::Path;
+use std::process::{Command, Output};
+
+/// Runs an external tool to completion and captures its output
+pub trait ToolRunner {
+    fn output(&self, program: &Path, args: &[String]) -> io::Result<Output>;
+}
+
+/// Runs tools as child processes
+#[derive(Debug, Default)]
+pub struct SystemRunner;
+
+impl ToolRunner for SystemRunner {
+    fn output(&self, program: &Path, args: &[String]) -> io::Result<Output> {
+        Command::new(program).args(args).output()
+    }
+}
+
+/// Run `program`, failing with [`Error::ExternalTool`] unless it exits successfully
+pub fn run(runner: &dyn ToolRunner, program: &Path, args: &[String]) -> Result<Output, Error> {
+    let output = runner
+        .output(program, args)
+        .map_err(|e| Error::FileIo(program.to_path_buf(), e))?;
+    if !output.status.success() {
+        return Err(Error::ExternalTool {
+            program: program.to_path_buf(),
+            args: args.to_vec(),
+            status: output.status,
+            stdout: String::from_utf8_lossy(&output.stdout).trim().to_string(),
+            stderr: String::from_utf8_lossy(&output.stderr).trim().to_string(),
+        });
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+pub(crate) mod fake {
+    use super::*;
+    use std::cell::RefCell;
+    use std::collections::VecDeque;
+    use std::os::unix::process::ExitStatusExt;
+    use std::path::PathBuf;
+    use std::process::ExitStatus;
+
+    /// A [`ToolRunner`] that records its calls and replies with canned outputs, in order, then
+    /// with success
+    #[derive(Default)]
+    pub(crate) struct FakeRunner {
+        pub calls: RefCell<Vec<(PathBuf, Vec<String>)>>,
+        pub replies: RefCell<VecDeque<io::Result<Output>>>,
+    }
+
+    impl FakeRunner {
+        pub fn reply(self, reply: io::Result<Output>) -> Self {
+            self.replies.borrow_mut().push_back(reply);
+            self
+        }
+    }
+
+    impl ToolRunner for FakeRunner {
+        fn output(&self, program: &Path, args: &[String]) -> io::Result<Output> {
+            self.calls
+                .borrow_mut()
+                .push((program.to_path_buf(), args.to_vec()));
+            self.replies
+                .borrow_mut()
+                .pop_front()
+                .unwrap_or_else(|| Ok(exited(0, "", "")))
+        }
+    }
+
+    /// Output of a tool that exited with `code`
+    pub(crate) fn exited(code: i32, stdout: &str, stderr: &str) -> Output {
+        Output {
+            status: ExitStatus::from_raw(code << 8),
+            stdout: stdout.as_bytes().to_vec(),
+            stderr: stderr.as_bytes().to_vec(),
+        }
+    }
+
+    /// Output of a tool that was killed by `signal`
+    pub(crate) fn killed(signal: i32) -> Output {
+        Output {
+            status: ExitStatus::from_raw(signal),
+            stdout: vec![],
+            stderr: vec![],
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::fake::*;
+    use super::*;
+
+    #[test]
+    fn test_run() {
+        let program = Path::new("/sdk/Tools_v2/azsphere");
+        let args = vec!["device".to_string(), "show".to_string()];
+        let runner = FakeRunner::default()
+            .reply(Ok(exited(0, "ok\n", "")))
+            .reply(Ok(exited(2, "partial\n", "  no device attached\n")))
+            .reply(Ok(killed(9)))
+            .reply(Err(io::ErrorKind::NotFound.into()));
+
+        assert_eq!(run(&runner, program, &args).unwrap().stdout, b"ok\n");
+        match run(&runner, program, &args) {
+            Err(Error::ExternalTool {
+                status,
+                stdout,
+                stderr,
+                ..
+            }) => {
+                assert_eq!(status.code(), Some(2));
+                assert_eq!(stdout, "partial");
+                assert_eq!(stderr, "no device attached");
+            }
+            other => panic!("{other:?}"),
+        }
+        // A tool killed by a signal has no exit code, which mustn't panic
+        let err = run(&runner, program, &args).unwrap_err();
+        assert!(matches!(err, Error::ExternalTool { status, .. } if status.code().is_none()));
+        assert!(matches!(
+            run(&runner, program, &args),
+            Err(Error::FileIo(path, _)) if path == program
+        ));
+        assert_eq!(runner.calls.borrow().len(), 4);
+        assert_eq!(runner.calls.borrow()[0], (program.to_path_buf(), args));
+    }
+}
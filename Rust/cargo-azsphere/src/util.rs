@@ -1,10 +1,38 @@
-use std::path::{Path, PathBuf};
+use crate::error::Error;
+use std::path::{Component, Path, PathBuf};
 
 pub fn is_wsl() -> bool {
     Path::new("/proc/sys/fs/binfmt_misc/WSLInterop").exists()
 }
 
-pub fn azsphere_tool_path() -> (PathBuf, Vec<String>) {
+/// The Azure Sphere SDK directory, from AzureSphereDefaultSDKDir
+pub fn sdk_path() -> Option<PathBuf> {
+    std::env::var_os("AzureSphereDefaultSDKDir").map(PathBuf::from)
+}
+
+/// The Azure Sphere SDK directory, which must exist
+pub fn required_sdk_path() -> Result<PathBuf, Error> {
+    let sdk_path = sdk_path()
+        .ok_or_else(|| Error::MissingSdk("AzureSphereDefaultSDKDir is not set".to_string()))?;
+    if !sdk_path.is_dir() {
+        return Err(Error::MissingSdk(format!(
+            "{} does not exist",
+            sdk_path.display()
+        )));
+    }
+    Ok(sdk_path)
+}
+
+/// A cross tool of the Sysroot for `arv`, such as `strip` or `gdb`
+pub fn sysroot_tool(sdk_path: &Path, arv: &str, tool: &str) -> PathBuf {
+    sdk_path
+        .join("Sysroots")
+        .join(arv)
+        .join("tools/sysroots/x86_64-pokysdk-linux/usr/bin/arm-poky-linux-musleabi")
+        .join(format!("arm-poky-linux-musleabi-{tool}"))
+}
+
+pub fn azsphere_tool_path() -> Result<(PathBuf, Vec<String>), Error> {
     let mut args: Vec<String> = vec![];
     let azsphere = if is_wsl() {
         // Use the Windows azsphere.exe, found on the path, as it can communicate with the device via SLIP etc.
@@ -12,8 +40,23 @@ pub fn azsphere_tool_path() -> (PathBuf, Vec<String>) {
         args.push("azure.cli".to_string());
         PathBuf::from("/mnt/c/Program Files (x86)/Microsoft Azure Sphere SDK/Tools_v2/python.exe")
     } else {
-        let sdk_path = PathBuf::from(std::env::var("AzureSphereDefaultSDKDir").unwrap());
-        sdk_path.join("Tools_v2/azsphere")
+        required_sdk_path()?.join("Tools_v2/azsphere")
     };
-    (azsphere, args)
+    Ok((azsphere, args))
+}
+
+/// Path of `to` relative to the directory `from`; both must be absolute
+pub fn relative_path(from: &Path, to: &Path) -> PathBuf {
+    let from = from.components().collect::<Vec<_>>();
+    let to = to.components().collect::<Vec<_>>();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+    let mut path = PathBuf::new();
+    for _ in common..from.len() {
+        path.push(Component::ParentDir);
+    }
+    path.extend(&to[common..]);
+    if path.as_os_str().is_empty() {
+        path.push(Component::CurDir);
+    }
+    path
 }
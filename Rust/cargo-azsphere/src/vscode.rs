@@ -0,0 +1,468 @@
+//! `cargo azsphere vscode`: generate or update the package's `.vscode/launch.json` and
+//! `tasks.json`, so F5 in VS Code builds, deploys and starts the app in debug mode, then attaches
+//! the Sysroot's gdb to it.
+
+use crate::config::ExtraMetadataSource;
+use crate::dry_run::Changes;
+use crate::error::Error;
+use crate::logs;
+use crate::package;
+use crate::util;
+use crate::workspace::Workspace;
+use serde_json::{json, Value};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where the SDK's installer puts it, for when AzureSphereDefaultSDKDir isn't set
+const DEFAULT_SDK_PATH: &str = "/opt/azurespheresdk";
+
+/// Port gdbserver listens on, on the device, for an app started in debug mode
+const GDB_PORT: u16 = 2345;
+
+#[derive(clap::Parser, Debug)]
+pub(crate) struct CliArgs {
+    #[clap(flatten)]
+    common: package::CliArgs,
+    /// IP address of the device to debug on, which also selects it when multiple devices are
+    /// attached [DEFAULT = 192.168.35.2]
+    #[arg(short, long)]
+    device: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct CliSetting {
+    package: Option<String>,
+    verbose: bool,
+    release: bool,
+    device: Option<String>,
+    extra_metadata: Vec<ExtraMetadataSource>,
+}
+
+impl CliSetting {
+    pub(crate) fn new(args: CliArgs) -> Self {
+        Self {
+            package: args.common.package.clone(),
+            verbose: args.common.verbose,
+            release: args.common.release,
+            device: args.device,
+            extra_metadata: args.common.extra_metadata(),
+        }
+    }
+
+    pub fn do_vscode(&self) -> Result<(), Error> {
+        let workspace = Workspace::load(self.verbose)?;
+        for member in workspace.members(self.package.as_deref())? {
+            let package_config =
+                workspace.package_config(&member, &self.extra_metadata, self.verbose)?;
+            let launch = Launch {
+                name: package_config.name,
+                variant: package_config.variant,
+                arv: package_config.arv,
+                sdk_path: util::sdk_path(),
+                crate_dir: member.dir().to_path_buf(),
+                target_path: workspace.target_path(self.release),
+                release: self.release,
+                device: self.device.clone(),
+            };
+            launch.write(Changes(None), self.verbose)?;
+            println!(
+                "Wrote the VS Code settings of {}; start debugging with F5",
+                launch.package_name()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// What the generated launch configuration and tasks debug
+#[derive(Debug)]
+pub(crate) struct Launch {
+    /// package name, which is also that of the executable
+    pub name: String,
+    /// build variant, from `[package.metadata.azsphere.variants]`
+    pub variant: Option<String>,
+    pub arv: String,
+    pub sdk_path: Option<PathBuf>,
+    /// where `.vscode` is, which VS Code has open as `${workspaceFolder}`
+    pub crate_dir: PathBuf,
+    /// build output directory, such as target/armv7-unknown-linux-musleabihf/debug
+    pub target_path: PathBuf,
+    pub release: bool,
+    pub device: Option<String>,
+}
+
+impl Launch {
+    fn flavor(&self) -> &'static str {
+        if self.release {
+            "release"
+        } else {
+            "debug"
+        }
+    }
+
+    /// `name`, or `name-variant`, as packaging names its files
+    fn package_name(&self) -> String {
+        match &self.variant {
+            Some(variant) => format!("{}-{}", self.name, variant),
+            None => self.name.clone(),
+        }
+    }
+
+    /// `path` relative to `${workspaceFolder}`, when it is absolute
+    fn workspace_folder_path(&self, path: &Path) -> String {
+        if path.is_absolute() && self.crate_dir.is_absolute() {
+            let relative = util::relative_path(&self.crate_dir, path);
+            format!("${{workspaceFolder}}/{}", relative.display())
+        } else {
+            format!("${{workspaceFolder}}/{}", path.display())
+        }
+    }
+
+    /// Label of the task that deploys the app and starts it in debug mode
+    fn prelaunch_task(&self) -> String {
+        format!(
+            "cargo azsphere: debug {} ({})",
+            self.package_name(),
+            self.flavor()
+        )
+    }
+
+    pub fn configuration(&self) -> Value {
+        let sdk_path = self
+            .sdk_path
+            .clone()
+            .unwrap_or_else(|| PathBuf::from(DEFAULT_SDK_PATH));
+        let device = self.device.as_deref().unwrap_or(logs::DEFAULT_DEVICE);
+        let program = package::unstripped_executable(&self.target_path, &self.package_name());
+        json!({
+            "name": format!(
+                "Debug {} on Azure Sphere ({})",
+                self.package_name(),
+                self.flavor()
+            ),
+            "type": "cppdbg",
+            "request": "launch",
+            "program": self.workspace_folder_path(&program),
+            "cwd": "${workspaceFolder}",
+            "MIMode": "gdb",
+            "miDebuggerPath": util::sysroot_tool(&sdk_path, &self.arv, "gdb"),
+            // gdb runs `target remote` with it
+            "miDebuggerServerAddress": format!("{device}:{GDB_PORT}"),
+            "targetArchitecture": "ARM",
+            "externalConsole": false,
+            "setupCommands": [
+                {
+                    "description": "Enable pretty-printing for gdb",
+                    "text": "-enable-pretty-printing",
+                    "ignoreFailures": true,
+                }
+            ],
+            "preLaunchTask": self.prelaunch_task(),
+        })
+    }
+
+    pub fn tasks(&self) -> Vec<Value> {
+        let mut device_args = vec![];
+        if let Some(device) = &self.device {
+            device_args = vec!["--device".to_string(), device.clone()];
+        }
+        let mut variant_args = vec![];
+        if let Some(variant) = &self.variant {
+            variant_args = vec!["--variant".to_string(), variant.clone()];
+        }
+        let mut deploy_args = vec!["azsphere", "build", "--deploy", "--manual-start"]
+            .into_iter()
+            .map(str::to_string)
+            .chain(["--package".to_string(), self.name.clone()])
+            .collect::<Vec<_>>();
+        if self.release {
+            deploy_args.push("--release".to_string());
+        }
+        deploy_args.extend(variant_args.clone());
+        deploy_args.extend(device_args.clone());
+        let mut start_args = vec![
+            "azsphere".to_string(),
+            "start".to_string(),
+            "--package".to_string(),
+            self.name.clone(),
+            "--debug-mode".to_string(),
+        ];
+        start_args.extend(variant_args);
+        start_args.extend(device_args);
+
+        let package_name = self.package_name();
+        let deploy = format!(
+            "cargo azsphere: deploy {} ({})",
+            package_name,
+            self.flavor()
+        );
+        let start = format!("cargo azsphere: start {} in debug mode", package_name);
+        vec![
+            json!({
+                "label": deploy,
+                "type": "shell",
+                "command": "cargo",
+                "args": deploy_args,
+                "problemMatcher": ["$rustc"],
+                "group": "build",
+            }),
+            json!({
+                "label": start,
+                "type": "shell",
+                "command": "cargo",
+                "args": start_args,
+                "problemMatcher": [],
+            }),
+            json!({
+                "label": self.prelaunch_task(),
+                "dependsOn": [deploy, start],
+                "dependsOrder": "sequence",
+                "problemMatcher": [],
+            }),
+        ]
+    }
+
+    /// Add the configuration and tasks to `.vscode/launch.json` and `tasks.json`, replacing
+    /// those with the same names and keeping the rest
+    pub fn write(&self, changes: Changes, verbose: bool) -> Result<(), Error> {
+        let dir = self.crate_dir.join(".vscode");
+        changes.create_dir_all(&dir)?;
+        let files = [
+            (
+                "launch.json",
+                "0.2.0",
+                "configurations",
+                "name",
+                vec![self.configuration()],
+            ),
+            ("tasks.json", "2.0.0", "tasks", "label", self.tasks()),
+        ];
+        for (file, version, key, id, entries) in files {
+            let path = dir.join(file);
+            let existing = match fs::read_to_string(&path) {
+                Ok(text) => Some(
+                    serde_json::from_str(&strip_jsonc(&text))
+                        .map_err(|e| Error::Json(path.clone(), e))?,
+                ),
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => None,
+                Err(e) => return Err(Error::FileIo(path, e)),
+            };
+            if verbose {
+                let verb = if existing.is_some() {
+                    "Updating"
+                } else {
+                    "Writing"
+                };
+                println!("{verb} {}", path.display());
+            }
+            let merged = merge(existing, version, key, id, entries);
+            let text = serde_json::to_string_pretty(&merged)
+                .map_err(|e| Error::Json(path.clone(), e))?
+                + "\n";
+            changes.write(&path, text)?;
+        }
+        Ok(())
+    }
+}
+
+/// `existing` with `entries` in its `key` array, replacing those with the same `id`
+fn merge(
+    existing: Option<Value>,
+    version: &str,
+    key: &str,
+    id: &str,
+    entries: Vec<Value>,
+) -> Value {
+    let mut document = match existing {
+        Some(Value::Object(document)) => document,
+        _ => serde_json::Map::from_iter([("version".to_string(), json!(version))]),
+    };
+    let mut merged = match document.remove(key) {
+        Some(Value::Array(existing)) => existing,
+        _ => vec![],
+    };
+    for entry in entries {
+        match merged.iter_mut().find(|old| old[id] == entry[id]) {
+            Some(old) => *old = entry,
+            None => merged.push(entry),
+        }
+    }
+    document.insert(key.to_string(), Value::Array(merged));
+    Value::Object(document)
+}
+
+/// The JSON of VS Code's JSON with comments: without comments, and without trailing commas
+fn strip_jsonc(text: &str) -> String {
+    let mut json = String::with_capacity(text.len());
+    let mut chars = text.chars().peekable();
+    let mut in_string = false;
+    while let Some(c) = chars.next() {
+        if in_string {
+            json.push(c);
+            match c {
+                '\\' => json.extend(chars.next()),
+                '"' => in_string = false,
+                _ => {}
+            }
+            continue;
+        }
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                json.push(c);
+            }
+            ('/', Some('/')) => while chars.next_if(|&c| c != '\n').is_some() {},
+            ('/', Some('*')) => {
+                chars.next();
+                let mut previous = ' ';
+                for c in chars.by_ref() {
+                    if previous == '*' && c == '/' {
+                        break;
+                    }
+                    previous = c;
+                }
+            }
+            (']' | '}', _) => {
+                // Drop a comma before the closing bracket
+                let end = json.trim_end().len();
+                if json[..end].ends_with(',') {
+                    json.truncate(end - 1);
+                }
+                json.push(c);
+            }
+            _ => json.push(c),
+        }
+    }
+    json
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn launch(crate_dir: &Path) -> Launch {
+        Launch {
+            name: "blink".to_string(),
+            variant: None,
+            arv: "16".to_string(),
+            sdk_path: Some(PathBuf::from("/opt/azurespheresdk")),
+            crate_dir: crate_dir.to_path_buf(),
+            target_path: crate_dir.join("../../target/armv7-unknown-linux-musleabihf/release"),
+            release: true,
+            device: None,
+        }
+    }
+
+    #[test]
+    fn test_strip_jsonc() {
+        let text = r#"{
+            // a comment, with "quotes"
+            "url": "http://example.com/*", /* block
+            comment */ "list": [1, 2,],
+            "escaped": "\"//\"",
+        }"#;
+        let value: Value = serde_json::from_str(&strip_jsonc(text)).unwrap();
+        assert_eq!(
+            value,
+            json!({ "url": "http://example.com/*", "list": [1, 2], "escaped": "\"//\"" })
+        );
+    }
+
+    #[test]
+    fn test_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let crate_dir = dir.path().join("rust/samples/blink");
+        fs::create_dir_all(crate_dir.join(".vscode")).unwrap();
+        // A hand-written configuration, like those of the samples
+        fs::write(
+            crate_dir.join(".vscode/launch.json"),
+            r#"{
+                // Use IntelliSense to learn about possible attributes.
+                "version": "0.2.0",
+                "configurations": [
+                    { "name": "Launch (gdb)", "type": "cppdbg", },
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let launch = launch(&crate_dir);
+        launch.write(Changes(None), false).unwrap();
+        launch.write(Changes(None), false).unwrap();
+        let read = |file: &str| -> Value {
+            let text = fs::read_to_string(crate_dir.join(".vscode").join(file)).unwrap();
+            serde_json::from_str(&text).unwrap()
+        };
+
+        let configurations = read("launch.json")["configurations"].clone();
+        assert_eq!(configurations.as_array().unwrap().len(), 2);
+        assert_eq!(configurations[0]["name"], "Launch (gdb)");
+        let configuration = &configurations[1];
+        assert_eq!(
+            configuration["program"],
+            "${workspaceFolder}/../../target/armv7-unknown-linux-musleabihf/release/blink.unstripped"
+        );
+        assert_eq!(
+            configuration["miDebuggerPath"],
+            "/opt/azurespheresdk/Sysroots/16/tools/sysroots/x86_64-pokysdk-linux/usr/bin/\
+             arm-poky-linux-musleabi/arm-poky-linux-musleabi-gdb"
+        );
+        assert_eq!(
+            configuration["miDebuggerServerAddress"],
+            "192.168.35.2:2345"
+        );
+
+        let tasks = read("tasks.json");
+        assert_eq!(tasks["version"], "2.0.0");
+        let tasks = tasks["tasks"].as_array().unwrap();
+        assert_eq!(tasks.len(), 3);
+        assert_eq!(configuration["preLaunchTask"], tasks[2]["label"]);
+        assert_eq!(
+            tasks[0]["args"],
+            json!([
+                "azsphere",
+                "build",
+                "--deploy",
+                "--manual-start",
+                "--package",
+                "blink",
+                "--release"
+            ])
+        );
+        assert_eq!(
+            tasks[1]["args"],
+            json!(["azsphere", "start", "--package", "blink", "--debug-mode"])
+        );
+        assert_eq!(
+            tasks[2]["dependsOn"],
+            json!([tasks[0]["label"], tasks[1]["label"]])
+        );
+
+        // A variant gets a configuration and tasks of its own
+        let launch = Launch {
+            variant: Some("avnet".to_string()),
+            ..launch
+        };
+        launch.write(Changes(None), false).unwrap();
+        let configurations = read("launch.json")["configurations"].clone();
+        assert_eq!(configurations.as_array().unwrap().len(), 3);
+        assert!(configurations[2]["program"]
+            .as_str()
+            .unwrap()
+            .ends_with("/release/blink-avnet.unstripped"));
+        let tasks = read("tasks.json")["tasks"].clone();
+        assert_eq!(tasks.as_array().unwrap().len(), 6);
+        assert_eq!(
+            tasks[4]["args"],
+            json!([
+                "azsphere",
+                "start",
+                "--package",
+                "blink",
+                "--debug-mode",
+                "--variant",
+                "avnet"
+            ])
+        );
+    }
+}
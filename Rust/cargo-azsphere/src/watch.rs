@@ -0,0 +1,216 @@
+//! `cargo azsphere watch`: build, package and deploy the app each time its sources, Cargo.toml,
+//! app manifest or extra files change, streaming its output meanwhile.  Files are polled for
+//! changes, which, unlike file system notifications, also works for Windows drives under WSL.
+
+use crate::build;
+use crate::logs::{self, LogStream};
+use crate::package::{self, PackageContext};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+/// How often files are checked for changes
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(clap::Parser, Debug)]
+pub(crate) struct CliArgs {
+    #[clap(flatten)]
+    common: package::CliArgs,
+    /// the device to deploy to when multiple devices are attached.
+    /// Values from: azsphere device list-attached. Specify the ID, IP address, or Local Connection ID of the device.
+    #[arg(short, long)]
+    device: Option<String>,
+    /// how long files must be left unchanged before rebuilding, in milliseconds
+    #[arg(long, default_value_t = 500)]
+    debounce_ms: u64,
+    /// don't stream the app's output
+    #[arg(long)]
+    no_logs: bool,
+}
+
+/// What is watched: files, and directories whose files at any depth are
+#[derive(Debug, Default, PartialEq, Eq)]
+struct WatchSet {
+    dirs: Vec<PathBuf>,
+    files: Vec<PathBuf>,
+}
+
+/// The modification time and size of each watched file, or `None` for a missing one
+type Snapshot = BTreeMap<PathBuf, Option<(SystemTime, u64)>>;
+
+fn stat(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+fn walk(dir: &Path, snapshot: &mut Snapshot) {
+    for entry in fs::read_dir(dir).into_iter().flatten().flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            walk(&path, snapshot);
+        } else {
+            snapshot.insert(path.clone(), stat(&path));
+        }
+    }
+}
+
+impl WatchSet {
+    /// The files `contexts` are built and packaged from, other than those the build writes
+    fn new(contexts: &[PackageContext]) -> Self {
+        let mut watch = Self::default();
+        for context in contexts {
+            let dir = &context.manifest_file_dir;
+            watch.dirs.push(dir.join("src"));
+            watch.files.push(dir.join("Cargo.toml"));
+            watch.files.push(dir.join("build.rs"));
+            if context.package_config.capabilities.is_none() {
+                watch
+                    .files
+                    .push(context.package_config.app_manifest.clone());
+            }
+            // A missing extra file is reported by packaging; fixing it is a change like any other
+            for extra_file in context.extra_files().unwrap_or_default() {
+                if !extra_file.source.starts_with(&context.target_path) {
+                    watch.files.push(extra_file.source);
+                }
+            }
+        }
+        watch
+    }
+
+    fn snapshot(&self) -> Snapshot {
+        let mut snapshot = Snapshot::new();
+        for dir in &self.dirs {
+            walk(dir, &mut snapshot);
+        }
+        for file in &self.files {
+            snapshot.insert(file.clone(), stat(file));
+        }
+        snapshot
+    }
+
+    /// Wait for a change since `snapshot`, then for the files to be left unchanged for
+    /// `debounce`, so that a burst of edits, such as a save of several files, is taken as one
+    fn wait_for_change(&self, mut snapshot: Snapshot, debounce: Duration, poll_interval: Duration) {
+        let mut changed = None;
+        loop {
+            thread::sleep(poll_interval);
+            let next = self.snapshot();
+            if next != snapshot {
+                snapshot = next;
+                changed = Some(Instant::now());
+            } else if changed.is_some_and(|changed| changed.elapsed() >= debounce) {
+                return;
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct CliSetting {
+    common: package::CliArgs,
+    device: Option<String>,
+    debounce: Duration,
+    logs: bool,
+}
+
+impl CliSetting {
+    pub(crate) fn new(args: CliArgs) -> Self {
+        Self {
+            common: args.common,
+            device: args.device,
+            debounce: Duration::from_millis(args.debounce_ms),
+            logs: !args.no_logs,
+        }
+    }
+
+    /// Build, package and deploy, as `build --deploy` does.  A failure is only reported, so that
+    /// the app already on the device keeps running until the next change.
+    fn deploy(&self) {
+        let build = build::CliSetting::new(build::CliArgs::deploy(
+            self.common.clone(),
+            self.device.clone(),
+        ));
+        match build.do_build() {
+            Ok(()) => println!("Deployed; waiting for changes"),
+            Err(e) => eprintln!("❌: {e:#}\nWaiting for changes"),
+        }
+    }
+
+    pub fn do_watch(self) -> anyhow::Result<()> {
+        if self.logs {
+            // The stream waits for the app to restart after each deploy
+            let device = self.device.as_deref().unwrap_or(logs::DEFAULT_DEVICE);
+            let mut logs = LogStream::new(device);
+            logs.verbose = self.common.verbose;
+            thread::spawn(move || logs.follow(&mut io::stdout(), None));
+        }
+        let package = package::CliSetting::new(package::PackageArgs::new(self.common.clone()));
+        let mut watch = WatchSet::new(&package.contexts()?);
+        loop {
+            // Taken before deploying, so that edits saved during the build are noticed
+            let snapshot = watch.snapshot();
+            self.deploy();
+            watch.wait_for_change(snapshot, self.debounce, POLL_INTERVAL);
+            // Found again each time, as Cargo.toml may have changed what is packaged.  When it
+            // can't be read, deploying reports why, and the files found last are still watched.
+            if let Ok(contexts) = package.contexts() {
+                watch = WatchSet::new(&contexts);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_watch_set() {
+        let dir = tempfile::tempdir().unwrap();
+        let src = dir.path().join("src");
+        fs::create_dir_all(src.join("bin")).unwrap();
+        fs::write(src.join("main.rs"), "fn main() {}").unwrap();
+        fs::write(src.join("bin/tool.rs"), "fn main() {}").unwrap();
+        let manifest = dir.path().join("app_manifest.json");
+        let watch = WatchSet {
+            dirs: vec![src.clone()],
+            files: vec![manifest.clone()],
+        };
+        let snapshot = watch.snapshot();
+        assert_eq!(
+            snapshot.keys().collect::<Vec<_>>(),
+            [&manifest, &src.join("bin/tool.rs"), &src.join("main.rs")]
+        );
+        assert_eq!(snapshot[&manifest], None);
+
+        // Three writes in quick succession make one change, once they stop
+        let writer = thread::spawn(move || {
+            for text in ["{", "{}", "{ }"] {
+                thread::sleep(Duration::from_millis(20));
+                fs::write(&manifest, text).unwrap();
+            }
+        });
+        let start = Instant::now();
+        watch.wait_for_change(
+            snapshot.clone(),
+            Duration::from_millis(100),
+            Duration::from_millis(5),
+        );
+        writer.join().unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(160));
+        assert_eq!(
+            fs::read_to_string(dir.path().join("app_manifest.json")).unwrap(),
+            "{ }"
+        );
+        assert_ne!(watch.snapshot(), snapshot);
+
+        // A change made before waiting, such as during a build, is noticed too
+        let snapshot = watch.snapshot();
+        fs::write(src.join("main.rs"), "fn main() { }").unwrap();
+        watch.wait_for_change(snapshot, Duration::ZERO, Duration::from_millis(5));
+    }
+}
@@ -0,0 +1,211 @@
+//! The crates a subcommand operates on, found from `cargo metadata`.
+//!
+//! With `--package`, that workspace member is used.  Otherwise the crate whose Cargo.toml is in
+//! the current directory is used, or, from the root of a virtual workspace, every default member.
+
+use crate::config::{CargoConfig, Config, ExtraMetadataSource, PackageConfig};
+use crate::error::Error;
+use serde_json::Value;
+use std::env;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// A workspace member
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Member {
+    pub name: String,
+    pub manifest_path: PathBuf,
+}
+
+impl Member {
+    /// Directory containing the member's Cargo.toml
+    pub fn dir(&self) -> &Path {
+        self.manifest_path
+            .parent()
+            .unwrap_or_else(|| Path::new("."))
+    }
+}
+
+#[derive(Debug)]
+pub struct Workspace {
+    pub metadata: Value,
+}
+
+impl Workspace {
+    /// Run `cargo metadata` in the current directory
+    pub fn load(verbose: bool) -> Result<Self, Error> {
+        if verbose {
+            println!("Invoking 'cargo metadata'");
+        }
+        // Only workspace members and their declared dependencies are needed, so don't resolve the
+        // dependency graph, which can need the network
+        let output = Command::new("cargo")
+            .arg("metadata")
+            .arg("--format-version=1")
+            .arg("--no-deps")
+            .output()?;
+        if !output.status.success() {
+            return Err(Error::CargoMetadata(
+                String::from_utf8_lossy(&output.stderr).trim().to_string(),
+            ));
+        }
+        if verbose {
+            println!("Parsing metadata");
+        }
+        let metadata = serde_json::from_slice(output.stdout.as_slice())
+            .map_err(|e| Error::CargoMetadata(e.to_string()))?;
+        Ok(Self { metadata })
+    }
+
+    pub fn target_directory(&self) -> PathBuf {
+        PathBuf::from(
+            self.metadata["target_directory"]
+                .as_str()
+                .unwrap_or("target"),
+        )
+    }
+
+    /// Build output directory of the Azure Sphere target, such as
+    /// target/armv7-unknown-linux-musleabihf/debug
+    pub fn target_path(&self, release: bool) -> PathBuf {
+        let build_flavor = if release { "release" } else { "debug" };
+        self.target_directory()
+            .join("armv7-unknown-linux-musleabihf")
+            .join(build_flavor)
+    }
+
+    /// Read the `[package.metadata.azsphere]` config of `member`
+    pub fn package_config(
+        &self,
+        member: &Member,
+        extra_metadata: &[ExtraMetadataSource],
+        verbose: bool,
+    ) -> Result<PackageConfig, Error> {
+        if verbose {
+            println!("Finding package config of {}", member.name);
+        }
+        let config = Config::new(&member.manifest_path, extra_metadata)?;
+        let cargo_config = CargoConfig::load(member.dir())?;
+        config.package_config(&cargo_config, verbose)
+    }
+
+    /// The members selected by `package`, or by the current directory
+    pub fn members(&self, package: Option<&str>) -> Result<Vec<Member>, Error> {
+        select_members(&self.metadata, package, &env::current_dir()?)
+    }
+
+    /// The one member selected by `package`, or by the current directory
+    pub fn member(&self, package: Option<&str>) -> Result<Member, Error> {
+        let mut members = self.members(package)?;
+        match members.len() {
+            1 => Ok(members.remove(0)),
+            n => Err(Error::AmbiguousPackage(n)),
+        }
+    }
+}
+
+fn select_members(
+    metadata: &Value,
+    package: Option<&str>,
+    current_dir: &Path,
+) -> Result<Vec<Member>, Error> {
+    let ids = |key: &str| {
+        metadata[key]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|id| id.as_str())
+            .collect::<Vec<_>>()
+    };
+    let workspace_members = ids("workspace_members");
+    let members = metadata["packages"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter(|p| workspace_members.contains(&p["id"].as_str().unwrap_or_default()))
+        .map(|p| {
+            (
+                p["id"].as_str().unwrap_or_default(),
+                Member {
+                    name: p["name"].as_str().unwrap_or_default().to_string(),
+                    manifest_path: PathBuf::from(p["manifest_path"].as_str().unwrap_or_default()),
+                },
+            )
+        })
+        .collect::<Vec<_>>();
+
+    if let Some(package) = package {
+        return members
+            .into_iter()
+            .find(|(_, member)| member.name == package)
+            .map(|(_, member)| vec![member])
+            .ok_or_else(|| Error::UnknownPackage(package.to_string()));
+    }
+
+    let manifest_path = current_dir.join("Cargo.toml");
+    if let Some((_, member)) = members
+        .iter()
+        .find(|(_, member)| member.manifest_path == manifest_path)
+    {
+        return Ok(vec![member.clone()]);
+    }
+
+    // Older versions of cargo don't list the default members; they are then all members
+    let default_members = match metadata.get("workspace_default_members") {
+        Some(_) => ids("workspace_default_members"),
+        None => workspace_members,
+    };
+    Ok(members
+        .into_iter()
+        .filter(|(id, _)| default_members.contains(id))
+        .map(|(_, member)| member)
+        .collect())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_select_members() {
+        let metadata = json!({
+            "packages": [
+                { "id": "hardware 0.1.0", "name": "hardware", "manifest_path": "/ws/hardware/Cargo.toml" },
+                { "id": "adc 0.1.0", "name": "adc_high_level_app", "manifest_path": "/ws/samples/ADC/Cargo.toml" },
+                { "id": "gpio 0.1.0", "name": "gpio_high_level_app", "manifest_path": "/ws/samples/GPIO/Cargo.toml" },
+                { "id": "libc 0.2.0", "name": "libc", "manifest_path": "/registry/libc/Cargo.toml" }
+            ],
+            "workspace_members": ["hardware 0.1.0", "adc 0.1.0", "gpio 0.1.0"],
+            "workspace_default_members": ["adc 0.1.0", "gpio 0.1.0"],
+            "workspace_root": "/ws"
+        });
+        let names = |members: Vec<Member>| members.into_iter().map(|m| m.name).collect::<Vec<_>>();
+
+        let members =
+            select_members(&metadata, Some("adc_high_level_app"), Path::new("/ws")).unwrap();
+        assert_eq!(members[0].dir(), Path::new("/ws/samples/ADC"));
+        assert_eq!(names(members), ["adc_high_level_app"]);
+        assert!(matches!(
+            select_members(&metadata, Some("libc"), Path::new("/ws")),
+            Err(Error::UnknownPackage(name)) if name == "libc"
+        ));
+
+        let members = select_members(&metadata, None, Path::new("/ws/samples/GPIO")).unwrap();
+        assert_eq!(names(members), ["gpio_high_level_app"]);
+
+        let members = select_members(&metadata, None, Path::new("/ws")).unwrap();
+        assert_eq!(
+            names(members),
+            ["adc_high_level_app", "gpio_high_level_app"]
+        );
+
+        let mut metadata = metadata;
+        metadata
+            .as_object_mut()
+            .unwrap()
+            .remove("workspace_default_members");
+        let members = select_members(&metadata, None, Path::new("/ws")).unwrap();
+        assert_eq!(names(members).len(), 3);
+    }
+}